@@ -0,0 +1,470 @@
+//Backup Engine - incremental backups of a Database kept in a directory of their own
+
+/*
+Every backup starts as a checkpoint of the Database, its files are then filed away in the backup directory
+
+backup_dir/
+  shared/000005_3245235235.sst    tables, stored once for every backup that holds them
+  private/3/MANIFEST              the MANIFEST and WAL only backup 3 holds
+  meta/3                          what backup 3 is made of
+  NEXT_ID                         id the next backup gets
+
+Tables are never changed once written, so a table with the same name and checksum as one already backed up
+is the same table and is not copied again. Only the tables written since the last backup cost anything.
+Tables are copied and not linked, a backup shares no file with the Database it was taken from.
+
+lanadb-backup 1
+id 3
+created 1700000000000000
+timestamp 1700000000000123
+file 000005.sst shared/000005_3245235235.sst 4096 3245235235
+file MANIFEST private/3/MANIFEST 120 98123
+
+created is when the backup was taken by the Database's Clock, timestamp the newest write it holds. Every file
+is listed with its size and CRC-32. The meta file is written last, a backup cut short has none and what it
+left is removed by the next purge.
+
+Ids are never handed out twice, not even after every backup was purged. NEXT_ID is moved on before a backup
+writes anything, a backup cut short only skips its id. A directory without NEXT_ID, or with one behind its
+meta files, goes on after the newest backup.
+*/
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::database::Database;
+use crate::env::{default_env, Env};
+use crate::error::{Error, Result};
+use crate::utils::crc32_update;
+
+const HEADER: &str = "lanadb-backup 1";
+const NEXT_ID: &str = "NEXT_ID";
+
+pub struct BackupEngine {
+    env: Arc<dyn Env>,
+    dir: PathBuf,
+    backups: BTreeMap<u32, BackupInfo>,
+    next_id: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct BackupInfo {
+    pub id: u32,
+    //When the backup was taken, in microseconds since the UNIX epoch
+    pub created: u128,
+    //Timestamp of the newest write in the backup
+    pub timestamp: u128,
+    //Bytes of all the files of the backup, shared ones included
+    pub size: u64,
+    files: Vec<BackupFile>,
+}
+
+#[derive(Clone, Debug)]
+struct BackupFile {
+    //Name of the file in the Database directory
+    name: String,
+    //Path of the file relative to the backup directory
+    stored: String,
+    size: u64,
+    checksum: u32,
+}
+
+impl BackupEngine {
+    //Open the backups in dir, creating it when there is none yet
+    pub fn open(dir: impl AsRef<Path>) -> Result<BackupEngine> {
+        BackupEngine::open_with_env(default_env(), dir)
+    }
+
+    //Open the backups in dir through env, the Database backed up has to use the same Env
+    pub fn open_with_env(env: Arc<dyn Env>, dir: impl AsRef<Path>) -> Result<BackupEngine> {
+        let dir = dir.as_ref().to_path_buf();
+        for sub_dir in ["shared", "private", "meta"] {
+            env.create_dir_all(&dir.join(sub_dir))?;
+        }
+        let mut backups = BTreeMap::new();
+        for path in env.list_dir(&dir.join("meta"))? {
+            //Meta files still being written end in .tmp and do not parse as an id
+            let Some(id) = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.parse::<u32>().ok()) else {
+                continue;
+            };
+            backups.insert(id, read_meta(env.as_ref(), &path)?);
+        }
+        let next_id = match env.open_file(&dir.join(NEXT_ID)) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)?;
+                parse(contents.trim())?
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 1,
+            Err(err) => return Err(err.into()),
+        };
+        let next_id = backups.keys().next_back().map_or(next_id, |id| next_id.max(id + 1));
+        Ok(BackupEngine { env, dir, backups, next_id })
+    }
+
+    //Back up db, returns the id of the new backup
+    pub fn create_backup(&mut self, db: &Database) -> Result<u32> {
+        let id = self.next_id;
+        write_file(self.env.as_ref(), &self.dir.join(NEXT_ID), format!("{}\n", id + 1).as_bytes())?;
+        self.next_id = id + 1;
+        let tmp = self.dir.join("tmp");
+        let private = self.dir.join("private").join(id.to_string());
+        //Left behind by a backup that was cut short
+        let env = self.env.as_ref();
+        for dir in [&tmp, &private] {
+            if env.exists(dir) {
+                env.remove_dir_all(dir)?;
+            }
+        }
+        let timestamp = db.checkpoint_with_timestamp(&tmp)?;
+        env.create_dir(&private)?;
+
+        let mut paths = env.list_dir(&tmp)?;
+        paths.sort();
+        let mut files = Vec::new();
+        for path in paths {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let (size, checksum) = checksum_file(env, &path)?;
+            let stored = if path.extension().is_some_and(|e| e == "sst") {
+                let stem = path.file_stem().unwrap().to_string_lossy();
+                let stored = format!("shared/{}_{}.sst", stem, checksum);
+                let shared = self.dir.join(&stored);
+                if !env.exists(&shared) {
+                    //The checkpoint linked the table, copy it so the backup does not share it with the Database
+                    let partial = shared.with_extension("tmp");
+                    copy_file(env, &path, &partial)?;
+                    env.rename(&partial, &shared)?;
+                }
+                stored
+            } else {
+                let stored = format!("private/{}/{}", id, name);
+                env.rename(&path, &self.dir.join(&stored))?;
+                stored
+            };
+            files.push(BackupFile { name, stored, size, checksum });
+        }
+        env.remove_dir_all(&tmp)?;
+        //Every file has to be in place before the meta file lists it
+        for dir in [self.dir.join("shared"), private, self.dir.join("private")] {
+            env.sync_dir(&dir)?;
+        }
+
+        let backup = BackupInfo {
+            id,
+            created: db.clock().now_micros(),
+            timestamp,
+            size: files.iter().map(|file| file.size).sum(),
+            files,
+        };
+        write_meta(env, &self.dir.join("meta").join(id.to_string()), &backup)?;
+        self.backups.insert(id, backup);
+        Ok(id)
+    }
+
+    //Backups oldest first
+    pub fn backups(&self) -> Vec<&BackupInfo> {
+        self.backups.values().collect()
+    }
+
+    //Delete all but the keep newest backups and the tables none of the rest holds
+    pub fn purge_old_backups(&mut self, keep: usize) -> Result<()> {
+        let old: Vec<u32> = self.backups.keys().rev().skip(keep).copied().collect();
+        for id in old {
+            //The meta file goes first, so a purge cut short never leaves a backup missing files
+            self.env.remove_file(&self.dir.join("meta").join(id.to_string()))?;
+            self.backups.remove(&id);
+        }
+        self.env.sync_dir(&self.dir.join("meta"))?;
+        //Not synced, a file that comes back after a power loss is removed by the next purge
+        self.remove_unreferenced()
+    }
+
+    //Check that every file of the backup is there with the size and checksum it was backed up with
+    pub fn verify_backup(&self, id: u32) -> Result<()> {
+        let backup = self.backups.get(&id).ok_or(Error::UnknownBackup { id })?;
+        for file in backup.files.iter() {
+            let found = checksum_file(self.env.as_ref(), &self.dir.join(&file.stored));
+            check_file(id, file, found)?;
+        }
+        Ok(())
+    }
+
+    //Restore the backup into target_dir, which must not exist yet, and open it as a Database from there
+    pub fn restore(&self, id: u32, target_dir: impl AsRef<Path>) -> Result<()> {
+        let target = target_dir.as_ref();
+        let backup = self.backups.get(&id).ok_or(Error::UnknownBackup { id })?;
+        let env = self.env.as_ref();
+        if let Some(parent) = target.parent() {
+            env.create_dir_all(parent)?;
+        }
+        env.create_dir(target)?;
+        //Like a checkpoint the MANIFEST comes last, a restore cut short does not open as a Database
+        let (manifest, rest): (Vec<&BackupFile>, Vec<&BackupFile>) = backup.files.iter().partition(|file| file.name == "MANIFEST");
+        for file in rest.into_iter().chain(manifest) {
+            if file.name == "MANIFEST" {
+                env.sync_dir(target)?;
+            }
+            let copied = copy_file(env, &self.dir.join(&file.stored), &target.join(&file.name));
+            check_file(id, file, copied)?;
+        }
+        env.sync_dir(target)?;
+        env.sync_dir(target.parent().unwrap_or(Path::new(".")))?;
+        Ok(())
+    }
+
+    //Remove the shared tables and private files no backup holds anymore
+    fn remove_unreferenced(&self) -> Result<()> {
+        let referenced: HashSet<&str> = self
+            .backups
+            .values()
+            .flat_map(|backup| backup.files.iter())
+            .map(|file| file.stored.as_str())
+            .collect();
+        for path in self.env.list_dir(&self.dir.join("shared"))? {
+            let stored = format!("shared/{}", path.file_name().unwrap().to_string_lossy());
+            if !referenced.contains(stored.as_str()) {
+                self.env.remove_file(&path)?;
+            }
+        }
+        for path in self.env.list_dir(&self.dir.join("private"))? {
+            let id = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.parse::<u32>().ok());
+            if !id.is_some_and(|id| self.backups.contains_key(&id)) {
+                self.env.remove_dir_all(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+//Size and CRC-32 of the file
+fn checksum_file(env: &dyn Env, path: &Path) -> io::Result<(u64, u32)> {
+    let mut file = env.open_file(path)?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut size = 0;
+    let mut checksum = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok((size, checksum));
+        }
+        size += read as u64;
+        checksum = crc32_update(checksum, &buffer[..read]);
+    }
+}
+
+//Copy the file and sync the copy, returns the size and CRC-32 of what was copied
+fn copy_file(env: &dyn Env, from: &Path, to: &Path) -> io::Result<(u64, u32)> {
+    let mut source = env.open_file(from)?;
+    let mut copy = env.create_file(to)?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut size = 0;
+    let mut checksum = 0;
+    loop {
+        let read = source.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        copy.write_all(&buffer[..read])?;
+        size += read as u64;
+        checksum = crc32_update(checksum, &buffer[..read]);
+    }
+    copy.sync()?;
+    Ok((size, checksum))
+}
+
+//A stored file that is gone or differs from what was backed up makes the backup corrupted
+fn check_file(id: u32, file: &BackupFile, found: io::Result<(u64, u32)>) -> Result<()> {
+    match found {
+        Ok(found) if found == (file.size, file.checksum) => Ok(()),
+        Ok(_) => Err(Error::BackupCorrupted { id, file: file.stored.clone() }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Err(Error::BackupCorrupted { id, file: file.stored.clone() }),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn write_meta(env: &dyn Env, path: &Path, backup: &BackupInfo) -> io::Result<()> {
+    let mut contents = format!(
+        "{}\nid {}\ncreated {}\ntimestamp {}\n",
+        HEADER, backup.id, backup.created, backup.timestamp
+    );
+    for file in backup.files.iter() {
+        contents += &format!("file {} {} {} {}\n", file.name, file.stored, file.size, file.checksum);
+    }
+    write_file(env, path, contents.as_bytes())
+}
+
+//Write the file as .tmp and rename it into place, so it is either all there or not changed
+fn write_file(env: &dyn Env, path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = env.create_file(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync()?;
+    env.rename(&tmp_path, path)?;
+    env.sync_dir(path.parent().unwrap())
+}
+
+fn read_meta(env: &dyn Env, path: &Path) -> io::Result<BackupInfo> {
+    let mut contents = String::new();
+    env.open_file(path)?.read_to_string(&mut contents)?;
+    let mut lines = contents.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(corrupt("unknown header"));
+    }
+    let mut backup = BackupInfo {
+        id: 0,
+        created: 0,
+        timestamp: 0,
+        size: 0,
+        files: Vec::new(),
+    };
+    for line in lines {
+        let (tag, rest) = line.split_once(' ').ok_or_else(|| corrupt(line))?;
+        match tag {
+            "id" => backup.id = parse(rest)?,
+            "created" => backup.created = parse(rest)?,
+            "timestamp" => backup.timestamp = parse(rest)?,
+            "file" => {
+                let parts: Vec<&str> = rest.split(' ').collect();
+                let [name, stored, size, checksum] = parts[..] else {
+                    return Err(corrupt(line));
+                };
+                backup.files.push(BackupFile {
+                    name: name.to_owned(),
+                    stored: stored.to_owned(),
+                    size: parse(size)?,
+                    checksum: parse(checksum)?,
+                });
+            }
+            _ => return Err(corrupt(line)),
+        }
+    }
+    backup.size = backup.files.iter().map(|file| file.size).sum();
+    Ok(backup)
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> io::Result<T> {
+    value.parse().map_err(|_| corrupt(value))
+}
+
+fn corrupt(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("corrupt backup meta entry: {}", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backup::BackupEngine;
+    use crate::clock::Clock;
+    use crate::database::Database;
+    use crate::error::Error;
+    use crate::env::{Env, MemEnv};
+    use crate::options::Options;
+    use crate::simulation::Simulation;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_backup_and_restore() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let options = Options::new().env(env.clone());
+        let dir = Path::new("test");
+        let db_dir = dir.join("db");
+        let backup_dir = dir.join("backups");
+        let shared_files = || env.list_dir(&backup_dir.join("shared")).unwrap().len();
+
+        let db = Database::open(&db_dir, &options).unwrap();
+        let mut engine = BackupEngine::open_with_env(env.clone(), &backup_dir).unwrap();
+        db.set(b"Badri", b"Krishnan").unwrap();
+        db.flush().unwrap();
+        db.set(b"Keerthi", b"Krishnan").unwrap();
+        let first = engine.create_backup(&db).unwrap();
+        assert_eq!(shared_files(), 1);
+
+        //Only the new table is copied, the first one is shared by both backups
+        db.delete(b"Badri").unwrap();
+        db.flush().unwrap();
+        db.set(b"Lavanya", b"Krishnan").unwrap();
+        let second = engine.create_backup(&db).unwrap();
+        assert_eq!(shared_files(), 2);
+        assert_eq!(engine.backups().len(), 2);
+        assert!(engine.backups()[0].timestamp < engine.backups()[1].timestamp);
+        drop(db);
+
+        let mut engine = BackupEngine::open_with_env(env.clone(), &backup_dir).unwrap();
+        engine.verify_backup(first).unwrap();
+        engine.verify_backup(second).unwrap();
+        engine.restore(first, dir.join("first")).unwrap();
+        engine.restore(second, dir.join("second")).unwrap();
+        assert!(engine.restore(second, dir.join("second")).is_err());
+
+        let restored = Database::open(dir.join("first"), &options.clone().create_if_missing(false)).unwrap();
+        assert_eq!(restored.get(b"Badri").unwrap().unwrap().value(), b"Krishnan");
+        assert_eq!(restored.get(b"Keerthi").unwrap().unwrap().value(), b"Krishnan");
+        assert!(restored.get(b"Lavanya").unwrap().is_none());
+        drop(restored);
+        let restored = Database::open(dir.join("second"), &options.clone().create_if_missing(false)).unwrap();
+        assert!(restored.get(b"Badri").unwrap().is_none());
+        assert_eq!(restored.get(b"Lavanya").unwrap().unwrap().value(), b"Krishnan");
+        drop(restored);
+
+        //Purging the first backup keeps the table the second one still holds
+        engine.purge_old_backups(1).unwrap();
+        assert_eq!(engine.backups().len(), 1);
+        assert_eq!(shared_files(), 2);
+        assert!(matches!(engine.verify_backup(first), Err(Error::UnknownBackup { id }) if id == first));
+        assert!(matches!(engine.restore(first, dir.join("gone")), Err(Error::UnknownBackup { .. })));
+        engine.verify_backup(second).unwrap();
+
+        let wal = env
+            .list_dir(&backup_dir.join("private").join(second.to_string()))
+            .unwrap()
+            .into_iter()
+            .find(|path| path.extension().is_some_and(|e| e == "wal"))
+            .unwrap();
+        env.append_file(&wal).unwrap().write_all(b"garbage").unwrap();
+        assert!(matches!(engine.verify_backup(second), Err(Error::BackupCorrupted { .. })));
+        assert!(matches!(engine.restore(second, dir.join("corrupted")), Err(Error::BackupCorrupted { .. })));
+
+        engine.purge_old_backups(0).unwrap();
+        assert_eq!(shared_files(), 0);
+        assert_eq!(env.list_dir(&backup_dir.join("private")).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_backup_ids_not_reused() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let db = Database::open("db", &Options::new().env(env.clone())).unwrap();
+        db.set(b"Badri", b"Krishnan").unwrap();
+
+        let mut engine = BackupEngine::open_with_env(env.clone(), "backups").unwrap();
+        assert_eq!(engine.create_backup(&db).unwrap(), 1);
+        assert_eq!(engine.create_backup(&db).unwrap(), 2);
+        engine.purge_old_backups(0).unwrap();
+        assert_eq!(engine.create_backup(&db).unwrap(), 3);
+
+        //Also when the engine is opened again with no backup left
+        engine.purge_old_backups(0).unwrap();
+        let mut engine = BackupEngine::open_with_env(env.clone(), "backups").unwrap();
+        assert!(engine.backups().is_empty());
+        assert_eq!(engine.create_backup(&db).unwrap(), 4);
+
+        //A directory from before NEXT_ID goes on after its newest backup
+        env.remove_file(Path::new("backups/NEXT_ID")).unwrap();
+        let mut engine = BackupEngine::open_with_env(env.clone(), "backups").unwrap();
+        assert_eq!(engine.create_backup(&db).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_backup_time_from_clock() {
+        //Taken under a simulation the backup is stamped with its clock, a run replays from the seed
+        let simulation = Simulation::with_stalled_clock(3);
+        let db = Database::open("db", &simulation.options()).unwrap();
+        db.set(b"Badri", b"Krishnan").unwrap();
+        let mut engine = BackupEngine::open_with_env(simulation.env().clone(), "backups").unwrap();
+        let id = engine.create_backup(&db).unwrap();
+        assert_eq!(engine.backups()[0].id, id);
+        assert_eq!(engine.backups()[0].created, simulation.now_micros());
+    }
+}
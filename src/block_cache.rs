@@ -0,0 +1,113 @@
+//Block Cache - decoded data blocks kept in memory across reads
+
+/*
+Shared by every table of a Database and bounded by the size the blocks take on disk. Once it is full the
+block used least recently is evicted first. Blocks are keyed by the file number of their table and their
+offset in it, file numbers are never reused so a compacted table's blocks just age out.
+*/
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::mem_table::Record;
+
+type BlockKey = (u64, u64);
+
+pub struct BlockCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+struct CacheInner {
+    blocks: HashMap<BlockKey, CachedBlock>,
+    //Last use of every cached block, oldest first
+    recency: BTreeMap<u64, BlockKey>,
+    usage: usize,
+    clock: u64,
+}
+
+struct CachedBlock {
+    records: Arc<Vec<Record>>,
+    charge: usize,
+    last_use: u64,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            capacity,
+            inner: Mutex::new(CacheInner {
+                blocks: HashMap::new(),
+                recency: BTreeMap::new(),
+                usage: 0,
+                clock: 0,
+            }),
+        }
+    }
+
+    //Bytes of the blocks cached right now
+    pub fn usage(&self) -> usize {
+        self.inner.lock().unwrap().usage
+    }
+
+    pub fn get(&self, file_number: u64, offset: u64) -> Option<Arc<Vec<Record>>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+        let block = inner.blocks.get_mut(&(file_number, offset))?;
+        let previous = std::mem::replace(&mut block.last_use, clock);
+        let records = block.records.clone();
+        inner.recency.remove(&previous);
+        inner.recency.insert(clock, (file_number, offset));
+        Some(records)
+    }
+
+    //Cache a block that takes charge bytes, blocks bigger than the whole cache are not kept
+    pub fn insert(&self, file_number: u64, offset: u64, records: Arc<Vec<Record>>, charge: usize) {
+        if charge > self.capacity {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+        let key = (file_number, offset);
+        if let Some(old) = inner.blocks.insert(key, CachedBlock { records, charge, last_use: clock }) {
+            inner.recency.remove(&old.last_use);
+            inner.usage -= old.charge;
+        }
+        inner.recency.insert(clock, key);
+        inner.usage += charge;
+        while inner.usage > self.capacity {
+            let Some((_, oldest)) = inner.recency.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = inner.blocks.remove(&oldest) {
+                inner.usage -= evicted.charge;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block_cache::BlockCache;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = BlockCache::new(100);
+        cache.insert(1, 0, Arc::new(Vec::new()), 40);
+        cache.insert(1, 40, Arc::new(Vec::new()), 40);
+        assert!(cache.get(1, 0).is_some());
+        //Over capacity, the block at offset 40 was used last the longest ago
+        cache.insert(2, 0, Arc::new(Vec::new()), 40);
+        assert!(cache.get(1, 40).is_none());
+        assert!(cache.get(1, 0).is_some());
+        assert!(cache.get(2, 0).is_some());
+        assert_eq!(cache.usage(), 80);
+
+        cache.insert(3, 0, Arc::new(Vec::new()), 101);
+        assert!(cache.get(3, 0).is_none());
+        assert_eq!(cache.usage(), 80);
+    }
+}
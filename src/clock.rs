@@ -0,0 +1,55 @@
+//Clock - where the Database reads the time from
+
+/*
+Write timestamps, the time reads and expiry checks are done at, the periodic WAL sync and the names of new
+WALs all come from the Clock in Options. SystemClock is the time of day. A Simulation is a clock that only
+moves when it is read or told to, so a run on it can be repeated exactly.
+
+The Database copes with a clock that stalls or steps backwards, timestamps keep going up regardless.
+*/
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Send + Sync {
+    //Current time in microseconds since the UNIX epoch
+    fn now_micros(&self) -> u128;
+}
+
+pub fn default_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+pub struct SystemClock;
+
+//The only place the time of day is read, everything else asks a Clock
+impl Clock for SystemClock {
+    fn now_micros(&self) -> u128 {
+        SystemTime::now()
+          .duration_since(UNIX_EPOCH)
+          .unwrap()
+          .as_micros()
+    }
+}
+
+//Clock that only moves when a test sets it, to stall it or step it back
+#[cfg(test)]
+pub(crate) struct ManualClock(std::sync::Mutex<u128>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub(crate) fn new(now: u128) -> ManualClock {
+        ManualClock(std::sync::Mutex::new(now))
+    }
+
+    pub(crate) fn set(&self, now: u128) {
+        *self.0.lock().unwrap() = now;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now_micros(&self) -> u128 {
+        *self.0.lock().unwrap()
+    }
+}
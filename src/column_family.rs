@@ -0,0 +1,289 @@
+//Column Family - a separate keyspace inside the Database
+
+/*
+Each column family has its own MemTable, its own tables on disk and its own options, so a family
+full of small hot keys and one full of large cold values can be tuned apart. They all share the
+one WAL, which is what lets a WriteBatch touching several families commit atomically.
+
+A read looks at the MemTable first and then the tables from newest to oldest, and stops at the
+first version that is not a merge operand since nothing older can change what the key reads as.
+
+A flush writes the MemTable of every column family to a new table so the WAL can be dropped.
+Once a family has compaction_trigger tables they are all compacted into one.
+*/
+
+use std::cmp::Ordering;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::compaction;
+use crate::database::DatabaseRecord;
+use crate::mem_table::{HistoryRetention, MemTable, Record};
+use crate::merge_operator::MergeOperator;
+use crate::prefix_extractor::PrefixExtractor;
+use crate::sstable::{table_path, SSTable, TableOptions};
+
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+pub const DEFAULT_COLUMN_FAMILY_ID: u32 = 0;
+
+#[derive(Clone)]
+pub struct ColumnFamilyOptions {
+    //Flush once the column family's MemTable holds this many bytes
+    pub write_buffer_size: usize,
+    //Compact the column family's tables into one once it has this many
+    pub compaction_trigger: usize,
+    //Builds the prefix filters of the family's tables and checks them on prefix scans
+    pub prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    //Combines the operands written with merge, keys holding operands read as missing without one
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    pub history_retention: HistoryRetention,
+}
+
+impl Default for ColumnFamilyOptions {
+    fn default() -> Self {
+        ColumnFamilyOptions {
+            write_buffer_size: 4 * 1024 * 1024,
+            compaction_trigger: 4,
+            prefix_extractor: None,
+            merge_operator: None,
+            history_retention: HistoryRetention::default(),
+        }
+    }
+}
+
+pub(crate) struct ColumnFamily {
+    pub(crate) id: u32,
+    pub(crate) name: String,
+    pub(crate) options: ColumnFamilyOptions,
+    pub(crate) mem_table: MemTable,
+    //Oldest first, every table only holds versions newer than the ones before it
+    pub(crate) tables: Vec<SSTable>,
+    table_options: TableOptions,
+}
+
+impl ColumnFamily {
+    pub(crate) fn new(
+        id: u32,
+        name: &str,
+        options: ColumnFamilyOptions,
+        table_options: TableOptions,
+        mut mem_table: MemTable,
+        tables: Vec<SSTable>,
+    ) -> ColumnFamily {
+        mem_table.set_history_retention(options.history_retention);
+        ColumnFamily {
+            id,
+            name: name.to_owned(),
+            options,
+            mem_table,
+            tables,
+            table_options,
+        }
+    }
+
+    //Change the options, history the new retention does not cover is dropped from the MemTable right away
+    pub(crate) fn set_options(&mut self, options: ColumnFamilyOptions) {
+        self.mem_table.set_history_retention(options.history_retention);
+        self.mem_table.prune();
+        self.options = options;
+    }
+
+    pub(crate) fn table_numbers(&self) -> Vec<u64> {
+        self.tables.iter().map(|table| table.file_number()).collect()
+    }
+
+    //Drop the versions a WAL replay put in the MemTable that the tables hold already
+    //Every write to a family is newer than its last flush, so anything older came from a WAL that was flushed
+    pub(crate) fn drop_flushed(&mut self) {
+        if let Some(flushed) = self.tables.iter().map(|table| table.max_timestamp()).max() {
+            self.mem_table.drop_through(flushed);
+        }
+    }
+
+    //Newest timestamp the family holds, in the MemTable or on disk
+    pub(crate) fn max_timestamp(&self) -> u128 {
+        let tables = self.tables.iter().map(|table| table.max_timestamp());
+        self.mem_table.entries().iter().map(|e| e.timestamp).chain(tables).max().unwrap_or(0)
+    }
+
+    //Value of the key as of `at` with values that expired by `now` read as missing
+    //The table blocks read are only kept in the block cache when fill_cache is set
+    pub(crate) fn read(&self, key: &[u8], at: u128, now: u128, fill_cache: bool) -> io::Result<Option<DatabaseRecord>> {
+        let versions = self.versions_at(key, at, fill_cache)?;
+        Ok(resolve(key, &versions, now, self.options.merge_operator.as_deref()))
+    }
+
+    //Versions of the key written at or before `at`, newest first, down to the first one that is not a merge operand
+    pub(crate) fn versions_at(&self, key: &[u8], at: u128, fill_cache: bool) -> io::Result<Vec<Record>> {
+        Ok(self.collect_versions(&[key], at, fill_cache)?.pop().unwrap_or_default())
+    }
+
+    //Every version of the key still kept in the MemTable and the tables, newest first
+    pub(crate) fn versions(&self, key: &[u8]) -> io::Result<Vec<Record>> {
+        let mut versions: Vec<Record> = self.mem_table.versions(key).cloned().collect();
+        for table in self.tables.iter().rev() {
+            if table.may_contain_key(key) {
+                versions.extend(table.versions(key)?);
+            }
+        }
+        Ok(versions)
+    }
+
+    //Values of many keys as of `at` and expired as of `now`, keys have to be sorted
+    pub(crate) fn multi_get(&self, sorted_keys: &[&[u8]], at: u128, now: u128, fill_cache: bool) -> io::Result<Vec<Option<DatabaseRecord>>> {
+        let operator = self.options.merge_operator.as_deref();
+        Ok(self
+            .collect_versions(sorted_keys, at, fill_cache)?
+            .iter()
+            .zip(sorted_keys)
+            .map(|(versions, key)| resolve(key, versions, now, operator))
+            .collect())
+    }
+
+    //The MemTable is walked once for all the keys, then every table is asked only for the keys
+    //that are still missing a version that is not a merge operand, in one batch per table
+    fn collect_versions(&self, sorted_keys: &[&[u8]], at: u128, fill_cache: bool) -> io::Result<Vec<Vec<Record>>> {
+        let mut versions: Vec<Vec<Record>> = Vec::with_capacity(sorted_keys.len());
+        let mut complete: Vec<bool> = Vec::with_capacity(sorted_keys.len());
+        for (key, newest) in sorted_keys.iter().zip(self.mem_table.multi_get(sorted_keys)) {
+            let key_versions: Vec<Record> = match newest {
+                Some(newest) if newest.timestamp <= at && !newest.merge => vec![newest.clone()],
+                Some(_) => self.mem_table.versions_at(key, at).cloned().collect(),
+                None => Vec::new(),
+            };
+            complete.push(key_versions.last().is_some_and(|r| !r.merge));
+            versions.push(key_versions);
+        }
+
+        for table in self.tables.iter().rev() {
+            let pending: Vec<usize> = (0..sorted_keys.len())
+                .filter(|idx| !complete[*idx] && table.may_contain_key(sorted_keys[*idx]))
+                .collect();
+            if pending.is_empty() {
+                continue;
+            }
+            let keys: Vec<&[u8]> = pending.iter().map(|idx| sorted_keys[*idx]).collect();
+            for (idx, found) in pending.into_iter().zip(table.multi_get(&keys, fill_cache)?) {
+                for record in found.into_iter().filter(|r| r.timestamp <= at) {
+                    complete[idx] = !record.merge;
+                    versions[idx].push(record);
+                    if complete[idx] {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(versions)
+    }
+
+    //Live records whose key starts with prefix as of `at` and expired as of `now`, in key order
+    //Tables whose key range or prefix filter rules the prefix out are not read at all
+    pub(crate) fn scan_prefix(&self, prefix: &[u8], at: u128, now: u128, fill_cache: bool) -> io::Result<Vec<DatabaseRecord>> {
+        let mut records: Vec<Record> = self
+            .mem_table
+            .scan_prefix_versions(prefix)
+            .filter(|r| r.timestamp <= at)
+            .cloned()
+            .collect();
+        let extractor = self.options.prefix_extractor.as_deref();
+        for table in self.tables.iter().rev() {
+            if table.may_contain_prefix(prefix, extractor) {
+                records.extend(table.scan_prefix(prefix, fill_cache)?.into_iter().filter(|r| r.timestamp <= at));
+            }
+        }
+        //Stable so the MemTable and newer tables win a tie on timestamp
+        let comparator = &self.table_options.comparator;
+        records.sort_by(|a, b| comparator.compare(&a.key, &b.key).then(b.timestamp.cmp(&a.timestamp)));
+
+        let operator = self.options.merge_operator.as_deref();
+        Ok(records
+            .chunk_by(|a, b| comparator.compare(&a.key, &b.key) == Ordering::Equal)
+            .filter_map(|versions| resolve(&versions[0].key, versions, now, operator))
+            .collect())
+    }
+
+    //Write the MemTable to a new table and start an empty one, returns false when there was nothing to write
+    //Values that expired before the oldest snapshot are written as tombstones, nothing can read them anymore
+    //and a tombstone still hides the older versions in the tables under it
+    pub(crate) fn flush(&mut self, dir: &Path, file_number: u64, snapshots: &[u128], now: u128) -> io::Result<bool> {
+        if self.mem_table.is_empty() {
+            return Ok(false);
+        }
+        let horizon = snapshots.first().map_or(now, |oldest| (*oldest).min(now));
+        let records: Vec<Record> = self
+            .mem_table
+            .entries()
+            .iter()
+            .map(|record| match record.expires_at {
+                Some(expires_at) if expires_at <= horizon && !record.merge => Record {
+                    value: None,
+                    deleted: true,
+                    expires_at: None,
+                    ..record.clone()
+                },
+                _ => record.clone(),
+            })
+            .collect();
+
+        let path = table_path(dir, file_number);
+        SSTable::write(&path, records.iter(), self.options.prefix_extractor.as_deref(), &self.table_options)?;
+        self.tables.push(SSTable::open(&path, file_number, &self.table_options)?);
+
+        let mut mem_table = MemTable::with_comparator(self.table_options.comparator.clone());
+        mem_table.set_history_retention(self.options.history_retention);
+        mem_table.set_snapshots(snapshots.to_vec());
+        self.mem_table = mem_table;
+        Ok(true)
+    }
+
+    //Compact every table into one, returns the tables it replaced so their files can be deleted
+    //once the MANIFEST no longer lists them
+    pub(crate) fn compact(&mut self, dir: &Path, file_number: u64, snapshots: &[u128], now: u128) -> io::Result<Vec<SSTable>> {
+        let mut records: Vec<Record> = Vec::new();
+        for table in self.tables.iter().rev() {
+            records.extend(table.records()?);
+        }
+        let comparator = &self.table_options.comparator;
+        records.sort_by(|a, b| comparator.compare(&a.key, &b.key).then(b.timestamp.cmp(&a.timestamp)));
+        let records = compaction::compact(records, &self.options, comparator, snapshots, now);
+
+        let mut tables = Vec::new();
+        if !records.is_empty() {
+            let path = table_path(dir, file_number);
+            SSTable::write(&path, records.iter(), self.options.prefix_extractor.as_deref(), &self.table_options)?;
+            tables.push(SSTable::open(&path, file_number, &self.table_options)?);
+        }
+        Ok(std::mem::replace(&mut self.tables, tables))
+    }
+}
+
+//Turn the versions of a key, newest first, into what a read of the key returns with values that expired by `now` missing
+//Merge operands are combined with the newest version under them that is not an operand
+fn resolve(key: &[u8], versions: &[Record], now: u128, operator: Option<&dyn MergeOperator>) -> Option<DatabaseRecord> {
+    let newest = versions.first()?;
+    if !newest.merge {
+        return Some(newest).filter(|r| r.is_live(now)).map(DatabaseRecord::from);
+    }
+
+    let operator = operator?;
+    let mut operands: Vec<&[u8]> = Vec::new();
+    let mut existing = None;
+    for version in versions {
+        if !version.merge {
+            existing = Some(version).filter(|r| r.is_live(now)).and_then(|r| r.value.as_deref());
+            break;
+        }
+        operands.push(version.value.as_deref().unwrap_or_default());
+    }
+    operands.reverse();
+    let value = operator.full_merge(key, existing, &operands)?;
+    Some(DatabaseRecord::from(&Record {
+        key: key.to_owned(),
+        value: Some(value),
+        timestamp: newest.timestamp,
+        deleted: false,
+        expires_at: None,
+        merge: false,
+    }))
+}
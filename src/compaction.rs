@@ -0,0 +1,150 @@
+//Compaction - rewrite every table of a column family as one
+
+/*
+All the versions from the tables go through a MemTable with the family's history retention and the
+live snapshots, which drops every version that nothing can read anymore the same way the MemTable
+does for new writes. Since the output replaces every table there is nothing older under it, so:
+
+- a chain of merge operands on top of a key is folded into a single value with the merge operator,
+  as long as no snapshot can read the key part way up the chain and no extra history is kept
+- a tombstone at the bottom of a key hides nothing anymore and is dropped
+- a value at the bottom of a key that expired before the oldest snapshot is dropped
+*/
+
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use crate::column_family::ColumnFamilyOptions;
+use crate::comparator::Comparator;
+use crate::mem_table::{HistoryRetention, MemTable, Record};
+
+//Compact records sorted by key with comparator and then newest first into what the new table should hold
+pub fn compact(
+    records: Vec<Record>,
+    options: &ColumnFamilyOptions,
+    comparator: &Arc<dyn Comparator>,
+    snapshots: &[u128],
+    now: u128,
+) -> Vec<Record> {
+    let mut table = MemTable::with_comparator(comparator.clone());
+    table.set_history_retention(options.history_retention);
+    table.set_snapshots(snapshots.to_vec());
+    for record in records {
+        table.insert(record);
+    }
+    //Prune again now that the newest timestamp of the whole family is known for the retention window
+    table.prune();
+
+    let horizon = snapshots.first().map_or(now, |oldest| (*oldest).min(now));
+    let fold = options.history_retention == HistoryRetention::default();
+    let mut output = Vec::with_capacity(table.len());
+    for versions in table.entries().chunk_by(|a, b| comparator.compare(&a.key, &b.key) == Ordering::Equal) {
+        let mut versions = versions.to_vec();
+        if fold {
+            fold_merge_operands(&mut versions, options, snapshots);
+        }
+        while let Some(oldest) = versions.last() {
+            let expired = !oldest.merge && oldest.expires_at.is_some_and(|expires_at| expires_at <= horizon);
+            if !oldest.deleted && !expired {
+                break;
+            }
+            versions.pop();
+        }
+        output.extend(versions);
+    }
+    output
+}
+
+//Replace the merge operands on top of the key and the value under them with the merged value
+fn fold_merge_operands(versions: &mut Vec<Record>, options: &ColumnFamilyOptions, snapshots: &[u128]) {
+    let Some(operator) = options.merge_operator.as_deref() else {
+        return;
+    };
+    if !versions[0].merge {
+        return;
+    }
+    let operand_count = versions.iter().take_while(|r| r.merge).count();
+    let base = versions.get(operand_count);
+    //A value that expires would stop counting under the operands later, the fold cannot keep that
+    if base.is_some_and(|b| b.expires_at.is_some()) {
+        return;
+    }
+    let bottom = base.unwrap_or(&versions[operand_count - 1]).timestamp;
+    let newest = versions[0].timestamp;
+    if snapshots.iter().any(|s| *s >= bottom && *s < newest) {
+        return;
+    }
+
+    let existing = base.filter(|b| !b.deleted).and_then(|b| b.value.as_deref());
+    let operands: Vec<&[u8]> = versions[..operand_count]
+        .iter()
+        .rev()
+        .map(|r| r.value.as_deref().unwrap_or_default())
+        .collect();
+    //An operator that cannot merge makes the key read as missing, a tombstone keeps it that way
+    let value = operator.full_merge(&versions[0].key, existing, &operands);
+    let folded = Record {
+        key: versions[0].key.clone(),
+        deleted: value.is_none(),
+        value,
+        timestamp: newest,
+        expires_at: None,
+        merge: false,
+    };
+    let replaced = operand_count + usize::from(base.is_some());
+    versions.splice(0..replaced, [folded]);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::column_family::ColumnFamilyOptions;
+    use crate::compaction::compact;
+    use crate::comparator::{BytewiseComparator, Comparator};
+    use crate::mem_table::Record;
+    use crate::merge_operator::U64AddOperator;
+    use std::sync::Arc;
+
+    fn record(key: &[u8], value: Option<u64>, timestamp: u128, merge: bool) -> Record {
+        Record {
+            key: key.to_vec(),
+            value: value.map(|v| v.to_le_bytes().to_vec()),
+            timestamp,
+            deleted: value.is_none(),
+            expires_at: None,
+            merge,
+        }
+    }
+
+    #[test]
+    fn test_compact() {
+        let options = ColumnFamilyOptions {
+            merge_operator: Some(Arc::new(U64AddOperator)),
+            ..ColumnFamilyOptions::default()
+        };
+        let mut expired = record(b"Session", Some(1), 10, false);
+        expired.expires_at = Some(50);
+        let records = vec![
+            record(b"Badri", None, 30, false),
+            record(b"Badri", Some(1), 20, false),
+            record(b"Car", Some(2), 40, true),
+            record(b"Car", Some(3), 30, true),
+            record(b"Car", Some(10), 20, false),
+            record(b"Keerthi", Some(5), 20, false),
+            record(b"Keerthi", Some(4), 10, false),
+            expired,
+        ];
+
+        let comparator: Arc<dyn Comparator> = Arc::new(BytewiseComparator);
+        let output = compact(records.clone(), &options, &comparator, &[], 100);
+        let summary: Vec<(&[u8], u128, bool)> = output.iter().map(|r| (r.key.as_slice(), r.timestamp, r.merge)).collect();
+        assert_eq!(summary, vec![(b"Car".as_slice(), 40, false), (b"Keerthi".as_slice(), 20, false)]);
+        assert_eq!(output[0].value.as_ref().unwrap(), &15u64.to_le_bytes());
+
+        //A snapshot inside the merge chain keeps it, one before the expiry keeps the value
+        let output = compact(records, &options, &comparator, &[35], 100);
+        let car: Vec<u128> = output.iter().filter(|r| r.key == b"Car").map(|r| r.timestamp).collect();
+        assert_eq!(car, vec![40, 30, 20]);
+        assert!(output.iter().any(|r| r.key == b"Session"));
+        assert!(!output.iter().any(|r| r.key == b"Badri"));
+    }
+}
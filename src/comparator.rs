@@ -0,0 +1,121 @@
+//Comparator - the order keys are kept in
+
+/*
+The MemTable, the index blocks of the tables, scans and compaction all order keys with the Database's
+comparator. The name of the comparator is written to the MANIFEST and to every table, since data sorted
+one way cannot be searched the other way, and opening the Database with a different comparator fails.
+
+Keys the comparator calls Equal are versions of the same key, the MemTable, the tables and compaction
+all find the versions of a key with the comparator. Transactions and row locks still tell keys apart by
+their bytes, so a comparator that calls different bytes Equal should not be used with them.
+*/
+
+use std::cmp::Ordering;
+
+pub trait Comparator: Send + Sync {
+    //Name of the comparator, persisted with the data it sorted
+    fn name(&self) -> &str;
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+
+    //Do all the keys starting with a prefix sort right after the prefix and next to each other
+    //Prefix scans can only seek to the prefix and stop at the first key past it when this holds,
+    //otherwise they have to look at every key
+    fn groups_prefixes(&self) -> bool {
+        false
+    }
+}
+
+//Lexicographic byte order, the default
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &str {
+        "lanadb.BytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn groups_prefixes(&self) -> bool {
+        true
+    }
+}
+
+//Lexicographic byte order backwards, largest key first
+pub struct ReverseBytewiseComparator;
+
+impl Comparator for ReverseBytewiseComparator {
+    fn name(&self) -> &str {
+        "lanadb.ReverseBytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+//Keys that are all ASCII digits sort by the number they spell, so "9" comes before "10"
+//Numbers written with leading zeros come after the same number without them
+//Every other key sorts after the numbers in byte order
+pub struct NumericComparator;
+
+impl Comparator for NumericComparator {
+    fn name(&self) -> &str {
+        "lanadb.NumericComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let is_number = |key: &[u8]| !key.is_empty() && key.iter().all(u8::is_ascii_digit);
+        match (is_number(a), is_number(b)) {
+            (true, true) => {
+                let trim = |key: &[u8]| -> usize { key.iter().take_while(|d| **d == b'0').count().min(key.len() - 1) };
+                let (a_digits, b_digits) = (&a[trim(a)..], &b[trim(b)..]);
+                a_digits
+                    .len()
+                    .cmp(&b_digits.len())
+                    .then_with(|| a_digits.cmp(b_digits))
+                    .then_with(|| a.len().cmp(&b.len()))
+            }
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (false, false) => a.cmp(b),
+        }
+    }
+}
+
+//ASCII letters compare without case, keys that only differ in case are one key
+//Only for tests, to check that equality goes by the comparator and not by the bytes
+#[cfg(test)]
+pub(crate) struct CaseInsensitiveComparator;
+
+#[cfg(test)]
+impl Comparator for CaseInsensitiveComparator {
+    fn name(&self) -> &str {
+        "lanadb.CaseInsensitiveComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.iter().map(u8::to_ascii_lowercase).cmp(b.iter().map(u8::to_ascii_lowercase))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::comparator::{BytewiseComparator, Comparator, NumericComparator, ReverseBytewiseComparator};
+
+    fn sorted(comparator: &dyn Comparator, keys: &[&'static [u8]]) -> Vec<&'static [u8]> {
+        let mut keys = keys.to_vec();
+        keys.sort_by(|a, b| comparator.compare(a, b));
+        keys
+    }
+
+    #[test]
+    fn test_orders() {
+        let keys: Vec<&[u8]> = vec![b"10", b"9", b"b", b"A", b"010", b"a", b"0"];
+        assert_eq!(sorted(&BytewiseComparator, &keys), vec![b"0".as_slice(), b"010", b"10", b"9", b"A", b"a", b"b"]);
+        assert_eq!(sorted(&ReverseBytewiseComparator, &keys), vec![b"b".as_slice(), b"a", b"A", b"9", b"10", b"010", b"0"]);
+        assert_eq!(sorted(&NumericComparator, &keys), vec![b"0".as_slice(), b"9", b"10", b"010", b"A", b"a", b"b"]);
+    }
+}
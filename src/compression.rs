@@ -0,0 +1,166 @@
+//Compression - how the data blocks of a table are stored on disk
+
+/*
+Every table records the compression its data blocks were written with, so a Database can change its
+compression between opens and still read the tables written before.
+
+Lz is a small LZ77 coder: the block starts with its uncompressed length, then a run of tokens
+
++-----------------+----------...-----------+
+| Length (8B)     | Token | Token | ...    |
++-----------------+----------...-----------+
+
+Token byte below 0x80 = a literal run of token + 1 bytes follows
+Token byte 0x80 and up = copy (token & 0x7F) + 4 bytes starting Offset (2B) bytes back in the output
+*/
+
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = 0x7F + MIN_MATCH;
+const MAX_LITERALS: usize = 0x80;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 14;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz,
+}
+
+impl Compression {
+    //Id stored in the table
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz => 1,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Compression> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn compress(self, block: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => block.to_vec(),
+            Compression::Lz => lz_compress(block),
+        }
+    }
+
+    //None when the block is not valid for this compression
+    pub(crate) fn decompress(self, block: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Compression::None => Some(block.to_vec()),
+            Compression::Lz => lz_decompress(block),
+        }
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (word.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn lz_compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() / 2 + 16);
+    output.extend_from_slice(&(input.len() as u64).to_le_bytes());
+    //Last position each 4 byte sequence was seen at, plus one so zero means never
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let mut literal_start = 0;
+    let mut pos = 0;
+    while pos + MIN_MATCH <= input.len() {
+        let slot = hash(&input[pos..]);
+        let candidate = table[slot];
+        table[slot] = pos + 1;
+        if candidate > 0 && pos + 1 - candidate <= MAX_OFFSET {
+            let candidate = candidate - 1;
+            let len = input[candidate..]
+                .iter()
+                .zip(&input[pos..])
+                .take(MAX_MATCH)
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len >= MIN_MATCH {
+                push_literals(&mut output, &input[literal_start..pos]);
+                output.push(0x80 | (len - MIN_MATCH) as u8);
+                output.extend_from_slice(&((pos - candidate) as u16).to_le_bytes());
+                pos += len;
+                literal_start = pos;
+                continue;
+            }
+        }
+        pos += 1;
+    }
+    push_literals(&mut output, &input[literal_start..]);
+    output
+}
+
+fn push_literals(output: &mut Vec<u8>, literals: &[u8]) {
+    for run in literals.chunks(MAX_LITERALS) {
+        output.push((run.len() - 1) as u8);
+        output.extend_from_slice(run);
+    }
+}
+
+fn lz_decompress(input: &[u8]) -> Option<Vec<u8>> {
+    let len = u64::from_le_bytes(input.get(..8)?.try_into().ok()?) as usize;
+    let mut output: Vec<u8> = Vec::with_capacity(len);
+    let mut pos = 8;
+    while pos < input.len() {
+        let token = input[pos] as usize;
+        pos += 1;
+        if token < 0x80 {
+            let run = input.get(pos..pos + token + 1)?;
+            output.extend_from_slice(run);
+            pos += run.len();
+        } else {
+            let offset = u16::from_le_bytes(input.get(pos..pos + 2)?.try_into().ok()?) as usize;
+            pos += 2;
+            if offset == 0 || offset > output.len() {
+                return None;
+            }
+            //The copy can overlap what it writes, so it goes a byte at a time
+            let start = output.len() - offset;
+            for idx in 0..(token & 0x7F) + MIN_MATCH {
+                output.push(output[start + idx]);
+            }
+        }
+        if output.len() > len {
+            return None;
+        }
+    }
+    (output.len() == len).then_some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::Compression;
+
+    #[test]
+    fn test_round_trip() {
+        let mut repetitive = Vec::new();
+        for i in 0..500u32 {
+            repetitive.extend_from_slice(format!("user/{:04} Badri Krishnan ", i % 50).as_bytes());
+        }
+        let random: Vec<u8> = (0..3000).map(|_| rand::random::<u8>()).collect();
+        let blocks: Vec<&[u8]> = vec![b"", b"a", b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", &repetitive, &random];
+        for block in blocks {
+            for compression in [Compression::None, Compression::Lz] {
+                let compressed = compression.compress(block);
+                assert_eq!(compression.decompress(&compressed).unwrap(), block);
+            }
+        }
+        assert!(Compression::Lz.compress(&repetitive).len() < repetitive.len() / 4);
+
+        //A block cut short or pointing before its start is rejected
+        let compressed = Compression::Lz.compress(&repetitive);
+        assert!(Compression::Lz.decompress(&compressed[..compressed.len() - 1]).is_none());
+        assert!(Compression::Lz.decompress(&[1, 0, 0, 0, 0, 0, 0, 0, 0x80, 5, 0]).is_none());
+        assert_eq!(Compression::from_id(Compression::Lz.id()), Some(Compression::Lz));
+        assert_eq!(Compression::from_id(9), None);
+    }
+}
@@ -1,13 +1,41 @@
-use std::path::{PathBuf, Path, self};
-use crate::mem_table::MemTable;
-use crate::wal::WAL;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Read};
+use std::path::{PathBuf, Path};
+use crate::column_family::{ColumnFamily, ColumnFamilyOptions, DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_ID};
+use crate::block_cache::BlockCache;
+use crate::comparator::Comparator;
+use crate::clock::Clock;
+use crate::dir_lock::DirectoryLock;
+use crate::env::Env;
+use crate::mem_table::{HistoryRetention, MemTable, Record};
+use crate::lock_manager::LockManager;
+use crate::manifest::Manifest;
+use crate::merge_operator::MergeOperator;
+use crate::optimistic_transaction::OptimisticTransaction;
+use crate::options::{Options, ReadOptions, SyncPolicy, WriteOptions};
+use crate::pessimistic_transaction::PessimisticTransaction;
+use crate::prefix_extractor::PrefixExtractor;
+use crate::snapshot::{Snapshot, SnapshotList};
+use crate::sstable::{table_path, SSTable, TableOptions};
+use crate::error::{Error, ErrorSeverity, Result};
+use crate::ttl_sweeper::TtlSweeper;
+use crate::wal::{PreparedTransaction, WAL};
+use crate::wal_iterator::WALRecord;
+use crate::write_batch::WriteBatch;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+//How long a pessimistic transaction waits for a row lock before giving up
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Debug)]
 pub struct DatabaseRecord{
     key: Vec<u8>,
     value: Vec<u8>,
-    timestamp: u128
+    timestamp: u128,
+    deleted: bool,
+    merge: bool,
 }
 
 impl DatabaseRecord {
@@ -22,74 +50,2084 @@ impl DatabaseRecord {
     pub fn timestamp(&self) -> u128 {
         self.timestamp
     }
+
+    //Only version history returns deleted records, the value is empty for them
+    pub fn deleted(&self) -> bool {
+        self.deleted
+    }
+
+    //Only version history returns merge operands, the value is the operand
+    pub fn merge_operand(&self) -> bool {
+        self.merge
+    }
+}
+
+impl From<&Record> for DatabaseRecord {
+    fn from(record: &Record) -> DatabaseRecord {
+        DatabaseRecord {
+            key: record.key.clone(),
+            value: record.value.clone().unwrap_or_default(),
+            timestamp: record.timestamp,
+            deleted: record.deleted,
+            merge: record.merge,
+        }
+    }
 }
 
 pub struct Database{
     dir: PathBuf,
-    mem_table: MemTable,
-    wal: WAL,
+    options: Options,
+    block_cache: Option<Arc<BlockCache>>,
+    inner: Mutex<DatabaseInner>,
+    snapshots: Arc<SnapshotList>,
+    lock_manager: LockManager,
+    next_transaction_id: AtomicU64,
+    ttl_sweeper: Mutex<Option<TtlSweeper>>,
+    //Opened with open_as_secondary, it can catch up with the primary
+    secondary: bool,
+    //Held until the Database is closed or dropped, declared last so everything else is dropped first
+    //A read-only Database does not take it
+    dir_lock: Mutex<Option<DirectoryLock>>,
+}
+
+//Everything a write has to change together, behind one lock so the WAL, the MemTables and the MANIFEST always agree
+pub(crate) struct DatabaseInner{
+    dir: PathBuf,
+    env: Arc<dyn Env>,
+    clock: Arc<dyn Clock>,
+    column_families: BTreeMap<u32, ColumnFamily>,
+    manifest: Manifest,
+    //None when the Database is read-only
+    wal: Option<WAL>,
+    last_timestamp: u128,
+    prepared: HashMap<String, PreparedWrites>,
+    table_options: TableOptions,
+    sync_policy: SyncPolicy,
+    //When the WAL was last synced, in microseconds of the clock
+    last_sync: u128,
+    //Set by close, everything but a few infallible getters fails with Error::Closed from then on
+    closed: bool,
+    //I/O failure that stopped the writes and what caused it, cleared by resume
+    background_error: Option<(ErrorSeverity, String)>,
+}
+
+//Write set of a prepared transaction waiting on the coordinator
+//The row locks stay taken under transaction_id until it is committed or rolled back
+struct PreparedWrites{
+    transaction_id: u64,
+    records: Vec<WALRecord>,
+    timestamp: u128,
 }
 
 impl Database{
     pub fn new(dir:&str) -> Database{
-        let dir_buffer = PathBuf::from(dir);
-        let path_dir = Path::new(dir);
+        Database::open(dir, &Options::default()).unwrap()
+    }
 
-        let (wal, mem_table) = WAL::load_mem_table_from_dir(&path_dir).unwrap();
+    //Open the Database in dir with keys ordered by comparator
+    //A Database keeps the comparator it was created with, opening it with another one fails
+    pub fn with_comparator(dir: &str, comparator: Arc<dyn Comparator>) -> Result<Database> {
+        Database::open(dir, &Options::default().comparator(comparator))
+    }
 
-        Database{
-            dir: dir_buffer,
-            wal: wal,
-            mem_table: mem_table
+    //Open the Database in path, or create it there if options allow
+    pub fn open(path: impl AsRef<Path>, options: &Options) -> Result<Database> {
+        let path_dir = path.as_ref();
+        let dir_buffer = path_dir.to_path_buf();
+        let env = &options.env;
+        if env.exists(&path_dir.join("MANIFEST")) {
+            if options.error_if_exists {
+                return Err(Error::DatabaseExists { path: dir_buffer });
+            }
+        } else if options.create_if_missing {
+            env.create_dir_all(path_dir)?;
+        } else {
+            return Err(Error::DatabaseNotFound { path: dir_buffer });
         }
+        //Nothing in the directory is read before the lock is held
+        let lock = DirectoryLock::acquire(env, path_dir)?;
+
+        //The MANIFEST says which column families and tables there are, the WAL holds what was not flushed yet
+        let mut manifest = Manifest::load_or_create(env, path_dir, options.comparator.as_ref())?;
+        check_comparator(&manifest, options)?;
+        let (wal, mem_tables, recovered) = WAL::load_mem_tables_from_dir(env, options.clock.as_ref(), path_dir, &mut manifest, &options.comparator)?;
+        Database::from_recovered(path_dir, options, manifest, Some(wal), mem_tables, recovered, Some(lock))
     }
 
-    pub fn get(&mut self, key:&[u8]) -> Option<DatabaseRecord>{
-        let record = self.mem_table.get(key);
-        if record.is_some(){
-           return Some(DatabaseRecord{
-                key: record.unwrap().key.clone(),
-                value: record.unwrap().value.as_ref().unwrap().clone(),
-                timestamp: record.unwrap().timestamp,
-            })
+    //Open the Database in path to read it without changing anything in the directory
+    //No file is created, rewritten or deleted and no lock is taken, so the Database that owns the directory
+    //can keep writing to it. Every write fails with Error::ReadOnly.
+    //This sees the data as it was when it was opened, open it again to see newer writes.
+    pub fn open_read_only(path: impl AsRef<Path>, options: &Options) -> Result<Database> {
+        Database::open_without_lock(path.as_ref(), options, false)
+    }
+
+    //Open a read-only secondary that follows the primary Database writing to path
+    //It sees the primary as of when it was opened, try_catch_up pulls in what the primary wrote since
+    pub fn open_as_secondary(path: impl AsRef<Path>, options: &Options) -> Result<Database> {
+        Database::open_without_lock(path.as_ref(), options, true)
+    }
+
+    fn open_without_lock(path_dir: &Path, options: &Options, secondary: bool) -> Result<Database> {
+        let env = &options.env;
+        if !env.exists(&path_dir.join("MANIFEST")) {
+            return Err(Error::DatabaseNotFound { path: path_dir.to_path_buf() });
+        }
+        retry_vanished_tables(|| {
+            let (manifest, mem_tables, recovered) = read_unflushed(env, path_dir, &options.comparator)?;
+            check_comparator(&manifest, options)?;
+            let mut db = Database::from_recovered(path_dir, options, manifest, None, mem_tables, recovered, None)?;
+            db.secondary = secondary;
+            Ok(db)
+        })
+    }
+
+    //Pull in the tables the primary flushed and the writes it logged since the last catch-up, or since
+    //this secondary was opened. The MemTables are replayed again from the primary's WAL files as they
+    //are now, tables that are already open stay open. Reads keep working while this runs, nothing
+    //changes if it fails.
+    pub fn try_catch_up(&self) -> Result<()> {
+        if !self.secondary {
+            return Err(Error::NotSecondary);
+        }
+        retry_vanished_tables(|| self.catch_up())
+    }
+
+    fn catch_up(&self) -> Result<()> {
+        let comparator = &self.options.comparator;
+        let env = &self.options.env;
+        let (manifest, mut mem_tables, recovered) = read_unflushed(env, &self.dir, comparator)?;
+        check_comparator(&manifest, &self.options)?;
+
+        let mut inner = self.lock_open()?;
+        let table_options = inner.table_options.clone();
+        //Open the new tables before anything changes, so a table the primary deleted in the meantime leaves things as they were
+        let open: HashSet<u64> = inner.column_families.values().flat_map(|cf| cf.table_numbers()).collect();
+        let mut tables: HashMap<u64, SSTable> = HashMap::new();
+        for file_number in manifest.column_families().iter().flat_map(|cf| cf.tables.iter()) {
+            if !open.contains(file_number) {
+                let table = SSTable::open(&table_path(&self.dir, *file_number), *file_number, &table_options)?;
+                tables.insert(*file_number, table);
+            }
+        }
+
+        let mut previous = std::mem::take(&mut inner.column_families);
+        for column_family in previous.values_mut() {
+            tables.extend(column_family.tables.drain(..).map(|table| (table.file_number(), table)));
+        }
+        let snapshots = self.snapshots.timestamps();
+        for entry in manifest.column_families() {
+            //Families this secondary already had keep the options set on them
+            let options = previous.remove(&entry.id).map_or_else(ColumnFamilyOptions::default, |cf| cf.options);
+            let cf_tables: Vec<SSTable> = entry.tables.iter().filter_map(|n| tables.remove(n)).collect();
+            let mut mem_table = mem_tables
+                .remove(&entry.id)
+                .unwrap_or_else(|| MemTable::with_comparator(comparator.clone()));
+            mem_table.set_snapshots(snapshots.clone());
+            let mut column_family = ColumnFamily::new(entry.id, &entry.name, options, table_options.clone(), mem_table, cf_tables);
+            column_family.drop_flushed();
+            inner.column_families.insert(entry.id, column_family);
+        }
+        inner.manifest = manifest;
+        let newest = inner.column_families.values().map(|cf| cf.max_timestamp()).max().unwrap_or(0);
+        inner.last_timestamp = inner.last_timestamp.max(newest);
+
+        for (_, prepared) in inner.prepared.drain() {
+            self.lock_manager.unlock(prepared.transaction_id, prepared.records.iter().map(|r| &r.key));
+        }
+        self.restore_prepared(&mut inner, recovered)
+    }
+
+    //Put the Database together from what was recovered from the directory, without a WAL it is read-only
+    fn from_recovered(
+        path_dir: &Path,
+        options: &Options,
+        manifest: Manifest,
+        wal: Option<WAL>,
+        mut mem_tables: BTreeMap<u32, MemTable>,
+        recovered: Vec<PreparedTransaction>,
+        lock: Option<DirectoryLock>,
+    ) -> Result<Database> {
+        let dir_buffer = path_dir.to_path_buf();
+        let comparator = options.comparator.clone();
+        let block_cache = (options.block_cache_size > 0).then(|| Arc::new(BlockCache::new(options.block_cache_size)));
+        let table_options = TableOptions {
+            comparator: comparator.clone(),
+            compression: options.compression,
+            block_cache: block_cache.clone(),
+            env: options.env.clone(),
+        };
+
+        //Records of dropped column families are not kept, later writes still have to be stamped past them
+        let dropped_newest = mem_tables.values().flat_map(|mem_table| mem_table.entries().iter().map(|e| e.timestamp)).max();
+        let mut column_families = BTreeMap::new();
+        for entry in manifest.column_families() {
+            let tables = entry
+                .tables
+                .iter()
+                .map(|file_number| SSTable::open(&table_path(path_dir, *file_number), *file_number, &table_options))
+                .collect::<io::Result<Vec<SSTable>>>()?;
+            let mem_table = mem_tables
+                .remove(&entry.id)
+                .unwrap_or_else(|| MemTable::with_comparator(comparator.clone()));
+            let mut cf_options = ColumnFamilyOptions::default();
+            if entry.id == DEFAULT_COLUMN_FAMILY_ID {
+                cf_options.write_buffer_size = options.write_buffer_size;
+            }
+            let mut column_family = ColumnFamily::new(entry.id, &entry.name, cf_options, table_options.clone(), mem_table, tables);
+            column_family.drop_flushed();
+            column_families.insert(entry.id, column_family);
+        }
+        let last_timestamp = column_families.values().map(|cf| cf.max_timestamp()).chain(dropped_newest).max().unwrap_or(0);
+
+        let db = Database{
+            dir: dir_buffer.clone(),
+            options: options.clone(),
+            block_cache,
+            inner: Mutex::new(DatabaseInner{
+                dir: dir_buffer,
+                env: options.env.clone(),
+                clock: options.clock.clone(),
+                column_families,
+                manifest,
+                wal,
+                last_timestamp,
+                prepared: HashMap::new(),
+                table_options,
+                sync_policy: options.sync_policy,
+                last_sync: options.clock.now_micros(),
+                closed: false,
+                background_error: None,
+            }),
+            snapshots: Arc::new(SnapshotList::new()),
+            lock_manager: LockManager::new(),
+            next_transaction_id: AtomicU64::new(1),
+            ttl_sweeper: Mutex::new(None),
+            secondary: false,
+            dir_lock: Mutex::new(lock),
+        };
+        db.restore_prepared(&mut db.lock(), recovered)?;
+        Ok(db)
+    }
+
+    //Prepared transactions take their row locks back until the coordinator decides them
+    fn restore_prepared(&self, inner: &mut DatabaseInner, recovered: Vec<PreparedTransaction>) -> Result<()> {
+        for transaction in recovered {
+            let transaction_id = self.next_transaction_id.fetch_add(1, Ordering::Relaxed);
+            for record in transaction.records.iter() {
+                self.lock_manager.lock(transaction_id, &record.key, Duration::ZERO)?;
+            }
+            let name = String::from_utf8_lossy(&transaction.name).into_owned();
+            inner.prepared.insert(name, PreparedWrites{
+                transaction_id,
+                records: transaction.records,
+                timestamp: transaction.timestamp,
+            });
         }
-        None
+        Ok(())
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn comparator(&self) -> &Arc<dyn Comparator> {
+        &self.options.comparator
+    }
+
+    //Options the Database was opened with
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    //Clock the Database reads the time from
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.options.clock
+    }
+
+    //Was the Database opened with open_read_only or open_as_secondary
+    pub fn is_read_only(&self) -> bool {
+        self.lock().wal.is_none()
+    }
+
+    //Bytes of data blocks in the block cache right now
+    pub fn block_cache_usage(&self) -> usize {
+        self.block_cache.as_ref().map_or(0, |cache| cache.usage())
+    }
+
+    //Prefix extractor of the default column family, used to build and check the prefix filters of its tables
+    pub fn set_prefix_extractor(&self, extractor: Box<dyn PrefixExtractor>) {
+        let mut inner = self.lock();
+        inner.default_column_family_mut().options.prefix_extractor = Some(Arc::from(extractor));
+    }
+
+    pub fn prefix_extractor(&self) -> Option<Arc<dyn PrefixExtractor>> {
+        self.lock().column_family(DEFAULT_COLUMN_FAMILY_ID).options.prefix_extractor.clone()
+    }
+
+    //Operator that combines the operands written with merge to the default column family
+    //Set it before reading merged keys, keys holding merge operands read as missing without one
+    pub fn set_merge_operator(&self, operator: Box<dyn MergeOperator>) {
+        let mut inner = self.lock();
+        inner.default_column_family_mut().options.merge_operator = Some(Arc::from(operator));
+    }
+
+    pub fn merge_operator(&self) -> Option<Arc<dyn MergeOperator>> {
+        self.lock().column_family(DEFAULT_COLUMN_FAMILY_ID).options.merge_operator.clone()
+    }
+
+    //Add a column family with its own MemTable, tables and options, it is kept across restarts
+    pub fn create_column_family(&self, name: &str, options: ColumnFamilyOptions) -> Result<()> {
+        if name.is_empty() || name.contains(['\n', '\r']) {
+            return Err(Error::InvalidColumnFamilyName { name: name.to_owned() });
+        }
+        let mut inner = self.lock();
+        inner.check_writable()?;
+        if inner.column_family_id(name).is_ok() {
+            return Err(Error::ColumnFamilyExists { name: name.to_owned() });
+        }
+        let id = inner.manifest.add_column_family(name);
+        if let Err(err) = inner.manifest.save() {
+            inner.manifest.drop_column_family(id);
+            return Err(err.into());
+        }
+        let mem_table = MemTable::with_comparator(self.options.comparator.clone());
+        let table_options = inner.table_options.clone();
+        let mut column_family = ColumnFamily::new(id, name, options, table_options, mem_table, Vec::new());
+        column_family.mem_table.set_snapshots(self.snapshots.timestamps());
+        inner.column_families.insert(id, column_family);
+        Ok(())
+    }
+
+    //Remove a column family and delete its tables
+    //Its writes still in the WAL are skipped on recovery, a family created later under the same name starts empty
+    pub fn drop_column_family(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_COLUMN_FAMILY {
+            return Err(Error::DropDefaultColumnFamily);
+        }
+        let mut inner = self.lock();
+        inner.check_writable()?;
+        let id = inner.column_family_id(name)?;
+        inner.manifest.drop_column_family(id);
+        inner.manifest.save()?;
+        let column_family = inner.column_families.remove(&id).unwrap();
+        for table in column_family.tables.iter() {
+            inner.env.remove_file(table.path())?;
+        }
+        inner.env.sync_dir(&inner.dir)?;
+        Ok(())
+    }
+
+    //Names of every column family, the default one first and then in the order they were created
+    pub fn list_column_families(&self) -> Vec<String> {
+        self.lock().column_families.values().map(|cf| cf.name.clone()).collect()
+    }
+
+    //Options are not stored on disk, set them again after a restart
+    pub fn set_column_family_options(&self, name: &str, options: ColumnFamilyOptions) -> Result<()> {
+        let mut inner = self.lock_open()?;
+        let id = inner.column_family_id(name)?;
+        inner.column_families.get_mut(&id).unwrap().set_options(options);
+        Ok(())
+    }
+
+    pub fn get(&self, key:&[u8]) -> Result<Option<DatabaseRecord>>{
+        self.get_cf(DEFAULT_COLUMN_FAMILY, key)
+    }
+
+    pub fn get_cf(&self, column_family: &str, key:&[u8]) -> Result<Option<DatabaseRecord>>{
+        self.get_cf_opt(column_family, key, &ReadOptions::default())
+    }
+
+    pub fn get_opt(&self, key:&[u8], options: &ReadOptions) -> Result<Option<DatabaseRecord>>{
+        self.get_cf_opt(DEFAULT_COLUMN_FAMILY, key, options)
+    }
+
+    pub fn get_cf_opt(&self, column_family: &str, key:&[u8], options: &ReadOptions) -> Result<Option<DatabaseRecord>>{
+        let inner = self.lock_open()?;
+        let id = inner.column_family_id(column_family)?;
+        let (at, now) = inner.read_timestamp(options);
+        Ok(inner.column_family(id).read(key, at, now, options.fill_cache)?)
+    }
+
+    //Look up many keys at once, results come back in the same order as keys
+    //Keys are sorted first so the MemTable is walked once and each table is read once for all of them
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<DatabaseRecord>>> {
+        self.multi_get_opt(keys, &ReadOptions::default())
+    }
+
+    pub fn multi_get_opt(&self, keys: &[&[u8]], options: &ReadOptions) -> Result<Vec<Option<DatabaseRecord>>> {
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|a, b| self.options.comparator.compare(keys[*a], keys[*b]));
+        let sorted_keys: Vec<&[u8]> = order.iter().map(|idx| keys[*idx]).collect();
+
+        let inner = self.lock_open()?;
+        let (at, now) = inner.read_timestamp(options);
+        let found = inner
+            .column_family(DEFAULT_COLUMN_FAMILY_ID)
+            .multi_get(&sorted_keys, at, now, options.fill_cache)?;
+        let mut results: Vec<Option<DatabaseRecord>> = (0..keys.len()).map(|_| None).collect();
+        for (idx, record) in order.into_iter().zip(found) {
+            results[idx] = record;
+        }
+        Ok(results)
+    }
+
+    //How many old versions of each key to keep for get_at and versions in the default column family
+    //The WAL replay keeps every version so history from before a restart is still there to trim
+    pub fn set_history_retention(&self, retention: HistoryRetention) {
+        let mut inner = self.lock();
+        let column_family = inner.default_column_family_mut();
+        let options = ColumnFamilyOptions {
+            history_retention: retention,
+            ..column_family.options.clone()
+        };
+        column_family.set_options(options);
+    }
+
+    //Value of the key as of timestamp, None if it did not exist, was deleted or had expired at that time
+    pub fn get_at(&self, key:&[u8], timestamp: u128) -> Result<Option<DatabaseRecord>>{
+        let inner = self.lock_open()?;
+        Ok(inner.column_family(DEFAULT_COLUMN_FAMILY_ID).read(key, timestamp, timestamp, true)?)
+    }
+
+    //Every version of the key still retained, newest first, deletions and merge operands included
+    pub fn versions(&self, key:&[u8]) -> Result<Vec<DatabaseRecord>>{
+        let inner = self.lock_open()?;
+        let versions = inner.column_family(DEFAULT_COLUMN_FAMILY_ID).versions(key)?;
+        Ok(versions.iter().map(DatabaseRecord::from).collect())
+    }
+
+    //Pin the current point in time, reads through the snapshot only see writes committed before it was taken
+    //The snapshot takes a timestamp of its own so values that expire later still read as live through it
+    pub fn snapshot(&self) -> Snapshot {
+        let mut inner = self.lock();
+        let timestamp = inner.next_timestamp();
+        let snapshot = Snapshot::new(timestamp, self.snapshots.clone());
+        inner.set_snapshots(self.snapshots.timestamps());
+        snapshot
+    }
+
+    //Value of the key as of the snapshot
+    pub fn get_with_snapshot(&self, key:&[u8], snapshot: &Snapshot) -> Result<Option<DatabaseRecord>>{
+        self.get_at(key, snapshot.timestamp())
+    }
+
+    //All live records whose key starts with prefix, in key order
+    //The scan stops at the first key past the prefix instead of walking the whole table
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<DatabaseRecord>> {
+        self.scan_prefix_cf_opt(DEFAULT_COLUMN_FAMILY, prefix, &ReadOptions::default())
+    }
+
+    pub fn scan_prefix_cf(&self, column_family: &str, prefix: &[u8]) -> Result<Vec<DatabaseRecord>> {
+        self.scan_prefix_cf_opt(column_family, prefix, &ReadOptions::default())
+    }
+
+    //Same as scan_prefix but reads the keys as of the snapshot
+    pub fn scan_prefix_with_snapshot(&self, prefix: &[u8], snapshot: &Snapshot) -> Result<Vec<DatabaseRecord>> {
+        self.scan_prefix_cf_opt(DEFAULT_COLUMN_FAMILY, prefix, &ReadOptions::default().snapshot(snapshot))
+    }
+
+    pub fn scan_prefix_opt(&self, prefix: &[u8], options: &ReadOptions) -> Result<Vec<DatabaseRecord>> {
+        self.scan_prefix_cf_opt(DEFAULT_COLUMN_FAMILY, prefix, options)
+    }
+
+    pub fn scan_prefix_cf_opt(&self, column_family: &str, prefix: &[u8], options: &ReadOptions) -> Result<Vec<DatabaseRecord>> {
+        let inner = self.lock_open()?;
+        let id = inner.column_family_id(column_family)?;
+        let (at, now) = inner.read_timestamp(options);
+        Ok(inner.column_family(id).scan_prefix(prefix, at, now, options.fill_cache)?)
+    }
+
+    pub fn set(&self, key:&[u8], value:&[u8]) -> Result<usize>{
+        self.set_cf(DEFAULT_COLUMN_FAMILY, key, value)
     }
-        
 
-    pub fn set(&mut self, key:&[u8], value:&[u8]) -> Result<usize, usize>{
-        let timestamp = SystemTime::now()
-          .duration_since(UNIX_EPOCH)
-          .unwrap()
-          .as_micros();
-        let wal_result = self.wal.set(key, value, timestamp);
-        
-        if wal_result.is_err(){
-            return Err(0);
+    pub fn set_cf(&self, column_family: &str, key:&[u8], value:&[u8]) -> Result<usize>{
+        self.set_cf_opt(column_family, key, value, &WriteOptions::default())
+    }
+
+    pub fn set_opt(&self, key:&[u8], value:&[u8], options: &WriteOptions) -> Result<usize>{
+        self.set_cf_opt(DEFAULT_COLUMN_FAMILY, key, value, options)
+    }
+
+    pub fn set_cf_opt(&self, column_family: &str, key:&[u8], value:&[u8], options: &WriteOptions) -> Result<usize>{
+        let mut inner = self.lock_open()?;
+        let id = inner.column_family_id(column_family)?;
+        let timestamp = inner.next_timestamp();
+        inner.write_records(&[WALRecord::set(id, key, value, timestamp)], &self.snapshots, options)?;
+        Ok(1)
+    }
+    //Replace the value of the key with new only if it currently holds expected
+    //None as expected means the key must be missing and None as new deletes it
+    //The check and the write happen under the lock that appends to the WAL, so nothing can slip in between
+    //The check is against the newest version whatever its timestamp, only whether it expired depends on the clock
+    //If the key holds something else Error::ConditionFailed carries the current value
+    pub fn compare_and_swap(&self, key:&[u8], expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<()>{
+        let mut inner = self.lock_open()?;
+        let now = inner.clock.now_micros();
+        let current = inner
+            .column_family(DEFAULT_COLUMN_FAMILY_ID)
+            .read(key, u128::MAX, now, true)?
+            .map(|r| r.value);
+        if current.as_deref() != expected {
+            return Err(Error::ConditionFailed { current });
+        }
+
+        let mut batch = WriteBatch::new();
+        match new {
+            Some(value) => batch.set(key, value),
+            None => batch.delete(key),
         }
+        inner.write_batch(&batch, &self.snapshots, &WriteOptions::default())
+    }
+
+    //Set the key only if it is missing, otherwise Error::ConditionFailed carries the current value
+    pub fn put_if_absent(&self, key:&[u8], value:&[u8]) -> Result<()>{
+        self.compare_and_swap(key, None, Some(value))
+    }
 
-        if self.wal.flush().is_err(){
-            return Err(0);
+    //Log a merge operand for the key without reading it, the merge operator combines it on read
+    pub fn merge(&self, key:&[u8], operand:&[u8]) -> Result<usize>{
+        self.merge_cf(DEFAULT_COLUMN_FAMILY, key, operand)
+    }
+
+    pub fn merge_cf(&self, column_family: &str, key:&[u8], operand:&[u8]) -> Result<usize>{
+        let mut inner = self.lock_open()?;
+        let id = inner.column_family_id(column_family)?;
+        if inner.column_family(id).options.merge_operator.is_none() {
+            return Err(Error::NoMergeOperator);
         }
-        
-        self.mem_table.set(key, value,timestamp);
+        let timestamp = inner.next_timestamp();
+        inner.write_records(&[WALRecord::merge(id, key, operand, timestamp)], &self.snapshots, &WriteOptions::default())?;
+        Ok(1)
+    }
+
+    //Set a value that reads as missing once ttl has passed
+    //Expired values are hidden from reads right away and deleted for real by sweep_expired, flushes and compactions
+    pub fn set_with_ttl(&self, key:&[u8], value:&[u8], ttl: Duration) -> Result<usize>{
+        let mut inner = self.lock_open()?;
+        let timestamp = inner.next_timestamp();
+        let expires_at = timestamp + ttl.as_micros();
+        let record = WALRecord::set_with_ttl(DEFAULT_COLUMN_FAMILY_ID, key, value, timestamp, Some(expires_at));
+        inner.write_records(&[record], &self.snapshots, &WriteOptions::default())?;
         Ok(1)
     }
-    pub fn delete(&mut self, key:&[u8]) -> Result<usize, usize> {
-        let timestamp = SystemTime::now()
-          .duration_since(UNIX_EPOCH)
-          .unwrap()
-          .as_micros();
-        
-        let wal_result = self.wal.delete(key,timestamp);
-        if wal_result.is_err(){
-            return Err(0);
+
+    //Write a tombstone for every key in a MemTable whose value has expired, returns how many keys were swept
+    //The keys are found and deleted under one lock so a key set again in between is never swept
+    pub fn sweep_expired(&self) -> Result<usize> {
+        let mut inner = self.lock_open()?;
+        let now = inner.clock.now_micros();
+        let expired: Vec<(u32, Vec<u8>)> = inner
+            .column_families
+            .values()
+            .flat_map(|cf| cf.mem_table.expired_keys(now).into_iter().map(|key| (cf.id, key)))
+            .collect();
+        if expired.is_empty() {
+            return Ok(0);
         }
+        let timestamp = inner.next_timestamp();
+        let records: Vec<WALRecord> = expired
+            .iter()
+            .map(|(id, key)| WALRecord::delete(*id, key, timestamp))
+            .collect();
+        inner.write_records(&records, &self.snapshots, &WriteOptions::default())?;
+        Ok(records.len())
+    }
 
-        if self.wal.flush().is_err(){
-            return Err(0);
+    //Hand an error nobody called for to Options::on_background_error
+    pub(crate) fn report_background_error(&self, err: &Error) {
+        if let Some(handler) = self.options.on_background_error.as_ref() {
+            handler(err);
         }
-        self.mem_table.delete(key, timestamp);
+    }
+
+    //Run sweep_expired every interval on a background thread until stop_ttl_sweeper or the Database is dropped
+    pub fn start_ttl_sweeper(self: &Arc<Self>, interval: Duration) {
+        let sweeper = TtlSweeper::start(Arc::downgrade(self), interval, self.options.scheduler.as_ref());
+        *self.ttl_sweeper.lock().unwrap() = Some(sweeper);
+    }
+
+    pub fn stop_ttl_sweeper(&self) {
+        self.ttl_sweeper.lock().unwrap().take();
+    }
+
+    pub fn delete(&self, key:&[u8]) -> Result<usize> {
+        self.delete_cf(DEFAULT_COLUMN_FAMILY, key)
+    }
+
+    pub fn delete_cf(&self, column_family: &str, key:&[u8]) -> Result<usize> {
+        self.delete_cf_opt(column_family, key, &WriteOptions::default())
+    }
+
+    pub fn delete_opt(&self, key:&[u8], options: &WriteOptions) -> Result<usize> {
+        self.delete_cf_opt(DEFAULT_COLUMN_FAMILY, key, options)
+    }
+
+    pub fn delete_cf_opt(&self, column_family: &str, key:&[u8], options: &WriteOptions) -> Result<usize> {
+        let mut inner = self.lock_open()?;
+        let id = inner.column_family_id(column_family)?;
+        let timestamp = inner.next_timestamp();
+        inner.write_records(&[WALRecord::delete(id, key, timestamp)], &self.snapshots, options)?;
         Ok(1)
     }
+
+    //Apply every write in the batch atomically, returns how many writes were applied
+    //The batch can write to several column families and is still all or nothing
+    pub fn write(&self, batch: &WriteBatch) -> Result<usize> {
+        self.write_opt(batch, &WriteOptions::default())
+    }
+
+    pub fn write_opt(&self, batch: &WriteBatch, options: &WriteOptions) -> Result<usize> {
+        let mut inner = self.lock_open()?;
+        inner.write_batch(batch, &self.snapshots, options)?;
+        Ok(batch.len())
+    }
+
+    //Write every MemTable to a table and start a new WAL, this also happens on its own once a MemTable is full
+    pub fn flush(&self) -> Result<()> {
+        self.lock_open()?.flush(&self.snapshots.timestamps(), false)
+    }
+
+    //Compact the tables of every column family, each one ends up with at most one table
+    pub fn compact(&self) -> Result<()> {
+        let mut inner = self.lock_open()?;
+        let ids: Vec<u32> = inner.column_families.keys().copied().collect();
+        for id in ids {
+            inner.compact(id, &self.snapshots.timestamps())?;
+        }
+        Ok(())
+    }
+
+    //Make a consistent copy of the Database as it is now in target_dir, which must not exist yet
+    //The tables are hard linked since they never change, the WAL is copied up to where it was synced
+    //and a MANIFEST listing the tables is written, the copy opens with Database::open like any Database
+    //Writes wait until the checkpoint is done
+    pub fn checkpoint(&self, target_dir: impl AsRef<Path>) -> Result<()> {
+        self.lock_open()?.checkpoint(target_dir.as_ref()).map(|_| ())
+    }
+
+    //Checkpoint that also returns the timestamp of the newest write it holds
+    pub(crate) fn checkpoint_with_timestamp(&self, target_dir: &Path) -> Result<u128> {
+        self.lock_open()?.checkpoint(target_dir)
+    }
+
+    //The error the writes are stopped by, None while they go through
+    pub fn background_error(&self) -> Option<Error> {
+        self.lock().writes_stopped()
+    }
+
+    //Let writes through again once what stopped them is fixed, for example space was freed on a full disk
+    //Every MemTable is flushed and a new WAL started, so the next write is not appended after part of a record
+    //If that fails again the writes stay stopped and resume can be retried
+    pub fn resume(&self) -> Result<()> {
+        let mut inner = self.lock_open()?;
+        let Some(previous) = inner.background_error.take() else {
+            return Ok(());
+        };
+        let result = inner.flush(&self.snapshots.timestamps(), true);
+        if result.is_err() && inner.background_error.as_ref().is_none_or(|(severity, _)| *severity < previous.0) {
+            inner.background_error = Some(previous);
+        }
+        result
+    }
+
+    //Stop the background work, sync the WAL and release the directory so another Database can open it
+    //With Options::flush_on_close the MemTables are written to tables first and the next open has no WAL to replay
+    //The Database is closed even when this returns an error, every call after it fails with Error::Closed
+    pub fn close(&self) -> Result<()> {
+        self.stop_ttl_sweeper();
+        let result = {
+            //Drop closes too, it must not panic on a lock a panicking writer left poisoned
+            let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+            inner.check_open()?;
+            let result = inner.close(self.options.flush_on_close, &self.snapshots.timestamps());
+            inner.closed = true;
+            result
+        };
+        self.dir_lock.lock().unwrap_or_else(PoisonError::into_inner).take();
+        result
+    }
+
+    //Transaction that buffers its writes and fails to commit if a key it read changed underneath it
+    pub fn begin_optimistic(&self) -> OptimisticTransaction<'_> {
+        OptimisticTransaction::new(self)
+    }
+
+    //Transaction that locks every key it writes or reads with get_for_update until it ends
+    pub fn begin_pessimistic(&self) -> PessimisticTransaction<'_> {
+        let id = self.next_transaction_id.fetch_add(1, Ordering::Relaxed);
+        PessimisticTransaction::new(self, id, DEFAULT_LOCK_TIMEOUT)
+    }
+
+    //Names of the transactions that are prepared and waiting to be committed or rolled back
+    pub fn prepared_transactions(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.lock().prepared.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    //Log the write set of a transaction under name, its row locks are kept until it is decided
+    pub(crate) fn prepare(&self, name: &str, transaction_id: u64, batch: &WriteBatch) -> Result<()> {
+        let mut inner = self.lock_open()?;
+        if inner.prepared.contains_key(name) {
+            return Err(Error::PreparedNameInUse { name: name.to_owned() });
+        }
+        let timestamp = inner.next_timestamp();
+        let records = inner.wal_records(batch, timestamp)?;
+        inner.log(|wal| wal.prepare(name.as_bytes(), &records, timestamp), false)?;
+        inner.prepared.insert(name.to_owned(), PreparedWrites{ transaction_id, records, timestamp });
+        Ok(())
+    }
+
+    //Apply the write set of the prepared transaction and release its row locks
+    pub fn commit_prepared(&self, name: &str) -> Result<()> {
+        let mut inner = self.lock_open()?;
+        if !inner.prepared.contains_key(name) {
+            return Err(Error::UnknownPrepared { name: name.to_owned() });
+        }
+        let timestamp = inner.next_timestamp();
+        inner.log(|wal| wal.commit_prepared(name.as_bytes(), timestamp), false)?;
+
+        let mut prepared = inner.prepared.remove(name).unwrap();
+        for record in prepared.records.iter_mut() {
+            record.timestamp = timestamp;
+        }
+        inner.apply_records(&prepared.records, &self.snapshots);
+        self.lock_manager.unlock(prepared.transaction_id, prepared.records.iter().map(|r| &r.key));
+        Ok(())
+    }
+
+    //Throw away the write set of the prepared transaction and release its row locks
+    pub fn rollback_prepared(&self, name: &str) -> Result<()> {
+        let mut inner = self.lock_open()?;
+        if !inner.prepared.contains_key(name) {
+            return Err(Error::UnknownPrepared { name: name.to_owned() });
+        }
+        let timestamp = inner.next_timestamp();
+        inner.log(|wal| wal.rollback_prepared(name.as_bytes(), timestamp), false)?;
+
+        let prepared = inner.prepared.remove(name).unwrap();
+        self.lock_manager.unlock(prepared.transaction_id, prepared.records.iter().map(|r| &r.key));
+        Ok(())
+    }
+
+    pub(crate) fn lock_manager(&self) -> &LockManager {
+        &self.lock_manager
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, DatabaseInner> {
+        self.inner.lock().unwrap()
+    }
+
+    //Lock for an operation that fails with Error::Closed once the Database is closed
+    pub(crate) fn lock_open(&self) -> Result<MutexGuard<'_, DatabaseInner>> {
+        let inner = self.lock();
+        inner.check_open()?;
+        Ok(inner)
+    }
+
+    pub(crate) fn snapshot_list(&self) -> &Arc<SnapshotList> {
+        &self.snapshots
+    }
+}
+
+impl DatabaseInner{
+    //Timestamps double as the version of a write so they have to keep going up
+    //even if the clock stalls or steps backwards
+    fn next_timestamp(&mut self) -> u128 {
+        let now = self.clock.now_micros();
+        self.last_timestamp = now.max(self.last_timestamp + 1);
+        self.last_timestamp
+    }
+
+    //Timestamp a read with these options sees the Database at, and the time values are checked for expiry at
+    //Without a snapshot every write so far is seen, a write made while the clock stalled or stepped back
+    //is stamped past the clock and has to be read back all the same
+    fn read_timestamp(&self, options: &ReadOptions) -> (u128, u128) {
+        match options.snapshot {
+            Some(snapshot) => (snapshot.timestamp(), snapshot.timestamp()),
+            None => {
+                let now = self.clock.now_micros();
+                (now.max(self.last_timestamp), now)
+            }
+        }
+    }
+
+    pub(crate) fn column_family(&self, id: u32) -> &ColumnFamily {
+        &self.column_families[&id]
+    }
+
+    //The WAL writes are logged to, a read-only Database has none so every write fails here
+    fn wal(&mut self) -> Result<&mut WAL> {
+        self.check_writable()?;
+        Ok(self.wal.as_mut().unwrap())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        self.check_open()?;
+        if let Some(err) = self.writes_stopped() {
+            return Err(err);
+        }
+        match self.wal {
+            Some(_) => Ok(()),
+            None => Err(Error::ReadOnly),
+        }
+    }
+
+    fn writes_stopped(&self) -> Option<Error> {
+        self.background_error.as_ref().map(|(severity, cause)| Error::WritesStopped {
+            severity: *severity,
+            cause: cause.clone(),
+        })
+    }
+
+    //Stop the writes on an I/O failure of a write, flush or compaction, a hard error is never replaced by a soft one
+    fn record_error<T>(&mut self, severity: ErrorSeverity, result: Result<T>) -> Result<T> {
+        if let Err(Error::Io(err)) = &result {
+            if self.background_error.as_ref().is_none_or(|(current, _)| *current < severity) {
+                self.background_error = Some((severity, err.to_string()));
+            }
+        }
+        result
+    }
+
+    fn check_open(&self) -> Result<()> {
+        match self.closed {
+            false => Ok(()),
+            true => Err(Error::Closed),
+        }
+    }
+
+    fn default_column_family_mut(&mut self) -> &mut ColumnFamily {
+        self.column_families.get_mut(&DEFAULT_COLUMN_FAMILY_ID).unwrap()
+    }
+
+    fn column_family_id(&self, name: &str) -> Result<u32> {
+        self.column_families
+            .values()
+            .find(|cf| cf.name == name)
+            .map(|cf| cf.id)
+            .ok_or_else(|| Error::UnknownColumnFamily { name: name.to_owned() })
+    }
+
+    fn set_snapshots(&mut self, snapshots: Vec<u128>) {
+        for column_family in self.column_families.values_mut() {
+            column_family.mem_table.set_snapshots(snapshots.clone());
+        }
+    }
+
+    fn wal_records(&self, batch: &WriteBatch, timestamp: u128) -> Result<Vec<WALRecord>> {
+        batch
+            .writes()
+            .iter()
+            .map(|write| {
+                let id = self.column_family_id(&write.column_family)?;
+                Ok(match write.value.as_ref() {
+                    Some(value) => WALRecord::set(id, &write.key, value, timestamp),
+                    None => WALRecord::delete(id, &write.key, timestamp),
+                })
+            })
+            .collect()
+    }
+
+    //Log the batch behind one header then apply it to the MemTables, every write shares one timestamp
+    pub(crate) fn write_batch(&mut self, batch: &WriteBatch, snapshots: &SnapshotList, options: &WriteOptions) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let timestamp = self.next_timestamp();
+        let records = self.wal_records(batch, timestamp)?;
+        self.write_records(&records, snapshots, options)
+    }
+
+    //Log the records then apply them, more than one goes behind a batch header so they are recovered all together or not at all
+    fn write_records(&mut self, records: &[WALRecord], snapshots: &SnapshotList, options: &WriteOptions) -> Result<()> {
+        match records {
+            [] => return Ok(()),
+            [record] => self.log(|wal| wal.write_record(record), options.sync)?,
+            _ => self.log(|wal| wal.batch(records, records[0].timestamp), options.sync)?,
+        }
+        self.apply_records(records, snapshots);
+        Ok(())
+    }
+
+    //Append to the WAL and sync it if asked to, a failure can leave part of a record at the end of the WAL
+    //so nothing more is appended to it until resume has moved on to a new one
+    fn log(&mut self, append: impl FnOnce(&mut WAL) -> io::Result<()>, sync: bool) -> Result<()> {
+        let appended = append(self.wal()?);
+        let result = appended.map_err(Error::from).and_then(|_| self.sync_wal(sync));
+        self.record_error(ErrorSeverity::Hard, result)
+    }
+
+    //Hand what was logged to the OS, and sync it to disk when the write asks for it or the sync policy is due
+    fn sync_wal(&mut self, sync: bool) -> Result<()> {
+        let due = match self.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::Periodic(interval) => self.clock.now_micros().saturating_sub(self.last_sync) >= interval.as_micros(),
+        };
+        if !sync && !due {
+            self.wal()?.flush()?;
+            return Ok(());
+        }
+        self.wal()?.sync()?;
+        self.last_sync = self.clock.now_micros();
+        Ok(())
+    }
+
+    //Apply logged records to the MemTables of their column families and flush if one of them is full
+    //The records are logged and applied by then, so a failed flush does not fail their write. It stops
+    //the writes that come after it with a soft error instead, until resume
+    fn apply_records(&mut self, records: &[WALRecord], snapshots: &SnapshotList) {
+        let snapshots = snapshots.timestamps();
+        self.set_snapshots(snapshots.clone());
+        for record in records {
+            if let Some(column_family) = self.column_families.get_mut(&record.column_family) {
+                WAL::replay_record(&mut column_family.mem_table, record);
+            }
+        }
+        if self.column_families.values().any(|cf| cf.mem_table.size() >= cf.options.write_buffer_size) {
+            let _ = self.flush(&snapshots, false);
+        }
+    }
+
+    //Write the MemTable of every column family to a new table, then replace the WAL with one that only
+    //holds the prepared transactions since everything else in it is in the tables now
+    //Column families that reached their compaction trigger are compacted afterwards
+    //With force the MANIFEST is saved and the WAL replaced even when every MemTable is empty
+    fn flush(&mut self, snapshots: &[u128], force: bool) -> Result<()> {
+        self.check_writable()?;
+        let result = self.flush_mem_tables(snapshots, force);
+        self.record_error(ErrorSeverity::Soft, result)
+    }
+
+    fn flush_mem_tables(&mut self, snapshots: &[u128], force: bool) -> Result<()> {
+        let now = self.clock.now_micros();
+        let mut flushed = false;
+        for column_family in self.column_families.values_mut() {
+            if column_family.mem_table.is_empty() {
+                continue;
+            }
+            let file_number = self.manifest.new_file_number();
+            column_family.flush(&self.dir, file_number, snapshots, now)?;
+            self.manifest.set_tables(column_family.id, column_family.table_numbers());
+            flushed = true;
+        }
+        if !flushed && !force {
+            return Ok(());
+        }
+
+        let mut wal = WAL::new(&self.env, self.clock.as_ref(), &self.dir, self.manifest.oldest_wal())?;
+        for (name, prepared) in self.prepared.iter() {
+            wal.prepare(name.as_bytes(), &prepared.records, prepared.timestamp)?;
+        }
+        //The old WAL is deleted next, the prepared transactions must be on disk in the new one first
+        wal.sync()?;
+        //Once the MANIFEST says so the old WAL is not replayed, even if a crash keeps it from being deleted
+        let oldest_wal = self.manifest.oldest_wal();
+        self.manifest.set_oldest_wal(wal.number());
+        if let Err(err) = self.manifest.save() {
+            self.manifest.set_oldest_wal(oldest_wal);
+            //Left behind it would be replayed after the writes that keep going to the old WAL
+            let _ = self.env.remove_file(wal.path());
+            return Err(err.into());
+        }
+        let old_wal = std::mem::replace(self.wal()?, wal);
+        self.env.remove_file(old_wal.path())?;
+        self.env.sync_dir(&self.dir)?;
+
+        let full: Vec<u32> = self
+            .column_families
+            .values()
+            .filter(|cf| cf.tables.len() >= cf.options.compaction_trigger)
+            .map(|cf| cf.id)
+            .collect();
+        for id in full {
+            self.compact(id, snapshots)?;
+        }
+        Ok(())
+    }
+
+    //Flush the MemTables if asked to and sync the WAL, a read-only Database has nothing to write
+    //The WAL is synced even when the flush fails, so the writes are on disk either way
+    fn close(&mut self, flush: bool, snapshots: &[u128]) -> Result<()> {
+        if self.wal.is_none() {
+            return Ok(());
+        }
+        let flushed = if flush { self.flush(snapshots, false) } else { Ok(()) };
+        //Synced even when writes are stopped, what was written before has to reach the disk
+        let synced = self.wal.as_mut().unwrap().sync();
+        flushed?;
+        Ok(synced?)
+    }
+
+    fn checkpoint(&mut self, target: &Path) -> Result<u128> {
+        let wal = self.wal()?;
+        wal.sync()?;
+        let wal_path = wal.path().to_path_buf();
+        let env = &self.env;
+        let synced = env.file_size(&wal_path)?;
+        if let Some(parent) = target.parent() {
+            env.create_dir_all(parent)?;
+        }
+        env.create_dir(target)?;
+
+        for column_family in self.column_families.values() {
+            for table in column_family.tables.iter() {
+                env.link_file(table.path(), &table_path(target, table.file_number()))?;
+            }
+        }
+
+        let mut source = env.open_file(&wal_path)?.take(synced);
+        let mut wal_copy = env.create_file(&target.join(wal_path.file_name().unwrap()))?;
+        io::copy(&mut source, &mut wal_copy)?;
+        wal_copy.sync()?;
+
+        //Written last, a checkpoint cut short has no MANIFEST and does not open as a Database
+        self.manifest.save_to(target)?;
+        env.sync_dir(target.parent().unwrap_or(Path::new(".")))?;
+        Ok(self.last_timestamp)
+    }
+
+    //Compact the tables of one column family into one and delete the old files once the MANIFEST has moved on
+    fn compact(&mut self, id: u32, snapshots: &[u128]) -> Result<()> {
+        self.check_writable()?;
+        let result = self.compact_tables(id, snapshots);
+        self.record_error(ErrorSeverity::Soft, result)
+    }
+
+    fn compact_tables(&mut self, id: u32, snapshots: &[u128]) -> Result<()> {
+        let column_family = self.column_families.get_mut(&id).unwrap();
+        if column_family.tables.is_empty() {
+            return Ok(());
+        }
+        let file_number = self.manifest.new_file_number();
+        let replaced = column_family.compact(&self.dir, file_number, snapshots, self.clock.now_micros())?;
+        self.manifest.set_tables(id, column_family.table_numbers());
+        self.manifest.save()?;
+        for table in replaced.iter() {
+            self.env.remove_file(table.path())?;
+        }
+        self.env.sync_dir(&self.dir)?;
+        Ok(())
+    }
+}
+
+//A Database that was not closed is closed when it is dropped, a failure goes to Options::on_background_error then
+impl Drop for Database {
+    fn drop(&mut self) {
+        match self.close() {
+            Ok(()) | Err(Error::Closed) => {}
+            Err(err) => self.report_background_error(&err),
+        }
+    }
+}
+
+//How many times a read-only open or a catch-up starts over when the primary deletes a table or WAL it was about to read
+const VANISHED_TABLE_RETRIES: usize = 3;
+
+//A table the MANIFEST listed can be compacted away and deleted by the primary before it is opened,
+//the next read of the MANIFEST no longer lists it
+fn retry_vanished_tables<T>(mut attempt: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut retries = 0;
+    loop {
+        match attempt() {
+            Err(Error::Io(err)) if err.kind() == io::ErrorKind::NotFound && retries < VANISHED_TABLE_RETRIES => retries += 1,
+            result => return result,
+        }
+    }
+}
+
+//Read the MANIFEST and then the WALs it says were not flushed yet, without changing anything in dir
+//A flush the owner of dir finishes in between deletes WALs this MANIFEST still needs, that fails with NotFound
+//so retry_vanished_tables starts over with the new MANIFEST
+fn read_unflushed(
+    env: &Arc<dyn Env>,
+    dir: &Path,
+    comparator: &Arc<dyn Comparator>,
+) -> Result<(Manifest, BTreeMap<u32, MemTable>, Vec<PreparedTransaction>)> {
+    let manifest = Manifest::load(env, dir)?;
+    let (mem_tables, recovered) = WAL::read_mem_tables_from_dir(env, dir, manifest.oldest_wal(), comparator)?;
+    if Manifest::load(env, dir)?.oldest_wal() != manifest.oldest_wal() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "the WAL was flushed while it was read").into());
+    }
+    Ok((manifest, mem_tables, recovered))
+}
+
+//The data on disk can only be read with the comparator it was sorted with
+fn check_comparator(manifest: &Manifest, options: &Options) -> Result<()> {
+    if manifest.comparator() != options.comparator.name() {
+        return Err(Error::ComparatorMismatch {
+            expected: manifest.comparator().to_owned(),
+            found: options.comparator.name().to_owned(),
+        });
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::ManualClock;
+    use crate::column_family::{ColumnFamilyOptions, DEFAULT_COLUMN_FAMILY_ID};
+    use crate::comparator::{BytewiseComparator, CaseInsensitiveComparator, NumericComparator};
+    use crate::compression::Compression;
+    use crate::database::Database;
+    use crate::error::{Error, ErrorSeverity};
+    use crate::mem_table::HistoryRetention;
+    use crate::merge_operator::{AppendOperator, U64AddOperator};
+    use crate::options::{Options, ReadOptions, SyncPolicy, WriteOptions};
+    use crate::prefix_extractor::DelimitedPrefix;
+    use crate::env::{Env, MemEnv, PosixEnv, TempDir};
+    use crate::fault_injection::FaultInjectionEnv;
+    use crate::simulation::Simulation;
+    use crate::sstable::table_path;
+    use crate::write_batch::WriteBatch;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_scan_prefix() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        db.set(b"acme/users/1", b"Badri").unwrap();
+        db.set(b"acme/users/2", b"Lavanya").unwrap();
+        db.set(b"acme/users/3", b"Keerthi").unwrap();
+        db.set(b"acme/orders/1", b"Car").unwrap();
+        db.delete(b"acme/users/2").unwrap();
+
+        let records = db.scan_prefix(b"acme/users/").unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].key(), b"acme/users/1");
+        assert_eq!(records[0].value(), b"Badri");
+        assert_eq!(records[1].key(), b"acme/users/3");
+        assert_eq!(records[1].value(), b"Keerthi");
+
+        assert!(db.scan_prefix(b"globex/").unwrap().is_empty());
+        assert!(db.get(b"acme/users/2").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_snapshot_reads() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        db.set(b"acme/users/1", b"Badri").unwrap();
+        db.set(b"acme/users/2", b"Lavanya").unwrap();
+
+        let snapshot = db.snapshot();
+        db.set(b"acme/users/1", b"Badri Krishnan").unwrap();
+        db.delete(b"acme/users/2").unwrap();
+        db.set(b"acme/users/3", b"Keerthi").unwrap();
+
+        assert_eq!(db.get_with_snapshot(b"acme/users/1", &snapshot).unwrap().unwrap().value(), b"Badri");
+        assert_eq!(db.get_with_snapshot(b"acme/users/2", &snapshot).unwrap().unwrap().value(), b"Lavanya");
+        assert!(db.get_with_snapshot(b"acme/users/3", &snapshot).unwrap().is_none());
+
+        let records = db.scan_prefix_with_snapshot(b"acme/users/", &snapshot).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].value(), b"Badri");
+        assert_eq!(records[1].value(), b"Lavanya");
+
+        assert_eq!(db.get(b"acme/users/1").unwrap().unwrap().value(), b"Badri Krishnan");
+        assert!(db.get(b"acme/users/2").unwrap().is_none());
+
+        //Once the snapshot is gone the next write drops the versions only it could see
+        drop(snapshot);
+        db.set(b"acme/users/4", b"Car").unwrap();
+        assert_eq!(db.lock().column_family(DEFAULT_COLUMN_FAMILY_ID).mem_table.len(), 4);
+    }
+
+    #[test]
+    fn test_time_travel() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        db.set_history_retention(HistoryRetention { window: None, max_versions: Some(10) });
+        db.set(b"Car", b"Garage").unwrap();
+        db.set(b"Car", b"Driveway").unwrap();
+        db.delete(b"Car").unwrap();
+
+        let versions = db.versions(b"Car").unwrap();
+        assert_eq!(versions.len(), 3);
+        assert!(versions[0].deleted());
+        assert_eq!(versions[1].value(), b"Driveway");
+        assert_eq!(versions[2].value(), b"Garage");
+
+        assert!(db.get_at(b"Car", versions[0].timestamp()).unwrap().is_none());
+        assert_eq!(db.get_at(b"Car", versions[0].timestamp() - 1).unwrap().unwrap().value(), b"Driveway");
+        assert_eq!(db.get_at(b"Car", versions[2].timestamp()).unwrap().unwrap().value(), b"Garage");
+        assert!(db.get_at(b"Car", versions[2].timestamp() - 1).unwrap().is_none());
+
+        //History survives a restart because the WAL replay keeps every version
+        drop(db);
+        let db = Database::open("db", &options).unwrap();
+        db.set_history_retention(HistoryRetention { window: None, max_versions: Some(2) });
+        assert_eq!(db.versions(b"Car").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        db.set(b"Car", b"Garage").unwrap();
+        db.set(b"Bike", b"Bike Rack").unwrap();
+        db.set(b"Pedestrian", b"Pedastrian Walkway").unwrap();
+        db.delete(b"Bike").unwrap();
+
+        let records = db.multi_get(&[b"Pedestrian", b"Bike", b"Truck", b"Car", b"Pedestrian"]).unwrap();
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[0].as_ref().unwrap().value(), b"Pedastrian Walkway");
+        assert!(records[1].is_none());
+        assert!(records[2].is_none());
+        assert_eq!(records[3].as_ref().unwrap().value(), b"Garage");
+        assert_eq!(records[4].as_ref().unwrap().key(), b"Pedestrian");
+        assert!(db.multi_get(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ttl_expiry() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        db.set(b"session/1", b"Badri").unwrap();
+        db.set_with_ttl(b"session/1", b"Lavanya", Duration::from_millis(20)).unwrap();
+        db.set_with_ttl(b"session/2", b"Keerthi", Duration::from_secs(60)).unwrap();
+        assert_eq!(db.get(b"session/1").unwrap().unwrap().value(), b"Lavanya");
+
+        thread::sleep(Duration::from_millis(30));
+        //The expired value hides the key, the older value does not come back
+        assert!(db.get(b"session/1").unwrap().is_none());
+        assert_eq!(db.scan_prefix(b"session/").unwrap().len(), 1);
+        assert_eq!(db.multi_get(&[b"session/1", b"session/2"]).unwrap().iter().flatten().count(), 1);
+
+        //Expiry survives a restart and sweeping writes a tombstone
+        drop(db);
+        let db = Database::open("db", &options).unwrap();
+        assert!(db.get(b"session/1").unwrap().is_none());
+        assert_eq!(db.sweep_expired().unwrap(), 1);
+        assert_eq!(db.sweep_expired().unwrap(), 0);
+        assert!(db.versions(b"session/1").unwrap()[0].deleted());
+        assert_eq!(db.get(b"session/2").unwrap().unwrap().value(), b"Keerthi");
+    }
+
+    #[test]
+    fn test_ttl_sweeper() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Arc::new(Database::open("db", &options).unwrap());
+        db.start_ttl_sweeper(Duration::from_millis(5));
+        db.set_with_ttl(b"cache/1", b"Car", Duration::from_millis(10)).unwrap();
+
+        let mut swept = false;
+        for _ in 0..200 {
+            thread::sleep(Duration::from_millis(5));
+            if db.versions(b"cache/1").unwrap()[0].deleted() {
+                swept = true;
+                break;
+            }
+        }
+        assert!(swept);
+        db.stop_ttl_sweeper();
+        drop(db);
+    }
+
+    #[test]
+    fn test_merge() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        assert!(db.merge(b"counter", &1u64.to_le_bytes()).is_err());
+        db.set_merge_operator(Box::new(U64AddOperator));
+
+        db.merge(b"counter", &1u64.to_le_bytes()).unwrap();
+        db.merge(b"counter", &2u64.to_le_bytes()).unwrap();
+        assert_eq!(db.get(b"counter").unwrap().unwrap().value(), 3u64.to_le_bytes());
+
+        let snapshot = db.snapshot();
+        db.set(b"counter", &10u64.to_le_bytes()).unwrap();
+        db.merge(b"counter", &5u64.to_le_bytes()).unwrap();
+        assert_eq!(db.get(b"counter").unwrap().unwrap().value(), 15u64.to_le_bytes());
+        assert_eq!(db.get_with_snapshot(b"counter", &snapshot).unwrap().unwrap().value(), 3u64.to_le_bytes());
+        assert_eq!(db.scan_prefix(b"count").unwrap()[0].value(), 15u64.to_le_bytes());
+        assert_eq!(db.multi_get(&[b"counter"]).unwrap()[0].as_ref().unwrap().value(), 15u64.to_le_bytes());
+        drop(snapshot);
+
+        //A delete under the operands resets the counter
+        db.delete(b"counter").unwrap();
+        db.merge(b"counter", &7u64.to_le_bytes()).unwrap();
+        assert_eq!(db.get(b"counter").unwrap().unwrap().value(), 7u64.to_le_bytes());
+
+        //Operands are replayed from the WAL, the operator can be set while the Database is shared
+        drop(db);
+        let db = Arc::new(Database::open("db", &options).unwrap());
+        assert!(db.get(b"counter").unwrap().is_none());
+        let shared = db.clone();
+        thread::spawn(move || shared.set_merge_operator(Box::new(U64AddOperator))).join().unwrap();
+        assert_eq!(db.get(b"counter").unwrap().unwrap().value(), 7u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_merge_in_transaction() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        db.set_merge_operator(Box::new(AppendOperator::with_delimiter(b",")));
+        db.set(b"list", b"a").unwrap();
+        db.merge(b"list", b"b").unwrap();
+
+        let mut txn = db.begin_optimistic();
+        assert_eq!(txn.get(b"list").unwrap().unwrap(), b"a,b");
+        db.merge(b"list", b"c").unwrap();
+        txn.set(b"other", b"1");
+        assert!(txn.commit().is_err());
+        assert_eq!(db.get(b"list").unwrap().unwrap().value(), b"a,b,c");
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        db.put_if_absent(b"leader", b"Badri").unwrap();
+        match db.put_if_absent(b"leader", b"Lavanya") {
+            Err(Error::ConditionFailed { current }) => assert_eq!(current.unwrap(), b"Badri"),
+            _ => panic!("expected the condition to fail"),
+        }
+
+        db.compare_and_swap(b"leader", Some(b"Badri"), Some(b"Lavanya")).unwrap();
+        assert_eq!(db.get(b"leader").unwrap().unwrap().value(), b"Lavanya");
+        assert!(matches!(
+            db.compare_and_swap(b"leader", Some(b"Badri"), None),
+            Err(Error::ConditionFailed { .. })
+        ));
+
+        db.compare_and_swap(b"leader", Some(b"Lavanya"), None).unwrap();
+        assert!(db.get(b"leader").unwrap().is_none());
+        match db.compare_and_swap(b"leader", Some(b"Lavanya"), Some(b"Keerthi")) {
+            Err(Error::ConditionFailed { current }) => assert!(current.is_none()),
+            _ => panic!("expected the condition to fail"),
+        }
+
+        //Only one of many racing writers wins the election
+        let winners = thread::scope(|scope| {
+            let handles: Vec<_> = (0..8u8)
+                .map(|id| {
+                    let db = &db;
+                    scope.spawn(move || db.put_if_absent(b"leader", &[id]).is_ok())
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).filter(|won| *won).count()
+        });
+        assert_eq!(winners, 1);
+    }
+
+    #[test]
+    fn test_stalled_clock() {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let options = Options::new().env(Arc::new(MemEnv::new())).clock(clock.clone());
+        let db = Database::open("db", &options).unwrap();
+        db.set_merge_operator(Box::new(U64AddOperator));
+
+        //Every write after the first is stamped past the clock, each one is read back right away
+        db.set(b"Badri", b"1").unwrap();
+        db.set(b"Badri", b"2").unwrap();
+        assert_eq!(db.get(b"Badri").unwrap().unwrap().value(), b"2");
+        db.set(b"Lavanya", b"1").unwrap();
+        assert_eq!(db.get(b"Lavanya").unwrap().unwrap().value(), b"1");
+        db.merge(b"counter", &1u64.to_le_bytes()).unwrap();
+        db.merge(b"counter", &2u64.to_le_bytes()).unwrap();
+        assert_eq!(db.get(b"counter").unwrap().unwrap().value(), 3u64.to_le_bytes());
+        assert_eq!(db.scan_prefix(b"").unwrap().len(), 3);
+        assert_eq!(db.multi_get(&[b"Badri", b"Lavanya"]).unwrap().iter().flatten().count(), 2);
+
+        //Stepping back does not hide them either, and a snapshot taken now sees them too
+        clock.set(10);
+        db.delete(b"Lavanya").unwrap();
+        assert!(db.get(b"Lavanya").unwrap().is_none());
+        let snapshot = db.snapshot();
+        db.set(b"Badri", b"3").unwrap();
+        assert_eq!(db.get_with_snapshot(b"Badri", &snapshot).unwrap().unwrap().value(), b"2");
+        assert_eq!(db.get(b"Badri").unwrap().unwrap().value(), b"3");
+
+        //Expiry still goes by the clock
+        db.set_with_ttl(b"session", b"Keerthi", Duration::from_micros(100)).unwrap();
+        assert_eq!(db.get(b"session").unwrap().unwrap().value(), b"Keerthi");
+        clock.set(2_000);
+        assert!(db.get(b"session").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_clock_behind_oldest_wal() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let options = Options::new().env(Arc::new(MemEnv::new())).clock(clock.clone());
+        let db = Database::open("db", &options).unwrap();
+        db.set(b"Badri", b"1").unwrap();
+        db.flush().unwrap();
+        let oldest_wal = db.lock().manifest.oldest_wal();
+        drop(db);
+
+        //Reopened with the clock stepped back, the new WAL still comes after the one the flush left
+        clock.set(10);
+        let db = Database::open("db", &options).unwrap();
+        let wal_path = db.lock().wal().unwrap().path().to_path_buf();
+        let number: u128 = wal_path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+        assert!(number > oldest_wal);
+        db.set(b"Lavanya", b"2").unwrap();
+        drop(db);
+
+        //The write is replayed from it and not skipped as flushed
+        let db = Database::open("db", &options).unwrap();
+        assert_eq!(db.get(b"Badri").unwrap().unwrap().value(), b"1");
+        assert_eq!(db.get(b"Lavanya").unwrap().unwrap().value(), b"2");
+    }
+
+    #[test]
+    fn test_compare_and_swap_stalled_clock() {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let options = Options::new().env(Arc::new(MemEnv::new())).clock(clock.clone());
+        let db = Database::open("db", &options).unwrap();
+
+        //Both writes land in the same microsecond, the second one is stamped after the clock
+        db.set(b"leader", b"Badri").unwrap();
+        db.set(b"leader", b"Lavanya").unwrap();
+        db.compare_and_swap(b"leader", Some(b"Lavanya"), Some(b"Keerthi")).unwrap();
+        match db.compare_and_swap(b"leader", Some(b"Lavanya"), None) {
+            Err(Error::ConditionFailed { current }) => assert_eq!(current.unwrap(), b"Keerthi"),
+            _ => panic!("expected the condition to fail"),
+        }
+        db.set(b"follower", b"Car").unwrap();
+        assert!(matches!(db.put_if_absent(b"follower", b"Ford"), Err(Error::ConditionFailed { .. })));
+
+        //The clock steps back, what expires still goes by the clock
+        clock.set(500);
+        db.compare_and_swap(b"follower", Some(b"Car"), Some(b"Tesla")).unwrap();
+        db.set_with_ttl(b"session", b"Badri", Duration::from_micros(10)).unwrap();
+        db.compare_and_swap(b"session", Some(b"Badri"), Some(b"Lavanya")).unwrap();
+        db.set_with_ttl(b"session", b"Keerthi", Duration::from_micros(10)).unwrap();
+        clock.set(2_000);
+        db.put_if_absent(b"session", b"Car").unwrap();
+    }
+
+    #[test]
+    fn test_column_families() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        db.create_column_family("users", ColumnFamilyOptions::default()).unwrap();
+        db.create_column_family("orders", ColumnFamilyOptions::default()).unwrap();
+        assert!(matches!(
+            db.create_column_family("users", ColumnFamilyOptions::default()),
+            Err(Error::ColumnFamilyExists { .. })
+        ));
+        assert_eq!(db.list_column_families(), vec!["default", "users", "orders"]);
+
+        //The same key lives separately in every family
+        db.set(b"1", b"default").unwrap();
+        db.set_cf("users", b"1", b"Badri").unwrap();
+        assert_eq!(db.get_cf("users", b"1").unwrap().unwrap().value(), b"Badri");
+        assert_eq!(db.get(b"1").unwrap().unwrap().value(), b"default");
+        assert!(db.get_cf("orders", b"1").unwrap().is_none());
+        assert!(matches!(db.get_cf("nope", b"1"), Err(Error::UnknownColumnFamily { .. })));
+
+        //A batch across families commits together, or not at all when a family is missing
+        let mut batch = WriteBatch::new();
+        batch.set_cf("users", b"2", b"Lavanya");
+        batch.set_cf("orders", b"1", b"Car");
+        batch.delete(b"1");
+        db.write(&batch).unwrap();
+        batch.set_cf("nope", b"1", b"lost");
+        assert!(db.write(&batch).is_err());
+        assert_eq!(db.scan_prefix_cf("users", b"").unwrap().len(), 2);
+        assert_eq!(db.get_cf("orders", b"1").unwrap().unwrap().timestamp(), db.get_cf("users", b"2").unwrap().unwrap().timestamp());
+
+        //Families and their writes come back after a restart, a dropped family does not
+        db.drop_column_family("orders").unwrap();
+        assert!(matches!(db.drop_column_family("default"), Err(Error::DropDefaultColumnFamily)));
+        drop(db);
+        let db = Database::open("db", &options).unwrap();
+        assert_eq!(db.list_column_families(), vec!["default", "users"]);
+        assert_eq!(db.get_cf("users", b"2").unwrap().unwrap().value(), b"Lavanya");
+        assert!(db.get(b"1").unwrap().is_none());
+        db.create_column_family("orders", ColumnFamilyOptions::default()).unwrap();
+        assert!(db.get_cf("orders", b"1").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_flush_and_compaction() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let cf_options = ColumnFamilyOptions {
+            write_buffer_size: 4096,
+            compaction_trigger: 3,
+            prefix_extractor: Some(Arc::new(DelimitedPrefix::new(b'/', 1))),
+            merge_operator: Some(Arc::new(U64AddOperator)),
+            ..ColumnFamilyOptions::default()
+        };
+        let db = Database::open("db", &options).unwrap();
+        db.set_column_family_options("default", cf_options.clone()).unwrap();
+        db.set(b"counter", &1u64.to_le_bytes()).unwrap();
+        db.set(b"gone/1", b"Car").unwrap();
+        db.flush().unwrap();
+        let snapshot = db.snapshot();
+        db.delete(b"gone/1").unwrap();
+        db.merge(b"counter", &2u64.to_le_bytes()).unwrap();
+
+        //Enough writes to flush on their own and compact
+        for i in 0..500u32 {
+            db.set(format!("user/{:04}", i).as_bytes(), &[7; 32]).unwrap();
+        }
+        db.merge(b"counter", &3u64.to_le_bytes()).unwrap();
+        let tables = db.lock().column_family(DEFAULT_COLUMN_FAMILY_ID).tables.len();
+        assert!(tables < 3);
+        assert_eq!(db.get(b"counter").unwrap().unwrap().value(), 6u64.to_le_bytes());
+        assert_eq!(db.get_with_snapshot(b"gone/1", &snapshot).unwrap().unwrap().value(), b"Car");
+        assert!(db.get(b"gone/1").unwrap().is_none());
+        assert_eq!(db.scan_prefix(b"user/").unwrap().len(), 500);
+        assert_eq!(db.multi_get(&[b"user/0499", b"user/0500", b"counter"]).unwrap().iter().flatten().count(), 2);
+
+        //Without the snapshot compaction drops the tombstone and folds the operands
+        drop(snapshot);
+        db.flush().unwrap();
+        db.compact().unwrap();
+        assert_eq!(db.lock().column_family(DEFAULT_COLUMN_FAMILY_ID).tables.len(), 1);
+        assert!(db.versions(b"gone/1").unwrap().is_empty());
+        let versions = db.versions(b"counter").unwrap();
+        assert_eq!(versions.len(), 1);
+        assert!(!versions[0].merge_operand());
+
+        drop(db);
+        let db = Database::open("db", &options).unwrap();
+        db.set_column_family_options("default", cf_options).unwrap();
+        assert_eq!(db.get(b"counter").unwrap().unwrap().value(), 6u64.to_le_bytes());
+        assert_eq!(db.scan_prefix(b"user/").unwrap().len(), 500);
+        assert!(db.scan_prefix(b"gone/").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_comparator() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options.clone().comparator(Arc::new(NumericComparator))).unwrap();
+        db.set_column_family_options("default", ColumnFamilyOptions { compaction_trigger: 2, ..ColumnFamilyOptions::default() }).unwrap();
+        for i in [100u32, 9, 25, 1000] {
+            db.set(i.to_string().as_bytes(), b"Badri").unwrap();
+        }
+        db.flush().unwrap();
+        db.set(b"3", b"Lavanya").unwrap();
+        db.delete(b"25").unwrap();
+        db.flush().unwrap();
+        assert_eq!(db.lock().column_family(DEFAULT_COLUMN_FAMILY_ID).tables.len(), 1);
+        db.set(b"40", b"Keerthi").unwrap();
+
+        let keys = |db: &Database| -> Vec<Vec<u8>> { db.scan_prefix(b"").unwrap().iter().map(|r| r.key().to_vec()).collect() };
+        let expected: Vec<Vec<u8>> = [&b"3"[..], b"9", b"40", b"100", b"1000"].iter().map(|k| k.to_vec()).collect();
+        assert_eq!(keys(&db), expected);
+        let found = db.multi_get(&[b"1000", b"9", b"25"]).unwrap();
+        assert_eq!(found.iter().map(Option::is_some).collect::<Vec<bool>>(), vec![true, true, false]);
+
+        //The order is kept on disk, the Database cannot be opened with another comparator
+        drop(db);
+        assert!(matches!(
+            Database::open("db", &options.clone().comparator(Arc::new(BytewiseComparator))),
+            Err(Error::ComparatorMismatch { .. })
+        ));
+        let db = Database::open("db", &options.clone().comparator(Arc::new(NumericComparator))).unwrap();
+        assert_eq!(keys(&db), expected);
+        assert_eq!(db.get(b"40").unwrap().unwrap().value(), b"Keerthi");
+    }
+
+    #[test]
+    fn test_comparator_equality() {
+        let options = Options::new().env(Arc::new(MemEnv::new())).comparator(Arc::new(CaseInsensitiveComparator));
+        let db = Database::open("db", &options).unwrap();
+        db.set(b"Badri", b"Krishnan").unwrap();
+        db.set(b"Car", b"Ford").unwrap();
+        db.flush().unwrap();
+        db.set(b"CAR", b"Tesla").unwrap();
+
+        //Found in the MemTable and in the table whatever the case it is asked for in
+        assert_eq!(db.get(b"BADRI").unwrap().unwrap().value(), b"Krishnan");
+        assert_eq!(db.get(b"car").unwrap().unwrap().value(), b"Tesla");
+        assert_eq!(db.multi_get(&[b"badri", b"car"]).unwrap().iter().flatten().count(), 2);
+        assert_eq!(db.scan_prefix(b"").unwrap().len(), 2);
+
+        //Compaction keeps one version of the key
+        db.flush().unwrap();
+        db.compact().unwrap();
+        assert_eq!(db.versions(b"car").unwrap().len(), 1);
+        assert_eq!(db.get(b"Car").unwrap().unwrap().value(), b"Tesla");
+    }
+
+    #[test]
+    fn test_comparator_equal_keys_across_blocks() {
+        let options = Options::new().env(Arc::new(MemEnv::new())).comparator(Arc::new(CaseInsensitiveComparator));
+        let db = Database::open("db", &options).unwrap();
+        db.set_history_retention(HistoryRetention { window: None, max_versions: Some(1000) });
+        db.set_merge_operator(Box::new(AppendOperator::new()));
+
+        //Far more than a block of versions of one key, written in two cases
+        let value = |i: usize| vec![b'a' + (i % 26) as u8; 150];
+        for i in 0..60 {
+            let key: &[u8] = if i % 2 == 0 { b"bbbb" } else { b"BbBb" };
+            db.set(key, &value(i)).unwrap();
+        }
+        //And a merge base with more than a block of operands on top of it
+        db.set(b"log", &value(0)).unwrap();
+        for i in 1..40 {
+            let key: &[u8] = if i % 2 == 0 { b"log" } else { b"LOG" };
+            db.merge(key, &value(i)).unwrap();
+        }
+        let merged: Vec<u8> = (0..40).flat_map(value).collect();
+        assert!(merged.len() > 4096);
+
+        for flushed in [false, true] {
+            assert_eq!(db.versions(b"bbbb").unwrap().len(), 60, "flushed: {}", flushed);
+            assert_eq!(db.get(b"BBBB").unwrap().unwrap().value(), value(59));
+            assert_eq!(db.get(b"Log").unwrap().unwrap().value(), merged);
+            let found = db.multi_get(&[b"bbbb", b"log"]).unwrap();
+            assert_eq!(found[1].as_ref().unwrap().value(), merged);
+            db.flush().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_open_with_options() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+
+        let missing = Options::new().env(env.clone()).create_if_missing(false);
+        assert!(matches!(Database::open("db", &missing), Err(Error::DatabaseNotFound { .. })));
+
+        let options = Options::new()
+            .env(env.clone())
+            .write_buffer_size(4096)
+            .compression(Compression::Lz)
+            .sync_policy(SyncPolicy::EveryWrite)
+            .block_cache_size(64 * 1024);
+        let db = Database::open("db", &options).unwrap();
+        //Small enough a MemTable that the writes flush on their own
+        for i in 0..300u32 {
+            db.set(format!("user/{:04}", i).as_bytes(), b"Badri Krishnan").unwrap();
+        }
+        assert!(!db.lock().column_family(DEFAULT_COLUMN_FAMILY_ID).tables.is_empty());
+        let snapshot = db.snapshot();
+        db.set_opt(b"user/0000", b"Lavanya", &WriteOptions::new().sync(true)).unwrap();
+        db.delete_opt(b"user/0001", &WriteOptions::new().sync(true)).unwrap();
+
+        let at_snapshot = ReadOptions::new().snapshot(&snapshot);
+        assert_eq!(db.get_opt(b"user/0000", &at_snapshot).unwrap().unwrap().value(), b"Badri Krishnan");
+        assert!(db.get_opt(b"user/0001", &at_snapshot).unwrap().is_some());
+        assert_eq!(db.get(b"user/0000").unwrap().unwrap().value(), b"Lavanya");
+        assert!(db.get(b"user/0001").unwrap().is_none());
+
+        //A scan that does not fill the cache leaves it as it was
+        let usage = db.block_cache_usage();
+        assert_eq!(db.scan_prefix_opt(b"user/", &ReadOptions::new().fill_cache(false)).unwrap().len(), 299);
+        assert_eq!(db.block_cache_usage(), usage);
+        assert_eq!(db.multi_get_opt(&[b"user/0100", b"user/0001"], &at_snapshot).unwrap().iter().flatten().count(), 2);
+        assert!(db.block_cache_usage() > usage);
+        drop(snapshot);
+
+        drop(db);
+        assert!(matches!(
+            Database::open("db", &Options::new().env(env.clone()).error_if_exists(true)),
+            Err(Error::DatabaseExists { .. })
+        ));
+        assert!(matches!(
+            Database::open("db", &Options::new().env(env.clone()).comparator(Arc::new(NumericComparator))),
+            Err(Error::ComparatorMismatch { .. })
+        ));
+        //The compressed tables are still read after the compression changes
+        let db = Database::open("db", &Options::new().env(env).create_if_missing(false)).unwrap();
+        assert_eq!(db.scan_prefix(b"user/").unwrap().len(), 299);
+        assert_eq!(db.get(b"user/0000").unwrap().unwrap().value(), b"Lavanya");
+    }
+
+    #[test]
+    fn test_directory_lock() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        db.set(b"Badri", b"Krishnan").unwrap();
+        //A second open fails before it touches the WAL the first one is writing to
+        assert!(matches!(Database::open("db", &options), Err(Error::Locked { .. })));
+        db.set(b"Lavanya", b"Krishnan").unwrap();
+        assert_eq!(db.get(b"Badri").unwrap().unwrap().value(), b"Krishnan");
+
+        drop(db);
+        let db = Database::open("db", &options).unwrap();
+        assert_eq!(db.get(b"Lavanya").unwrap().unwrap().value(), b"Krishnan");
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let options = Options::new().env(env.clone());
+        assert!(matches!(Database::open_read_only("db", &options), Err(Error::DatabaseNotFound { .. })));
+
+        let db = Database::open("db", &options).unwrap();
+        db.create_column_family("users", ColumnFamilyOptions::default()).unwrap();
+        db.set(b"Badri", b"Krishnan").unwrap();
+        db.flush().unwrap();
+        db.set(b"Lavanya", b"Krishnan").unwrap();
+        db.set_cf("users", b"Keerthi", b"Krishnan").unwrap();
+        let files = || -> Vec<PathBuf> {
+            let mut files = env.list_dir(Path::new("db")).unwrap();
+            files.sort();
+            files
+        };
+        let before = files();
+
+        //Opened next to the Database that owns the directory, from the tables and the live WAL
+        let reader = Database::open_read_only("db", &options).unwrap();
+        assert!(reader.is_read_only());
+        assert!(!db.is_read_only());
+        assert_eq!(reader.get(b"Badri").unwrap().unwrap().value(), b"Krishnan");
+        assert_eq!(reader.get(b"Lavanya").unwrap().unwrap().value(), b"Krishnan");
+        assert_eq!(reader.get_cf("users", b"Keerthi").unwrap().unwrap().value(), b"Krishnan");
+
+        assert!(matches!(reader.set(b"Car", b"Tesla"), Err(Error::ReadOnly)));
+        assert!(matches!(reader.delete(b"Badri"), Err(Error::ReadOnly)));
+        let mut batch = WriteBatch::new();
+        batch.set(b"Car", b"Tesla");
+        assert!(matches!(reader.write(&batch), Err(Error::ReadOnly)));
+        assert!(matches!(reader.put_if_absent(b"Car", b"Tesla"), Err(Error::ReadOnly)));
+        assert!(matches!(reader.create_column_family("orders", ColumnFamilyOptions::default()), Err(Error::ReadOnly)));
+        assert!(matches!(reader.drop_column_family("users"), Err(Error::ReadOnly)));
+        assert!(matches!(reader.flush(), Err(Error::ReadOnly)));
+        assert!(matches!(reader.compact(), Err(Error::ReadOnly)));
+        let mut transaction = reader.begin_optimistic();
+        transaction.set(b"Car", b"Tesla");
+        assert!(matches!(transaction.commit(), Err(Error::ReadOnly)));
+        assert!(reader.get(b"Car").unwrap().is_none());
+        drop(reader);
+        assert_eq!(files(), before);
+
+        //The owner keeps writing as before
+        db.set(b"Car", b"Tesla").unwrap();
+        drop(db);
+        let db = Database::open("db", &options).unwrap();
+        assert_eq!(db.get(b"Car").unwrap().unwrap().value(), b"Tesla");
+    }
+
+    #[test]
+    fn test_secondary_catch_up() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let primary = Database::open("db", &options).unwrap();
+        primary.set_column_family_options("default", ColumnFamilyOptions { compaction_trigger: 2, ..ColumnFamilyOptions::default() }).unwrap();
+        primary.set(b"Badri", b"Krishnan").unwrap();
+        let secondary = Database::open_as_secondary("db", &options).unwrap();
+        assert!(secondary.is_read_only());
+        assert_eq!(secondary.get(b"Badri").unwrap().unwrap().value(), b"Krishnan");
+        assert!(matches!(primary.try_catch_up(), Err(Error::NotSecondary)));
+        assert!(matches!(Database::open_read_only("db", &options).unwrap().try_catch_up(), Err(Error::NotSecondary)));
+
+        //Newly logged writes, flushed and compacted tables and a new column family
+        primary.set(b"Lavanya", b"Krishnan").unwrap();
+        primary.flush().unwrap();
+        primary.delete(b"Badri").unwrap();
+        primary.flush().unwrap();
+        primary.create_column_family("users", ColumnFamilyOptions::default()).unwrap();
+        primary.set_cf("users", b"Keerthi", b"Krishnan").unwrap();
+        primary.set(b"Car", b"Tesla").unwrap();
+        assert!(secondary.get(b"Lavanya").unwrap().is_none());
+        assert!(secondary.get_cf("users", b"Keerthi").is_err());
+
+        secondary.try_catch_up().unwrap();
+        assert!(secondary.get(b"Badri").unwrap().is_none());
+        assert_eq!(secondary.get(b"Lavanya").unwrap().unwrap().value(), b"Krishnan");
+        assert_eq!(secondary.get(b"Car").unwrap().unwrap().value(), b"Tesla");
+        assert_eq!(secondary.get_cf("users", b"Keerthi").unwrap().unwrap().value(), b"Krishnan");
+        assert_eq!(secondary.list_column_families(), vec!["default", "users"]);
+        assert!(matches!(secondary.set(b"Car", b"Ford"), Err(Error::ReadOnly)));
+
+        //Catching up again with nothing new changes nothing
+        secondary.try_catch_up().unwrap();
+        assert_eq!(secondary.scan_prefix(b"").unwrap().len(), 2);
+
+        drop(secondary);
+        drop(primary);
+    }
+
+    #[test]
+    fn test_read_only_skips_flushed_wal() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let options = Options::new().env(env.clone());
+        let primary = Database::open("db", &options).unwrap();
+        primary.set_merge_operator(Box::new(U64AddOperator));
+        primary.merge(b"counter", &1u64.to_le_bytes()).unwrap();
+        primary.merge(b"counter", &2u64.to_le_bytes()).unwrap();
+        let wal_path = primary.lock().wal().unwrap().path().to_path_buf();
+        primary.lock().wal().unwrap().sync().unwrap();
+        let mut logged = Vec::new();
+        env.open_file(&wal_path).unwrap().read_to_end(&mut logged).unwrap();
+        primary.flush().unwrap();
+
+        //A crash between the MANIFEST save and the delete leaves the flushed WAL behind
+        let write_wal = |path: &Path| {
+            let mut file = env.create_file(path).unwrap();
+            file.write_all(&logged).unwrap();
+            file.sync().unwrap();
+        };
+        write_wal(&wal_path);
+        let read = |db: &Database| u64::from_le_bytes(db.get(b"counter").unwrap().unwrap().value().try_into().unwrap());
+        let reader = Database::open_read_only("db", &options).unwrap();
+        reader.set_merge_operator(Box::new(U64AddOperator));
+        assert_eq!(read(&reader), 3);
+        let secondary = Database::open_as_secondary("db", &options).unwrap();
+        secondary.set_merge_operator(Box::new(U64AddOperator));
+        assert_eq!(read(&secondary), 3);
+
+        //Records the tables hold already are not applied twice, even from a WAL that is not below oldest_wal
+        env.remove_file(&wal_path).unwrap();
+        write_wal(&Path::new("db").join(format!("{}.wal", u64::MAX)));
+        secondary.try_catch_up().unwrap();
+        assert_eq!(read(&secondary), 3);
+        let reader = Database::open_read_only("db", &options).unwrap();
+        reader.set_merge_operator(Box::new(U64AddOperator));
+        assert_eq!(read(&reader), 3);
+
+        primary.merge(b"counter", &4u64.to_le_bytes()).unwrap();
+        secondary.try_catch_up().unwrap();
+        assert_eq!(read(&secondary), 7);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        db.create_column_family("users", ColumnFamilyOptions::default()).unwrap();
+        db.set(b"Badri", b"Krishnan").unwrap();
+        db.set_cf("users", b"Keerthi", b"Krishnan").unwrap();
+        db.flush().unwrap();
+        db.set(b"Lavanya", b"Krishnan").unwrap();
+        db.delete(b"Badri").unwrap();
+        db.checkpoint("checkpoint").unwrap();
+        assert!(db.checkpoint("checkpoint").is_err());
+
+        //The Database moves on without the checkpoint seeing it
+        db.set(b"Car", b"Tesla").unwrap();
+        db.flush().unwrap();
+        db.compact().unwrap();
+        drop(db);
+
+        let copy = Database::open("checkpoint", &options.clone().create_if_missing(false)).unwrap();
+        assert!(copy.get(b"Badri").unwrap().is_none());
+        assert_eq!(copy.get(b"Lavanya").unwrap().unwrap().value(), b"Krishnan");
+        assert_eq!(copy.get_cf("users", b"Keerthi").unwrap().unwrap().value(), b"Krishnan");
+        assert!(copy.get(b"Car").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_checkpoint_posix_env() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = TempDir::new();
+        let dir = temp_dir.path();
+        let target = dir.join("checkpoint");
+        let options = Options::new().env(Arc::new(PosixEnv));
+
+        let db = Database::open(dir, &options).unwrap();
+        db.set(b"Badri", b"Krishnan").unwrap();
+        db.flush().unwrap();
+        db.set(b"Lavanya", b"Krishnan").unwrap();
+        db.checkpoint(&target).unwrap();
+
+        //The tables are the same files on disk, hard linked rather than copied
+        let table = db.lock().column_family(DEFAULT_COLUMN_FAMILY_ID).tables[0].file_number();
+        let inode = |dir: &Path| table_path(dir, table).metadata().unwrap().ino();
+        assert_eq!(inode(dir), inode(&target));
+        db.compact().unwrap();
+        drop(db);
+
+        let copy = Database::open(&target, &options.create_if_missing(false)).unwrap();
+        assert_eq!(copy.get(b"Badri").unwrap().unwrap().value(), b"Krishnan");
+        assert_eq!(copy.get(b"Lavanya").unwrap().unwrap().value(), b"Krishnan");
+    }
+
+    #[test]
+    fn test_close() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Arc::new(Database::open("db", &options.clone().flush_on_close(true)).unwrap());
+        db.start_ttl_sweeper(Duration::from_millis(5));
+        db.set(b"Badri", b"Krishnan").unwrap();
+        db.close().unwrap();
+        assert!(matches!(db.get(b"Badri"), Err(Error::Closed)));
+        assert!(matches!(db.set(b"Lavanya", b"Krishnan"), Err(Error::Closed)));
+        assert!(matches!(db.flush(), Err(Error::Closed)));
+        assert!(matches!(db.close(), Err(Error::Closed)));
+
+        //The directory is released while the closed Database is still around, its MemTable went to a table
+        let reopened = Database::open("db", &options).unwrap();
+        assert_eq!(reopened.lock().column_family(DEFAULT_COLUMN_FAMILY_ID).tables.len(), 1);
+        assert_eq!(reopened.get(b"Badri").unwrap().unwrap().value(), b"Krishnan");
+        drop(db);
+        reopened.set(b"Lavanya", b"Krishnan").unwrap();
+        drop(reopened);
+
+        let reopened = Database::open("db", &options).unwrap();
+        assert_eq!(reopened.lock().column_family(DEFAULT_COLUMN_FAMILY_ID).tables.len(), 1);
+        assert_eq!(reopened.get(b"Lavanya").unwrap().unwrap().value(), b"Krishnan");
+        drop(reopened);
+    }
+
+    #[test]
+    fn test_background_error() {
+        let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+        let options = Options::new().env(env.clone());
+        let db = Database::open("db", &options).unwrap();
+        db.set(b"Badri", b"Krishnan").unwrap();
+
+        //The flush cannot create its table, the WAL still holds the writes so the error is soft
+        env.fail_operation(1);
+        assert!(matches!(db.flush(), Err(Error::Io(_))));
+        let stopped = |db: &Database, expected: ErrorSeverity| {
+            matches!(db.background_error(), Some(Error::WritesStopped { severity, .. }) if severity == expected)
+        };
+        assert!(stopped(&db, ErrorSeverity::Soft));
+        assert!(matches!(db.set(b"Lavanya", b"Krishnan"), Err(Error::WritesStopped { .. })));
+        assert_eq!(db.get(b"Badri").unwrap().unwrap().value(), b"Krishnan");
+        //The flush resume starts with fails the same way, the writes stay stopped
+        env.fail_operation(1);
+        assert!(db.resume().is_err());
+        assert!(stopped(&db, ErrorSeverity::Soft));
+        db.resume().unwrap();
+        assert!(db.background_error().is_none());
+        db.set(b"Lavanya", b"Krishnan").unwrap();
+
+        //Appending to the WAL fails in the middle of a write
+        env.fail_operation(1);
+        assert!(matches!(db.set(b"Keerthi", b"Krishnan"), Err(Error::Io(_))));
+        assert!(stopped(&db, ErrorSeverity::Hard));
+        assert!(matches!(db.delete(b"Badri"), Err(Error::WritesStopped { .. })));
+        assert!(matches!(db.flush(), Err(Error::WritesStopped { .. })));
+        assert!(db.get(b"Keerthi").unwrap().is_none());
+
+        //resume leaves the WAL behind for a new one
+        db.resume().unwrap();
+        db.set(b"Keerthi", b"Krishnan").unwrap();
+        drop(db);
+
+        let db = Database::open("db", &options).unwrap();
+        assert_eq!(db.get(b"Badri").unwrap().unwrap().value(), b"Krishnan");
+        assert_eq!(db.get(b"Lavanya").unwrap().unwrap().value(), b"Krishnan");
+        assert_eq!(db.get(b"Keerthi").unwrap().unwrap().value(), b"Krishnan");
+    }
+
+    #[test]
+    fn test_failed_automatic_flush() {
+        let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+        let db = Database::open("db", &Options::new().env(env.clone())).unwrap();
+        db.set(b"Badri", b"Krishnan").unwrap();
+        let before = env.operations();
+        db.set(b"Badri", b"Krishnan").unwrap();
+        let logged = env.operations() - before;
+
+        //The write is logged and applied before the flush it sets off fails, it still succeeds
+        db.set_column_family_options("default", ColumnFamilyOptions { write_buffer_size: 1, ..ColumnFamilyOptions::default() }).unwrap();
+        env.fail_operation(logged + 1);
+        db.set(b"Lavanya", b"Krishnan").unwrap();
+        assert!(matches!(db.background_error(), Some(Error::WritesStopped { severity: ErrorSeverity::Soft, .. })));
+        assert_eq!(db.get(b"Lavanya").unwrap().unwrap().value(), b"Krishnan");
+        assert!(matches!(db.set(b"Keerthi", b"Krishnan"), Err(Error::WritesStopped { .. })));
+
+        db.resume().unwrap();
+        db.set(b"Keerthi", b"Krishnan").unwrap();
+        drop(db);
+        let db = Database::open("db", &Options::new().env(env)).unwrap();
+        assert_eq!(db.get(b"Lavanya").unwrap().unwrap().value(), b"Krishnan");
+        assert_eq!(db.get(b"Keerthi").unwrap().unwrap().value(), b"Krishnan");
+    }
+
+    #[test]
+    fn test_on_background_error() {
+        let simulation = Simulation::new(3);
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let handler = {
+            let errors = errors.clone();
+            Arc::new(move |err: &Error| errors.lock().unwrap().push(err.to_string()))
+        };
+        let options = simulation.options().flush_on_close(true).on_background_error(handler);
+
+        //The sweep cannot log its tombstone, it is tried again on the next interval
+        let db = Arc::new(Database::open("db", &options).unwrap());
+        db.start_ttl_sweeper(Duration::from_millis(10));
+        db.set_with_ttl(b"session", b"Keerthi", Duration::from_millis(5)).unwrap();
+        simulation.env().fail_operation(1);
+        simulation.advance(Duration::from_millis(10));
+        assert_eq!(errors.lock().unwrap().len(), 1);
+        assert!(matches!(db.background_error(), Some(Error::WritesStopped { .. })));
+        db.resume().unwrap();
+        simulation.advance(Duration::from_millis(10));
+        assert_eq!(errors.lock().unwrap().len(), 1);
+        assert!(db.versions(b"session").unwrap()[0].deleted());
+
+        //Dropped without close, the flush on close fails with nobody to return it to
+        db.stop_ttl_sweeper();
+        db.set(b"Badri", b"Krishnan").unwrap();
+        simulation.env().fail_operation(1);
+        drop(db);
+        assert_eq!(errors.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_lose_unsynced_directory_entries() {
+        let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+        let options = Options::new().env(env.clone());
+
+        //WALs are created and deleted, tables written and compacted away and the MANIFEST replaced
+        let db = Database::open("db", &options).unwrap();
+        db.set(b"Badri", b"Krishnan").unwrap();
+        db.flush().unwrap();
+        db.set(b"Lavanya", b"Krishnan").unwrap();
+        db.delete(b"Badri").unwrap();
+        db.flush().unwrap();
+        db.compact().unwrap();
+        db.set_opt(b"Keerthi", b"Krishnan", &WriteOptions::new().sync(true)).unwrap();
+        drop(db);
+
+        //Every change to the directory was synced before it returned, nothing is lost or comes back
+        env.drop_unsynced_data().unwrap();
+        let db = Database::open("db", &options).unwrap();
+        assert!(db.get(b"Badri").unwrap().is_none());
+        assert_eq!(db.get(b"Lavanya").unwrap().unwrap().value(), b"Krishnan");
+        assert_eq!(db.get(b"Keerthi").unwrap().unwrap().value(), b"Krishnan");
+        assert_eq!(db.lock().column_family(DEFAULT_COLUMN_FAMILY_ID).tables.len(), 1);
+    }
+
+    #[test]
+    fn test_in_memory_env() {
+        let dir = Path::new("./db/");
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let options = Options::new().env(env.clone());
+
+        let db = Database::open(dir, &options).unwrap();
+        assert!(matches!(Database::open(dir, &options), Err(Error::Locked { .. })));
+        db.set(b"Badri", b"Krishnan").unwrap();
+        db.flush().unwrap();
+        db.set(b"Lavanya", b"Krishnan").unwrap();
+        db.checkpoint(dir.join("checkpoint")).unwrap();
+        drop(db);
+
+        //Tables, WAL, MANIFEST and the checkpoint all live in env, nothing touched the disk
+        assert!(!dir.exists());
+        for path in [dir.to_path_buf(), dir.join("checkpoint")] {
+            let db = Database::open(&path, &options).unwrap();
+            assert_eq!(db.get(b"Badri").unwrap().unwrap().value(), b"Krishnan");
+            assert_eq!(db.get(b"Lavanya").unwrap().unwrap().value(), b"Krishnan");
+        }
+        //Another MemEnv is another file system
+        let db = Database::open(dir, &Options::new().env(Arc::new(MemEnv::new()))).unwrap();
+        assert!(db.get(b"Badri").unwrap().is_none());
+    }
 }
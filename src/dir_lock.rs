@@ -0,0 +1,56 @@
+//Directory Lock - only one Database open on a directory at a time
+
+/*
+Two Databases on one directory would both replay the WAL and then delete each other's WAL files.
+Opening takes an exclusive lock on the LOCK file in the directory through the Env and holds it until the
+Database is closed or dropped. On PosixEnv it is an advisory lock (flock) the OS drops when the process
+dies, so a crash never leaves it stuck.
+*/
+
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::env::{Env, FileLock};
+use crate::error::{Error, Result};
+
+pub struct DirectoryLock {
+    //Released when dropped
+    _lock: Box<dyn FileLock>,
+}
+
+impl DirectoryLock {
+    //Lock dir, fails with Error::Locked when another Database holds it
+    pub fn acquire(env: &Arc<dyn Env>, dir: &Path) -> Result<DirectoryLock> {
+        let path = dir.join("LOCK");
+        match env.lock_file(&path) {
+            Ok(lock) => Ok(DirectoryLock { _lock: lock }),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Err(Error::Locked { path: dir.to_path_buf() }),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dir_lock::DirectoryLock;
+    use crate::env::{default_env, TempDir};
+    use crate::error::Error;
+    use std::fs::create_dir;
+
+    //flock itself is what is tested, so this one runs on the file system
+    #[test]
+    fn test_acquire_and_release() {
+        let temp_dir = TempDir::new();
+        let dir = temp_dir.path();
+        create_dir(dir).unwrap();
+        let env = default_env();
+
+        let lock = DirectoryLock::acquire(&env, dir).unwrap();
+        assert!(dir.join("LOCK").exists());
+        assert!(matches!(DirectoryLock::acquire(&env, dir), Err(Error::Locked { .. })));
+        drop(lock);
+        let lock = DirectoryLock::acquire(&env, dir).unwrap();
+        drop(lock);
+    }
+}
@@ -0,0 +1,547 @@
+//Env - every file the Database reads or writes goes through one
+
+/*
+PosixEnv is the file system, it is what a Database uses unless Options::env says otherwise.
+MemEnv keeps every file in memory: nothing touches the disk and nothing is left behind, which makes for
+hermetic tests, and a Database on it is gone once the last clone of the MemEnv is dropped.
+
+A custom Env can put the files anywhere else. It has to behave like a POSIX file system for what the
+Database relies on: a rename replaces its target at once, a file is only on disk after it was synced and
+a created, renamed or removed entry only after its directory was synced with sync_dir.
+*/
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub trait Env: Send + Sync {
+    //Create the file empty to write to it, an existing file is truncated
+    fn create_file(&self, path: &Path) -> io::Result<Box<dyn WritableFile>>;
+    //Open the file to append to its end, it is created when it is missing
+    fn append_file(&self, path: &Path) -> io::Result<Box<dyn WritableFile>>;
+    //Open the file to read it from the start
+    fn open_file(&self, path: &Path) -> io::Result<Box<dyn Read + Send>>;
+    //Open the file to read it at any offset
+    fn open_random_access(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>>;
+    fn exists(&self, path: &Path) -> bool;
+    fn file_size(&self, path: &Path) -> io::Result<u64>;
+    //Paths of the files and directories in dir, in no particular order
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    //Create dir, it must not exist yet
+    fn create_dir(&self, dir: &Path) -> io::Result<()>;
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()>;
+    //Move the file to to, replacing what was there
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    //Make to the same file as from without copying it where the Env can, otherwise copy it
+    fn link_file(&self, from: &Path, to: &Path) -> io::Result<()>;
+    //Make the entries created, renamed or removed in dir durable
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+    //Lock the file, created when it is missing, until the returned lock is dropped
+    //Fails with io::ErrorKind::WouldBlock while someone else holds it
+    fn lock_file(&self, path: &Path) -> io::Result<Box<dyn FileLock>>;
+}
+
+pub trait WritableFile: Write + Send {
+    //Flush and make what was written durable
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+pub trait RandomAccessFile: Send + Sync {
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()>;
+    fn size(&self) -> io::Result<u64>;
+}
+
+//Held lock on a file, dropping it releases the lock
+pub trait FileLock: Send + Sync {}
+
+//Env the Database uses when Options::env is not set
+pub fn default_env() -> Arc<dyn Env> {
+    Arc::new(PosixEnv)
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PosixEnv;
+
+impl Env for PosixEnv {
+    fn create_file(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let file = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn append_file(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn open_file(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_random_access(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        Ok(path.metadata()?.len())
+    }
+
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            paths.push(entry?.path());
+        }
+        Ok(paths)
+    }
+
+    fn create_dir(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::remove_dir_all(dir)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn link_file(&self, from: &Path, to: &Path) -> io::Result<()> {
+        //A hard link cannot cross file systems, only then the file is copied there instead
+        //Any other failure, like to already existing, is returned as it is
+        match fs::hard_link(from, to) {
+            Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+                let mut copy = OpenOptions::new().write(true).create_new(true).open(to)?;
+                io::copy(&mut File::open(from)?, &mut copy)?;
+                copy.sync_all()
+            }
+            result => result,
+        }
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        File::open(dir)?.sync_all()
+    }
+
+    fn lock_file(&self, path: &Path) -> io::Result<Box<dyn FileLock>> {
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Box::new(PosixFileLock { file })),
+            Err(TryLockError::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TryLockError::Error(err)) => Err(err),
+        }
+    }
+}
+
+impl WritableFile for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl RandomAccessFile for File {
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        FileExt::read_exact_at(self, buffer, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+//An advisory lock (flock), the OS drops it when the process dies so a crash never leaves it stuck
+struct PosixFileLock {
+    file: File,
+}
+
+impl FileLock for PosixFileLock {}
+
+impl Drop for PosixFileLock {
+    fn drop(&mut self) {
+        //Closing the file releases the lock as well, unlocking first makes it not depend on that
+        let _ = self.file.unlock();
+    }
+}
+
+//Files kept in memory, clones share the same files
+#[derive(Clone, Default)]
+pub struct MemEnv {
+    fs: Arc<Mutex<MemFs>>,
+}
+
+#[derive(Default)]
+struct MemFs {
+    //Linked files share the contents
+    files: BTreeMap<PathBuf, Arc<Mutex<Vec<u8>>>>,
+    dirs: BTreeSet<PathBuf>,
+    locked: BTreeSet<PathBuf>,
+}
+
+impl MemEnv {
+    pub fn new() -> MemEnv {
+        MemEnv::default()
+    }
+
+    fn fs(&self) -> std::sync::MutexGuard<'_, MemFs> {
+        self.fs.lock().unwrap()
+    }
+}
+
+impl MemFs {
+    fn file(&self, path: &Path) -> io::Result<Arc<Mutex<Vec<u8>>>> {
+        self.files.get(path).cloned().ok_or_else(|| not_found(path))
+    }
+
+    //Files can only be created in a directory that exists, the current directory always does
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() && !self.dirs.contains(parent) => Err(not_found(parent)),
+            _ => Ok(()),
+        }
+    }
+
+    fn create(&mut self, path: PathBuf, truncate: bool) -> io::Result<Arc<Mutex<Vec<u8>>>> {
+        self.check_parent(&path)?;
+        if self.dirs.contains(&path) {
+            return Err(io::Error::new(io::ErrorKind::IsADirectory, path.display().to_string()));
+        }
+        let file = self.files.entry(path).or_default().clone();
+        if truncate {
+            file.lock().unwrap().clear();
+        }
+        Ok(file)
+    }
+}
+
+impl Env for MemEnv {
+    fn create_file(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let data = self.fs().create(normalize(path), true)?;
+        Ok(Box::new(MemFile { data, position: 0 }))
+    }
+
+    fn append_file(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let data = self.fs().create(normalize(path), false)?;
+        Ok(Box::new(MemFile { data, position: 0 }))
+    }
+
+    fn open_file(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let data = self.fs().file(&normalize(path))?;
+        Ok(Box::new(MemFile { data, position: 0 }))
+    }
+
+    fn open_random_access(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        let data = self.fs().file(&normalize(path))?;
+        Ok(Box::new(MemFile { data, position: 0 }))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let path = normalize(path);
+        let fs = self.fs();
+        fs.files.contains_key(&path) || fs.dirs.contains(&path) || path.as_os_str().is_empty()
+    }
+
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        let data = self.fs().file(&normalize(path))?;
+        let len = data.lock().unwrap().len() as u64;
+        Ok(len)
+    }
+
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let dir = normalize(dir);
+        let fs = self.fs();
+        if !dir.as_os_str().is_empty() && !fs.dirs.contains(&dir) {
+            return Err(not_found(&dir));
+        }
+        let in_dir = |path: &&PathBuf| path.parent() == Some(dir.as_path());
+        Ok(fs.files.keys().chain(fs.dirs.iter()).filter(in_dir).cloned().collect())
+    }
+
+    fn create_dir(&self, dir: &Path) -> io::Result<()> {
+        let dir = normalize(dir);
+        let mut fs = self.fs();
+        fs.check_parent(&dir)?;
+        if fs.dirs.contains(&dir) || fs.files.contains_key(&dir) || dir.as_os_str().is_empty() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, dir.display().to_string()));
+        }
+        fs.dirs.insert(dir);
+        Ok(())
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let dir = normalize(dir);
+        let mut fs = self.fs();
+        for ancestor in dir.ancestors().filter(|a| !a.as_os_str().is_empty()) {
+            if fs.files.contains_key(ancestor) {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, ancestor.display().to_string()));
+            }
+            fs.dirs.insert(ancestor.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        self.fs().files.remove(&path).map(|_| ()).ok_or_else(|| not_found(&path))
+    }
+
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let dir = normalize(dir);
+        let mut fs = self.fs();
+        if !fs.dirs.remove(&dir) {
+            return Err(not_found(&dir));
+        }
+        fs.files.retain(|path, _| !path.starts_with(&dir));
+        fs.dirs.retain(|path| !path.starts_with(&dir));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (normalize(from), normalize(to));
+        let mut fs = self.fs();
+        fs.check_parent(&to)?;
+        let data = fs.files.remove(&from).ok_or_else(|| not_found(&from))?;
+        fs.files.insert(to, data);
+        Ok(())
+    }
+
+    fn link_file(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (normalize(from), normalize(to));
+        let mut fs = self.fs();
+        fs.check_parent(&to)?;
+        let data = fs.file(&from)?;
+        if fs.files.contains_key(&to) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, to.display().to_string()));
+        }
+        fs.files.insert(to, data);
+        Ok(())
+    }
+
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn lock_file(&self, path: &Path) -> io::Result<Box<dyn FileLock>> {
+        let path = normalize(path);
+        let mut fs = self.fs();
+        fs.create(path.clone(), false)?;
+        if !fs.locked.insert(path.clone()) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(Box::new(MemFileLock { fs: self.fs.clone(), path }))
+    }
+}
+
+struct MemFile {
+    data: Arc<Mutex<Vec<u8>>>,
+    //Where the next read starts, writes always go to the end
+    position: usize,
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.data.lock().unwrap().extend_from_slice(buffer);
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WritableFile for MemFile {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.lock().unwrap();
+        let available = data.get(self.position..).unwrap_or_default();
+        let len = available.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&available[..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+impl RandomAccessFile for MemFile {
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        let data = self.data.lock().unwrap();
+        let start = offset as usize;
+        let bytes = data
+            .get(start..start + buffer.len())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data.lock().unwrap().len() as u64)
+    }
+}
+
+struct MemFileLock {
+    fs: Arc<Mutex<MemFs>>,
+    path: PathBuf,
+}
+
+impl FileLock for MemFileLock {}
+
+impl Drop for MemFileLock {
+    fn drop(&mut self) {
+        self.fs.lock().unwrap().locked.remove(&self.path);
+    }
+}
+
+//"./db/" and "db" are the same directory
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    path.components().filter(|c| !matches!(c, Component::CurDir)).collect()
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, path.display().to_string())
+}
+
+//Directory in the system's temp directory for the tests that need PosixEnv, not created yet
+//It is removed with everything in it when the guard is dropped, even when the test panics
+#[cfg(test)]
+pub(crate) struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub(crate) fn new() -> TempDir {
+        use rand::Rng;
+        TempDir(std::env::temp_dir().join(format!("lanadb-{}", rand::thread_rng().gen::<u64>())))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::{Env, MemEnv, PosixEnv, TempDir};
+    use std::io::{ErrorKind, Read, Write};
+    use std::path::Path;
+
+    //The same calls give the same results on both
+    fn exercise(env: &dyn Env, dir: &Path) {
+        env.create_dir_all(&dir.join("sub")).unwrap();
+        assert_eq!(env.create_dir(dir).unwrap_err().kind(), ErrorKind::AlreadyExists);
+
+        let path = dir.join("000001.log");
+        let mut file = env.append_file(&path).unwrap();
+        file.write_all(b"Badri ").unwrap();
+        file.sync().unwrap();
+        drop(file);
+        let mut file = env.append_file(&path).unwrap();
+        file.write_all(b"Krishnan").unwrap();
+        file.flush().unwrap();
+        assert_eq!(env.file_size(&path).unwrap(), 14);
+
+        let mut contents = String::new();
+        env.open_file(&path).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "Badri Krishnan");
+        let mut buffer = [0u8; 8];
+        let file = env.open_random_access(&path).unwrap();
+        file.read_exact_at(&mut buffer, 6).unwrap();
+        assert_eq!(&buffer, b"Krishnan");
+        assert!(file.read_exact_at(&mut buffer, 7).is_err());
+
+        env.link_file(&path, &dir.join("linked")).unwrap();
+        assert_eq!(env.link_file(&path, &dir.join("linked")).unwrap_err().kind(), ErrorKind::AlreadyExists);
+        env.rename(&path, &dir.join("renamed")).unwrap();
+        assert!(!env.exists(&path));
+        assert_eq!(env.open_file(&path).err().unwrap().kind(), ErrorKind::NotFound);
+        assert_eq!(env.file_size(&dir.join("linked")).unwrap(), 14);
+        let mut file = env.create_file(&dir.join("renamed")).unwrap();
+        file.write_all(b"Lavanya").unwrap();
+        file.sync().unwrap();
+        assert_eq!(env.file_size(&dir.join("renamed")).unwrap(), 7);
+        env.sync_dir(dir).unwrap();
+
+        let mut listed = env.list_dir(dir).unwrap();
+        listed.sort();
+        let names: Vec<&str> = listed.iter().map(|p| p.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, ["linked", "renamed", "sub"]);
+
+        let lock = env.lock_file(&dir.join("LOCK")).unwrap();
+        assert_eq!(env.lock_file(&dir.join("LOCK")).err().unwrap().kind(), ErrorKind::WouldBlock);
+        drop(lock);
+        drop(env.lock_file(&dir.join("LOCK")).unwrap());
+
+        env.remove_file(&dir.join("linked")).unwrap();
+        assert!(env.remove_file(&dir.join("linked")).is_err());
+        env.remove_dir_all(dir).unwrap();
+        assert!(!env.exists(dir));
+        assert!(env.create_file(&dir.join("gone")).is_err());
+    }
+
+    #[test]
+    fn test_posix_env() {
+        let dir = TempDir::new();
+        exercise(&PosixEnv, dir.path());
+    }
+
+    #[test]
+    fn test_posix_link_across_devices() {
+        use std::os::unix::fs::MetadataExt;
+        //Needs /dev/shm on another file system than the temporary directory
+        let shm = Path::new("/dev/shm");
+        let source = TempDir::new();
+        PosixEnv.create_dir_all(source.path()).unwrap();
+        let device = |path: &Path| path.metadata().map(|m| m.dev()).ok();
+        if device(shm).is_none() || device(shm) == device(source.path()) {
+            return;
+        }
+        let target = TempDir(shm.join(TempDir::new().path().file_name().unwrap()));
+        let target = target.path();
+        PosixEnv.create_dir(target).unwrap();
+
+        let path = source.path().join("000001.sst");
+        let mut file = PosixEnv.create_file(&path).unwrap();
+        file.write_all(b"Badri Krishnan").unwrap();
+        file.sync().unwrap();
+        PosixEnv.link_file(&path, &target.join("000001.sst")).unwrap();
+        let mut contents = String::new();
+        PosixEnv.open_file(&target.join("000001.sst")).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "Badri Krishnan");
+        //The copy does not replace what is already there either
+        let error = PosixEnv.link_file(&path, &target.join("000001.sst")).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn test_mem_env() {
+        let env = MemEnv::new();
+        exercise(&env, Path::new("./db/"));
+        //Nothing reached the disk
+        assert!(!Path::new("./db/").exists());
+    }
+}
@@ -0,0 +1,136 @@
+//Errors returned by the Database
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum Error {
+    //Reading or writing the files of the Database failed
+    Io(io::Error),
+    //Another write changed a key the transaction read after the transaction started
+    Conflict { key: Vec<u8> },
+    //Waited longer than the lock timeout for another transaction to release the key
+    LockTimeout { key: Vec<u8> },
+    //Waiting for the key would close a cycle of transactions waiting on each other
+    Deadlock { key: Vec<u8> },
+    //rollback_to_savepoint was called without a savepoint to go back to
+    NoSavepoint,
+    //Another prepared transaction is already waiting under this name
+    PreparedNameInUse { name: String },
+    //No prepared transaction is waiting under this name
+    UnknownPrepared { name: String },
+    //The transaction was prepared, it can only be committed or rolled back now
+    TransactionPrepared,
+    //merge was called without a merge operator set on the Database
+    NoMergeOperator,
+    //A conditional write found a different value, current is what the key holds now
+    ConditionFailed { current: Option<Vec<u8>> },
+    //No column family by this name exists
+    UnknownColumnFamily { name: String },
+    //create_column_family was called with the name of a column family that already exists
+    ColumnFamilyExists { name: String },
+    //Column family names have to be non-empty and fit on one line of the MANIFEST
+    InvalidColumnFamilyName { name: String },
+    //The default column family is always there and cannot be dropped
+    DropDefaultColumnFamily,
+    //The Database was created with a different comparator than the one it is opened with
+    ComparatorMismatch { expected: String, found: String },
+    //There is no Database in the directory and Options::create_if_missing is off
+    DatabaseNotFound { path: PathBuf },
+    //There already is a Database in the directory and Options::error_if_exists is on
+    DatabaseExists { path: PathBuf },
+    //Another Database holds the LOCK file of the directory, in this process or another one
+    Locked { path: PathBuf },
+    //The Database was opened with open_read_only and cannot be written to
+    ReadOnly,
+    //try_catch_up was called on a Database not opened with open_as_secondary
+    NotSecondary,
+    //The Database was closed with close, it has to be opened again
+    Closed,
+    //An earlier I/O failure stopped the writes until Database::resume succeeds, reads still work
+    WritesStopped { severity: ErrorSeverity, cause: String },
+    //The backup directory holds no backup with this id
+    UnknownBackup { id: u32 },
+    //A file of the backup is missing or its size or checksum is not what was recorded
+    BackupCorrupted { id: u32, file: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+//How much a failed write, flush or compaction left behind
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorSeverity {
+    //A flush or compaction failed, the WAL still holds every write so nothing is lost
+    Soft,
+    //Appending to or syncing the WAL failed, it can end in part of a record and the last writes may not be on disk
+    Hard,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "io error: {}", err),
+            Error::Conflict { key } => {
+                write!(f, "transaction conflict on key {}", String::from_utf8_lossy(key))
+            }
+            Error::LockTimeout { key } => {
+                write!(f, "timed out waiting for the lock on key {}", String::from_utf8_lossy(key))
+            }
+            Error::Deadlock { key } => {
+                write!(f, "deadlock waiting for the lock on key {}", String::from_utf8_lossy(key))
+            }
+            Error::NoSavepoint => write!(f, "no savepoint to roll back to"),
+            Error::PreparedNameInUse { name } => {
+                write!(f, "a transaction is already prepared under the name {}", name)
+            }
+            Error::UnknownPrepared { name } => {
+                write!(f, "no transaction is prepared under the name {}", name)
+            }
+            Error::NoMergeOperator => write!(f, "no merge operator is set"),
+            Error::ConditionFailed { .. } => write!(f, "conditional write found a different value"),
+            Error::UnknownColumnFamily { name } => write!(f, "no column family named {}", name),
+            Error::ColumnFamilyExists { name } => write!(f, "column family {} already exists", name),
+            Error::InvalidColumnFamilyName { name } => write!(f, "invalid column family name {:?}", name),
+            Error::DropDefaultColumnFamily => write!(f, "the default column family cannot be dropped"),
+            Error::ComparatorMismatch { expected, found } => {
+                write!(f, "database was created with comparator {} but opened with {}", expected, found)
+            }
+            Error::DatabaseNotFound { path } => write!(f, "no database in {}", path.display()),
+            Error::DatabaseExists { path } => write!(f, "a database already exists in {}", path.display()),
+            Error::ReadOnly => write!(f, "database was opened read-only"),
+            Error::NotSecondary => write!(f, "only a secondary database can catch up"),
+            Error::Closed => write!(f, "database is closed"),
+            Error::WritesStopped { severity, cause } => {
+                let severity = match severity {
+                    ErrorSeverity::Soft => "soft",
+                    ErrorSeverity::Hard => "hard",
+                };
+                write!(f, "writes are stopped by a {} error until resume: {}", severity, cause)
+            }
+            Error::UnknownBackup { id } => write!(f, "no backup with id {}", id),
+            Error::BackupCorrupted { id, file } => write!(f, "file {} of backup {} is corrupted", file, id),
+            Error::Locked { path } => {
+                write!(f, "database in {} is already locked by another open Database", path.display())
+            }
+            Error::TransactionPrepared => {
+                write!(f, "transaction is prepared, it can only be committed or rolled back")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
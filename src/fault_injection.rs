@@ -0,0 +1,743 @@
+//Fault Injection - an Env that fails, crashes and loses data on purpose
+
+/*
+FaultInjectionEnv wraps another Env and passes every call on to it while keeping track of what a power loss
+would take away: the bytes written to a file since it was last synced and the changes to a directory since it
+was last synced. drop_unsynced_data takes exactly that away, so a test can crash a Database at any point and
+check that what was acknowledged as synced is still there when it is opened again.
+
+Every call on the Env and every write and sync on a file it created is an operation. fail_operation makes
+one chosen operation return an I/O error, crash_after_syncs makes every operation fail once a number of
+syncs went through, as if the process died right after the last of them. truncate and corrupt change a
+file behind the Database's back.
+
+A rename is a create of the new name and a remove of the old one. Until the directory is synced a created file
+is gone after drop_unsynced_data and a removed one, or one a rename replaced, is back as it was last synced.
+*/
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::env::{normalize, Env, FileLock, RandomAccessFile, WritableFile};
+
+#[derive(Clone)]
+pub struct FaultInjectionEnv {
+    target: Arc<dyn Env>,
+    state: Arc<Mutex<FaultState>>,
+}
+
+#[derive(Default)]
+struct FaultState {
+    //Length of every file written through the Env as of its last sync
+    synced: BTreeMap<PathBuf, u64>,
+    //Files created since their directory was last synced
+    unsynced_entries: BTreeSet<PathBuf>,
+    //Synced contents of the files removed since their directory was last synced
+    unsynced_removes: BTreeMap<PathBuf, Vec<u8>>,
+    operations: u64,
+    syncs: u64,
+    //Operation that fails
+    fail_at: Option<u64>,
+    //Sync after which every operation fails
+    crash_at: Option<u64>,
+    crashed: bool,
+}
+
+impl FaultState {
+    //Count an operation, Err if it is the one to fail or the process crashed
+    fn operation(&mut self) -> io::Result<()> {
+        if self.crashed {
+            return Err(io::Error::other("injected fault: crashed"));
+        }
+        self.operations += 1;
+        if self.fail_at == Some(self.operations) {
+            self.fail_at = None;
+            return Err(io::Error::other(format!("injected fault: operation {} failed", self.operations)));
+        }
+        Ok(())
+    }
+
+    fn synced(&mut self) {
+        self.syncs += 1;
+        if self.crash_at == Some(self.syncs) {
+            self.crash_at = None;
+            self.crashed = true;
+        }
+    }
+}
+
+impl FaultInjectionEnv {
+    pub fn new(target: Arc<dyn Env>) -> FaultInjectionEnv {
+        FaultInjectionEnv {
+            target,
+            state: Arc::new(Mutex::new(FaultState::default())),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, FaultState> {
+        self.state.lock().unwrap()
+    }
+
+    //Operations so far
+    pub fn operations(&self) -> u64 {
+        self.state().operations
+    }
+
+    //Syncs of files and directories that went through so far
+    pub fn syncs(&self) -> u64 {
+        self.state().syncs
+    }
+
+    //Make the nth operation from now return an I/O error, the ones after it go through again
+    pub fn fail_operation(&self, n: u64) {
+        let mut state = self.state();
+        state.fail_at = Some(state.operations + n);
+    }
+
+    //Once n more syncs went through every operation fails until drop_unsynced_data
+    pub fn crash_after_syncs(&self, n: u64) {
+        let mut state = self.state();
+        state.crash_at = Some(state.syncs + n);
+    }
+
+    //Lose what a power loss would: every file goes back to the length it was last synced at and the files
+    //created since their directory was last synced are gone. Faults still pending are cleared and operations
+    //go through again, so the Database can be opened again
+    pub fn drop_unsynced_data(&self) -> io::Result<()> {
+        let mut state = self.state();
+        for path in std::mem::take(&mut state.unsynced_entries) {
+            state.synced.remove(&path);
+            self.target.remove_file(&path)?;
+        }
+        for (path, data) in std::mem::take(&mut state.unsynced_removes) {
+            let mut file = self.target.create_file(&path)?;
+            file.write_all(&data)?;
+            file.sync()?;
+            state.synced.insert(path, data.len() as u64);
+        }
+        for (path, len) in state.synced.iter() {
+            if self.target.file_size(path).is_ok_and(|size| size > *len) {
+                self.rewrite(path, |data| data.truncate(*len as usize))?;
+            }
+        }
+        state.fail_at = None;
+        state.crash_at = None;
+        state.crashed = false;
+        Ok(())
+    }
+
+    //Cut the file off after len bytes
+    pub fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        self.rewrite(path, |data| data.truncate(len as usize))?;
+        if let Some(synced) = self.state().synced.get_mut(&normalize(path)) {
+            *synced = (*synced).min(len);
+        }
+        Ok(())
+    }
+
+    //Flip every bit of len bytes of the file starting at offset, bytes past its end are left alone
+    pub fn corrupt(&self, path: &Path, offset: u64, len: usize) -> io::Result<()> {
+        self.rewrite(path, |data| {
+            for byte in data.iter_mut().skip(offset as usize).take(len) {
+                *byte ^= 0xff;
+            }
+        })
+    }
+
+    //Change the contents of a file directly on the target, none of it counts as an operation
+    fn rewrite(&self, path: &Path, change: impl FnOnce(&mut Vec<u8>)) -> io::Result<()> {
+        let mut data = Vec::new();
+        self.target.open_file(path)?.read_to_end(&mut data)?;
+        change(&mut data);
+        let mut file = self.target.create_file(path)?;
+        file.write_all(&data)?;
+        file.sync()
+    }
+
+    //Keep what a power loss would bring back of a file that is about to be removed or replaced
+    //A file created since its directory was last synced would not come back
+    fn removing(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        if state.unsynced_entries.remove(path) || state.unsynced_removes.contains_key(path) || !self.target.exists(path) {
+            state.synced.remove(path);
+            return Ok(());
+        }
+        let mut data = Vec::new();
+        self.target.open_file(path)?.read_to_end(&mut data)?;
+        if let Some(synced) = state.synced.remove(path) {
+            data.truncate(synced as usize);
+        }
+        state.unsynced_removes.insert(path.to_path_buf(), data);
+        Ok(())
+    }
+
+    //Open a file for writing, with start bytes of it already synced
+    fn writable(&self, path: &Path, open: impl FnOnce() -> io::Result<Box<dyn WritableFile>>, start: u64) -> io::Result<Box<dyn WritableFile>> {
+        self.state().operation()?;
+        let created = !self.target.exists(path);
+        let file = open()?;
+        let path = normalize(path);
+        let mut state = self.state();
+        if created {
+            state.unsynced_entries.insert(path.clone());
+        }
+        state.synced.insert(path.clone(), start);
+        Ok(Box::new(FaultFile { env: self.clone(), path, file }))
+    }
+}
+
+impl Env for FaultInjectionEnv {
+    fn create_file(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        self.writable(path, || self.target.create_file(path), 0)
+    }
+
+    fn append_file(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let synced = match self.state().synced.get(&normalize(path)) {
+            Some(synced) => *synced,
+            None => self.target.file_size(path).unwrap_or(0),
+        };
+        self.writable(path, || self.target.append_file(path), synced)
+    }
+
+    fn open_file(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        self.state().operation()?;
+        self.target.open_file(path)
+    }
+
+    fn open_random_access(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        self.state().operation()?;
+        self.target.open_random_access(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.target.exists(path)
+    }
+
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        self.state().operation()?;
+        self.target.file_size(path)
+    }
+
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.state().operation()?;
+        self.target.list_dir(dir)
+    }
+
+    fn create_dir(&self, dir: &Path) -> io::Result<()> {
+        self.state().operation()?;
+        self.target.create_dir(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.state().operation()?;
+        self.target.create_dir_all(dir)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.state().operation()?;
+        if !self.target.exists(path) {
+            return self.target.remove_file(path);
+        }
+        self.removing(&normalize(path))?;
+        self.target.remove_file(path)
+    }
+
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.state().operation()?;
+        self.target.remove_dir_all(dir)?;
+        let dir = normalize(dir);
+        let mut state = self.state();
+        state.synced.retain(|path, _| !path.starts_with(&dir));
+        state.unsynced_entries.retain(|path| !path.starts_with(&dir));
+        state.unsynced_removes.retain(|path, _| !path.starts_with(&dir));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.state().operation()?;
+        if !self.target.exists(from) {
+            return self.target.rename(from, to);
+        }
+        let (from_path, to_path) = (normalize(from), normalize(to));
+        //The file keeps what it had synced under its new name
+        let synced = self.state().synced.get(&from_path).copied();
+        self.removing(&to_path)?;
+        self.removing(&from_path)?;
+        self.target.rename(from, to)?;
+        let mut state = self.state();
+        if let Some(synced) = synced {
+            state.synced.insert(to_path.clone(), synced);
+        }
+        state.unsynced_entries.insert(to_path);
+        Ok(())
+    }
+
+    fn link_file(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.state().operation()?;
+        self.target.link_file(from, to)?;
+        let to = normalize(to);
+        let mut state = self.state();
+        let synced = state.synced.get(&normalize(from)).copied();
+        if let Some(synced) = synced {
+            state.synced.insert(to.clone(), synced);
+        }
+        state.unsynced_entries.insert(to);
+        Ok(())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.state().operation()?;
+        self.target.sync_dir(dir)?;
+        let dir = normalize(dir);
+        let mut state = self.state();
+        state.unsynced_entries.retain(|path| path.parent() != Some(dir.as_path()));
+        state.unsynced_removes.retain(|path, _| path.parent() != Some(dir.as_path()));
+        state.synced();
+        Ok(())
+    }
+
+    fn lock_file(&self, path: &Path) -> io::Result<Box<dyn FileLock>> {
+        self.state().operation()?;
+        self.target.lock_file(path)
+    }
+}
+
+struct FaultFile {
+    env: FaultInjectionEnv,
+    path: PathBuf,
+    file: Box<dyn WritableFile>,
+}
+
+impl Write for FaultFile {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.env.state().operation()?;
+        self.file.write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl WritableFile for FaultFile {
+    fn sync(&mut self) -> io::Result<()> {
+        self.env.state().operation()?;
+        self.file.sync()?;
+        let size = self.env.target.file_size(&self.path)?;
+        let mut state = self.env.state();
+        state.synced.insert(self.path.clone(), size);
+        state.synced();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::Database;
+    use crate::env::{Env, MemEnv};
+    use crate::error::Error;
+    use crate::fault_injection::FaultInjectionEnv;
+    use crate::clock::{Clock, ManualClock};
+    use crate::merge_operator::U64AddOperator;
+    use crate::options::{Options, WriteOptions};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeMap;
+    use std::io::{Read, Write};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn contents(env: &dyn Env, path: &Path) -> Vec<u8> {
+        let mut data = Vec::new();
+        env.open_file(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    fn options(env: &Arc<FaultInjectionEnv>) -> Options {
+        Options::new().env(env.clone()).write_buffer_size(512)
+    }
+
+    fn wal_path(env: &dyn Env) -> PathBuf {
+        let wals: Vec<PathBuf> = env.list_dir(Path::new("db")).unwrap().into_iter().filter(|p| p.extension().is_some_and(|e| e == "wal")).collect();
+        assert_eq!(wals.len(), 1);
+        wals[0].clone()
+    }
+
+    #[test]
+    fn test_drop_unsynced_data() {
+        let env = FaultInjectionEnv::new(Arc::new(MemEnv::new()));
+        env.create_dir(Path::new("db")).unwrap();
+        let mut kept = env.create_file(Path::new("db/kept")).unwrap();
+        env.sync_dir(Path::new("db")).unwrap();
+        kept.write_all(b"synced").unwrap();
+        kept.sync().unwrap();
+        kept.write_all(b" and lost").unwrap();
+        let mut appended = env.append_file(Path::new("db/kept")).unwrap();
+        appended.write_all(b" again").unwrap();
+        //Synced, but its directory never was
+        let mut lost = env.create_file(Path::new("db/lost")).unwrap();
+        lost.write_all(b"synced").unwrap();
+        lost.sync().unwrap();
+        assert_eq!(env.syncs(), 3);
+
+        env.drop_unsynced_data().unwrap();
+        assert_eq!(contents(&env, Path::new("db/kept")), b"synced");
+        assert!(!env.exists(Path::new("db/lost")));
+    }
+
+    #[test]
+    fn test_drop_unsynced_directory_changes() {
+        let env = FaultInjectionEnv::new(Arc::new(MemEnv::new()));
+        env.create_dir(Path::new("db")).unwrap();
+        for name in ["db/removed", "db/renamed", "db/replaced"] {
+            let mut file = env.create_file(Path::new(name)).unwrap();
+            file.write_all(name.as_bytes()).unwrap();
+            file.sync().unwrap();
+        }
+        env.sync_dir(Path::new("db")).unwrap();
+
+        //None of it reaches the directory before it is synced
+        env.remove_file(Path::new("db/removed")).unwrap();
+        env.rename(Path::new("db/renamed"), Path::new("db/moved")).unwrap();
+        env.rename(Path::new("db/moved"), Path::new("db/replaced")).unwrap();
+        env.drop_unsynced_data().unwrap();
+        assert_eq!(contents(&env, Path::new("db/removed")), b"db/removed");
+        assert_eq!(contents(&env, Path::new("db/renamed")), b"db/renamed");
+        assert_eq!(contents(&env, Path::new("db/replaced")), b"db/replaced");
+        assert!(!env.exists(Path::new("db/moved")));
+
+        //Once it is synced it stays
+        env.remove_file(Path::new("db/removed")).unwrap();
+        env.rename(Path::new("db/renamed"), Path::new("db/replaced")).unwrap();
+        env.sync_dir(Path::new("db")).unwrap();
+        env.drop_unsynced_data().unwrap();
+        assert!(!env.exists(Path::new("db/removed")));
+        assert!(!env.exists(Path::new("db/renamed")));
+        assert_eq!(contents(&env, Path::new("db/replaced")), b"db/renamed");
+    }
+
+    #[test]
+    fn test_injected_failures() {
+        let env = FaultInjectionEnv::new(Arc::new(MemEnv::new()));
+        env.create_dir(Path::new("db")).unwrap();
+        let mut file = env.create_file(Path::new("db/file")).unwrap();
+        env.sync_dir(Path::new("db")).unwrap();
+        env.fail_operation(2);
+        file.write_all(b"first").unwrap();
+        assert!(file.write_all(b"second").is_err());
+        file.write_all(b"third").unwrap();
+        assert_eq!(env.operations(), 6);
+
+        //The sync goes through, nothing after it does
+        env.crash_after_syncs(1);
+        file.sync().unwrap();
+        assert!(file.write_all(b"fourth").is_err());
+        assert!(env.list_dir(Path::new("db")).is_err());
+        env.drop_unsynced_data().unwrap();
+        assert_eq!(contents(&env, Path::new("db/file")), b"firstthird");
+
+        env.truncate(Path::new("db/file"), 5).unwrap();
+        env.corrupt(Path::new("db/file"), 4, 10).unwrap();
+        assert_eq!(contents(&env, Path::new("db/file")), [b'f', b'i', b'r', b's', !b't']);
+    }
+
+    #[test]
+    fn test_resume_after_injected_error() {
+        let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+        let db = Database::open("db", &options(&env)).unwrap();
+        db.set(b"Badri", b"Krishnan").unwrap();
+        env.fail_operation(1);
+        assert!(matches!(db.set_opt(b"Lavanya", b"Krishnan", &WriteOptions::new().sync(true)), Err(Error::Io(_))));
+        assert!(matches!(db.set(b"Keerthi", b"Krishnan"), Err(Error::WritesStopped { .. })));
+        db.resume().unwrap();
+        db.set(b"Keerthi", b"Krishnan").unwrap();
+        drop(db);
+
+        let db = Database::open("db", &options(&env)).unwrap();
+        assert_eq!(db.get(b"Badri").unwrap().unwrap().value(), b"Krishnan");
+        assert_eq!(db.get(b"Keerthi").unwrap().unwrap().value(), b"Krishnan");
+    }
+
+    #[test]
+    fn test_corrupted_manifest() {
+        let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+        let db = Database::open("db", &options(&env)).unwrap();
+        db.set(b"Badri", b"Krishnan").unwrap();
+        drop(db);
+
+        env.corrupt(Path::new("db/MANIFEST"), 0, 1).unwrap();
+        assert!(matches!(Database::open("db", &options(&env)), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_truncated_wal() {
+        //Every record that is whole is replayed, the one the WAL ends part way through is dropped
+        let keys: Vec<Vec<u8>> = (0..5).map(|i| format!("key{}", i).into_bytes()).collect();
+        let write = |env: &Arc<FaultInjectionEnv>| {
+            let db = Database::open("db", &options(env)).unwrap();
+            for key in keys.iter() {
+                db.set(key, b"value").unwrap();
+            }
+        };
+        let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+        write(&env);
+        let size = env.file_size(&wal_path(env.as_ref())).unwrap();
+
+        let mut found = Vec::new();
+        for len in 0..=size {
+            let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+            write(&env);
+            env.truncate(&wal_path(env.as_ref()), len).unwrap();
+            let db = Database::open("db", &options(&env)).unwrap();
+            let present: Vec<bool> = keys.iter().map(|key| db.get(key).unwrap().is_some()).collect();
+            let count = present.iter().filter(|present| **present).count();
+            assert!(present[..count].iter().all(|present| *present), "WAL cut at {}: {:?}", len, present);
+            found.push(count);
+        }
+        assert!(found.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(found.first(), Some(&0));
+        assert_eq!(found.last(), Some(&keys.len()));
+    }
+
+    #[test]
+    fn test_corrupted_wal_length() {
+        //A key or value length past the end of the WAL stops the replay at that record, nothing is allocated for it
+        let keys: Vec<Vec<u8>> = (0..5).map(|i| format!("key{}", i).into_bytes()).collect();
+        for field in [0, 9] {
+            let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+            let db = Database::open("db", &options(&env)).unwrap();
+            for key in keys.iter() {
+                db.set(key, b"value").unwrap();
+            }
+            drop(db);
+            let path = wal_path(env.as_ref());
+            let record = env.file_size(&path).unwrap() / keys.len() as u64;
+            env.corrupt(&path, 2 * record + field, 8).unwrap();
+
+            let db = Database::open("db", &options(&env)).unwrap();
+            let present: Vec<bool> = keys.iter().map(|key| db.get(key).unwrap().is_some()).collect();
+            assert_eq!(present, vec![true, true, false, false, false], "length at {} corrupted", field);
+        }
+    }
+
+    #[test]
+    fn test_crash_while_recovering() {
+        //A crash part way through the open that rewrites the WAL must not replay the merges twice
+        let count = |env: &Arc<FaultInjectionEnv>| {
+            let db = Database::open("db", &options(env)).unwrap();
+            db.set_merge_operator(Box::new(U64AddOperator));
+            u64::from_le_bytes(db.get(b"counter").unwrap().unwrap().value().try_into().unwrap())
+        };
+        let merged = || {
+            let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+            let db = Database::open("db", &options(&env)).unwrap();
+            db.set_merge_operator(Box::new(U64AddOperator));
+            for _ in 0..3 {
+                db.merge(b"counter", &1u64.to_le_bytes()).unwrap();
+            }
+            drop(db);
+            env
+        };
+        let env = merged();
+        let before = env.syncs();
+        drop(Database::open("db", &options(&env)).unwrap());
+        let syncs = env.syncs() - before;
+        assert!(syncs > 2);
+
+        for crash_at in 1..=syncs {
+            let env = merged();
+            env.crash_after_syncs(crash_at);
+            let _ = Database::open("db", &options(&env));
+            env.drop_unsynced_data().unwrap();
+            assert_eq!(count(&env), 3, "crashed after sync {} of the open", crash_at);
+        }
+    }
+
+    //A change a workload made to one key
+    #[derive(Clone)]
+    enum Change {
+        //The value and, for a write with a time to live, that it expires at the next clock jump
+        Set(Vec<u8>, bool),
+        Delete,
+        Merge(u64),
+    }
+
+    //Every write a workload made in order, a committed transaction is one write of several keys. Only the
+    //first durable of them were acknowledged as synced. The clock jumps after each write in jumps.
+    struct Workload {
+        clock: Arc<ManualClock>,
+        writes: Vec<Vec<(Vec<u8>, Change)>>,
+        jumps: Vec<usize>,
+        durable: usize,
+    }
+
+    const KEYS: usize = 8;
+    const COUNTERS: usize = 3;
+
+    fn crash_options(env: &Arc<FaultInjectionEnv>, clock: &Arc<ManualClock>) -> Options {
+        options(env).clock(clock.clone())
+    }
+
+    fn open(env: &Arc<FaultInjectionEnv>, clock: &Arc<ManualClock>) -> crate::error::Result<Database> {
+        let db = Database::open("db", &crash_options(env, clock))?;
+        db.set_merge_operator(Box::new(U64AddOperator));
+        Ok(db)
+    }
+
+    //Random sets, synced sets, sets with a time to live, deletes, merges, prepared transactions that are
+    //committed, clock jumps, flushes and compactions until the Database fails
+    //The clock only moves when the workload makes it jump, the same seed makes the same files every time
+    fn run_workload(env: &Arc<FaultInjectionEnv>, seed: u64) -> Workload {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let mut workload = Workload { clock: clock.clone(), writes: Vec::new(), jumps: Vec::new(), durable: 0 };
+        let Ok(db) = open(env, &clock) else {
+            return workload;
+        };
+        for i in 0..40 {
+            let key = format!("key{}", rng.gen_range(0..KEYS)).into_bytes();
+            let counter = format!("counter{}", rng.gen_range(0..COUNTERS)).into_bytes();
+            let value = format!("value{}", i).into_bytes();
+            let mut write = |changes: Vec<(Vec<u8>, Change)>| workload.writes.push(changes);
+            let (result, synced) = match rng.gen_range(0..14) {
+                0..=2 => {
+                    write(vec![(key.clone(), Change::Set(value.clone(), false))]);
+                    (db.set(&key, &value).map(|_| ()), false)
+                }
+                3 => {
+                    write(vec![(key.clone(), Change::Set(value.clone(), false))]);
+                    (db.set_opt(&key, &value, &WriteOptions::new().sync(true)).map(|_| ()), true)
+                }
+                4 => {
+                    write(vec![(key.clone(), Change::Set(value.clone(), true))]);
+                    (db.set_with_ttl(&key, &value, Duration::from_millis(1)).map(|_| ()), false)
+                }
+                5 => {
+                    write(vec![(key.clone(), Change::Delete)]);
+                    (db.delete(&key).map(|_| ()), false)
+                }
+                6..=7 => {
+                    let operand = rng.gen_range(1..100);
+                    write(vec![(counter.clone(), Change::Merge(operand))]);
+                    (db.merge(&counter, &operand.to_le_bytes()).map(|_| ()), false)
+                }
+                8 => {
+                    write(vec![(counter.clone(), Change::Delete)]);
+                    (db.delete(&counter).map(|_| ()), false)
+                }
+                9 => {
+                    let other = format!("key{}", rng.gen_range(0..KEYS)).into_bytes();
+                    let mut transaction = db.begin_pessimistic();
+                    let result = transaction
+                        .set(&key, &value)
+                        .and_then(|_| transaction.delete(&other))
+                        .and_then(|_| transaction.prepare(&format!("transaction{}", i)));
+                    //The write set is only applied by the commit, whatever else happens to the keys in between
+                    let mut changes = vec![(other.clone(), Change::Delete), (key.clone(), Change::Set(value.clone(), false))];
+                    changes.dedup_by(|a, b| a.0 == b.0);
+                    write(changes);
+                    (result.and_then(|_| transaction.commit()), false)
+                }
+                10 => {
+                    clock.set(clock.now_micros() + 1_000_000);
+                    workload.jumps.push(workload.writes.len());
+                    (Ok(()), false)
+                }
+                11..=12 => (db.flush(), true),
+                _ => (db.compact(), false),
+            };
+            if result.is_err() {
+                break;
+            }
+            if synced {
+                workload.durable = workload.writes.len();
+            }
+        }
+        let _ = db.close();
+        workload
+    }
+
+    //Value of every key the workload writes to, as the Database reads it now
+    fn read_all(env: &Arc<FaultInjectionEnv>, clock: &Arc<ManualClock>) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let db = open(env, clock).unwrap();
+        let keys = (0..KEYS).map(|i| format!("key{}", i)).chain((0..COUNTERS).map(|i| format!("counter{}", i)));
+        let mut found = BTreeMap::new();
+        for key in keys.map(String::into_bytes) {
+            if let Some(record) = db.get(&key).unwrap() {
+                found.insert(key, record.value().to_vec());
+            }
+        }
+        found
+    }
+
+    //What the Database should read after the first n writes of the workload
+    fn expected(workload: &Workload, n: usize) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let mut model = BTreeMap::new();
+        for (i, write) in workload.writes[..n].iter().enumerate() {
+            let expired = workload.jumps.iter().any(|jump| *jump > i);
+            for (key, change) in write {
+                match change {
+                    Change::Set(_, true) if expired => model.remove(key),
+                    Change::Set(value, _) => model.insert(key.clone(), value.clone()),
+                    Change::Delete => model.remove(key),
+                    Change::Merge(operand) => {
+                        let total = model.get(key).map_or(0, |value: &Vec<u8>| u64::from_le_bytes(value[..].try_into().unwrap()));
+                        model.insert(key.clone(), (total + operand).to_le_bytes().to_vec())
+                    }
+                };
+            }
+        }
+        model
+    }
+
+    //The Database holds exactly what it held after some of the writes, never fewer than the synced ones
+    fn check_recovered(env: &Arc<FaultInjectionEnv>, workload: &Workload, context: &str) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let found = read_all(env, &workload.clock);
+        let matched = (workload.durable..=workload.writes.len()).any(|n| expected(workload, n) == found);
+        assert!(matched, "{}: recovered {:?} after {} synced of {} writes", context, found, workload.durable, workload.writes.len());
+        found
+    }
+
+    #[test]
+    fn test_crash_at_every_sync_point() {
+        for seed in 0..5 {
+            let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+            let workload = run_workload(&env, seed);
+            let syncs = env.syncs();
+            assert!(syncs > 10);
+            let found = check_recovered(&env, &workload, &format!("seed {} without a crash", seed));
+            assert_eq!(found, expected(&workload, workload.writes.len()));
+
+            for crash_at in 1..=syncs {
+                let crashed = || {
+                    let env = Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new())));
+                    env.crash_after_syncs(crash_at);
+                    let workload = run_workload(&env, seed);
+                    env.drop_unsynced_data().unwrap();
+                    (env, workload)
+                };
+                let (env, workload) = crashed();
+                let context = format!("seed {} crashed after sync {}", seed, crash_at);
+                let recovered = check_recovered(&env, &workload, &context);
+
+                //Crash again at every sync of the open that recovers it, the next open reads the same
+                for open_crash_at in 1.. {
+                    let (env, workload) = crashed();
+                    let before = env.syncs();
+                    env.crash_after_syncs(open_crash_at);
+                    drop(open(&env, &workload.clock));
+                    let crashed_open = env.syncs() - before == open_crash_at;
+                    env.drop_unsynced_data().unwrap();
+                    let found = read_all(&env, &workload.clock);
+                    assert_eq!(found, recovered, "{}, then after sync {} of the open", context, open_crash_at);
+                    if !crashed_open {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -2,5 +2,32 @@ mod mem_table;
 mod wal;
 mod wal_iterator;
 mod utils;
-mod database;
+pub mod database;
+pub mod prefix_extractor;
+pub mod snapshot;
+pub mod error;
+pub mod write_batch;
+pub mod optimistic_transaction;
+mod lock_manager;
+pub mod pessimistic_transaction;
+mod ttl_sweeper;
+pub mod merge_operator;
+mod sstable;
+mod manifest;
+pub mod column_family;
+mod compaction;
+pub mod comparator;
+pub mod compression;
+mod block_cache;
+pub mod options;
+mod dir_lock;
+pub mod backup;
+pub mod env;
+pub mod fault_injection;
+pub mod clock;
+pub mod scheduler;
+pub mod simulation;
+#[cfg(test)]
+mod model;
 
+pub use mem_table::HistoryRetention;
@@ -0,0 +1,161 @@
+//Lock Manager - exclusive row locks for pessimistic transactions
+
+/*
+Every lock is exclusive and owned by one transaction id. A transaction that wants a key someone
+else holds waits until the key is released or its lock timeout runs out.
+
+While waiting, the transaction is added to the wait-for graph as an edge waiter -> owner. Each waiter
+waits on exactly one owner, so following the edges from the owner is enough to find a cycle. If the
+walk comes back to the waiter, waiting would never end and the lock fails right away with a Deadlock.
+*/
+
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+
+#[derive(Default)]
+pub struct LockManager {
+    state: Mutex<LockState>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct LockState {
+    //key -> transaction holding the lock
+    owners: HashMap<Vec<u8>, u64>,
+    //wait-for graph, transaction -> transaction it is waiting on
+    waiting_for: HashMap<u64, u64>,
+}
+
+impl LockState {
+    //Would txn waiting on owner close a cycle in the wait-for graph
+    fn would_deadlock(&self, txn: u64, owner: u64) -> bool {
+        let mut current = owner;
+        //The graph has at most one edge per transaction so the walk is bounded by its size
+        for _ in 0..=self.waiting_for.len() {
+            if current == txn {
+                return true;
+            }
+            match self.waiting_for.get(&current) {
+                Some(next) => current = *next,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+impl LockManager {
+    pub fn new() -> LockManager {
+        LockManager::default()
+    }
+
+    //Take the lock on key for txn, waiting up to timeout if another transaction holds it
+    //Taking a lock txn already holds is a no-op
+    pub fn lock(&self, txn: u64, key: &[u8], timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let owner = match state.owners.get(key) {
+                None => {
+                    state.owners.insert(key.to_owned(), txn);
+                    return Ok(());
+                }
+                Some(owner) if *owner == txn => return Ok(()),
+                Some(owner) => *owner,
+            };
+
+            if state.would_deadlock(txn, owner) {
+                return Err(Error::Deadlock { key: key.to_owned() });
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::LockTimeout { key: key.to_owned() });
+            }
+
+            state.waiting_for.insert(txn, owner);
+            state = self.released.wait_timeout(state, deadline - now).unwrap().0;
+            state.waiting_for.remove(&txn);
+        }
+    }
+
+    //Release the locks txn holds on keys and wake up anyone waiting on them
+    pub fn unlock<'a>(&self, txn: u64, keys: impl IntoIterator<Item = &'a Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        for key in keys {
+            if state.owners.get(key) == Some(&txn) {
+                state.owners.remove(key);
+            }
+        }
+        self.released.notify_all();
+    }
+
+    //Transaction holding the lock on key, if any
+    #[cfg(test)]
+    pub fn owner(&self, key: &[u8]) -> Option<u64> {
+        self.state.lock().unwrap().owners.get(key).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::lock_manager::LockManager;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_lock_timeout() {
+        let locks = LockManager::new();
+        locks.lock(1, b"Badri", Duration::from_millis(10)).unwrap();
+        locks.lock(1, b"Badri", Duration::from_millis(10)).unwrap();
+        assert!(matches!(
+            locks.lock(2, b"Badri", Duration::from_millis(10)),
+            Err(Error::LockTimeout { .. })
+        ));
+
+        locks.unlock(1, &[b"Badri".to_vec()]);
+        locks.lock(2, b"Badri", Duration::from_millis(10)).unwrap();
+        assert_eq!(locks.owner(b"Badri"), Some(2));
+    }
+
+    #[test]
+    fn test_waiter_gets_lock_on_release() {
+        let locks = Arc::new(LockManager::new());
+        locks.lock(1, b"Badri", Duration::from_secs(1)).unwrap();
+
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || locks.lock(2, b"Badri", Duration::from_secs(5)))
+        };
+        thread::sleep(Duration::from_millis(20));
+        locks.unlock(1, &[b"Badri".to_vec()]);
+        waiter.join().unwrap().unwrap();
+        assert_eq!(locks.owner(b"Badri"), Some(2));
+    }
+
+    #[test]
+    fn test_deadlock_detected() {
+        let locks = Arc::new(LockManager::new());
+        locks.lock(1, b"Badri", Duration::from_secs(1)).unwrap();
+        locks.lock(2, b"Lavanya", Duration::from_secs(1)).unwrap();
+
+        //1 waits on 2 in the background
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || locks.lock(1, b"Lavanya", Duration::from_secs(5)))
+        };
+        thread::sleep(Duration::from_millis(20));
+
+        //2 waiting on 1 would close the cycle
+        assert!(matches!(
+            locks.lock(2, b"Badri", Duration::from_secs(5)),
+            Err(Error::Deadlock { .. })
+        ));
+        locks.unlock(2, &[b"Lavanya".to_vec()]);
+        waiter.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+//MANIFEST - which column families and tables make up the Database
+
+/*
+A small text file, one entry per line, that is rewritten whole every time a column family is created
+or dropped and every time a flush or compaction changes the set of tables.
+
+lanadb-manifest 1
+comparator lanadb.BytewiseComparator
+next_file_number 7
+next_column_family_id 2
+oldest_wal 1700000000000000
+column_family 0 default
+table 0 5
+column_family 1 users
+table 1 6
+
+Tables are listed per column family oldest first. The new contents go to MANIFEST.tmp first and are
+renamed over MANIFEST, so a crash leaves either the old or the new file and never half of one.
+The comparator line names the order every table was sorted in.
+Column family ids are never reused, records of a dropped family left in the WAL are skipped on replay.
+WALs numbered below oldest_wal were flushed to the tables listed and are not replayed, a crash after a flush
+can leave one behind. Every line above the column families has to be there, a MANIFEST missing one is corrupt.
+*/
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::column_family::{DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_ID};
+use crate::comparator::Comparator;
+use crate::env::Env;
+
+const HEADER: &str = "lanadb-manifest 1";
+
+pub struct Manifest {
+    env: Arc<dyn Env>,
+    path: PathBuf,
+    comparator: String,
+    next_file_number: u64,
+    next_column_family_id: u32,
+    oldest_wal: u128,
+    column_families: Vec<ManifestColumnFamily>,
+}
+
+pub struct ManifestColumnFamily {
+    pub id: u32,
+    pub name: String,
+    //File numbers of the column family's tables, oldest first
+    pub tables: Vec<u64>,
+}
+
+impl Manifest {
+    //Read the MANIFEST in dir, or start one with just the default column family sorted by comparator
+    pub fn load_or_create(env: &Arc<dyn Env>, dir: &Path, comparator: &dyn Comparator) -> io::Result<Manifest> {
+        let path = dir.join("MANIFEST");
+        if !env.exists(&path) {
+            let manifest = Manifest {
+                env: env.clone(),
+                path,
+                comparator: comparator.name().to_owned(),
+                next_file_number: 1,
+                next_column_family_id: DEFAULT_COLUMN_FAMILY_ID + 1,
+                oldest_wal: 0,
+                column_families: vec![ManifestColumnFamily {
+                    id: DEFAULT_COLUMN_FAMILY_ID,
+                    name: DEFAULT_COLUMN_FAMILY.to_owned(),
+                    tables: Vec::new(),
+                }],
+            };
+            manifest.save()?;
+            return Ok(manifest);
+        }
+        Manifest::load(env, dir)
+    }
+
+    //Read the MANIFEST in dir, it has to exist
+    pub fn load(env: &Arc<dyn Env>, dir: &Path) -> io::Result<Manifest> {
+        let path = dir.join("MANIFEST");
+        let mut contents = String::new();
+        env.open_file(&path)?.read_to_string(&mut contents)?;
+        let mut lines = contents.lines();
+        if lines.next() != Some(HEADER) {
+            return Err(corrupt("unknown header"));
+        }
+        let mut comparator = None;
+        let mut next_file_number = None;
+        let mut next_column_family_id = None;
+        let mut oldest_wal = None;
+        let mut column_families: Vec<ManifestColumnFamily> = Vec::new();
+        for line in lines {
+            let (tag, rest) = line.split_once(' ').ok_or_else(|| corrupt(line))?;
+            match tag {
+                "comparator" => comparator = Some(rest.to_owned()),
+                "next_file_number" => next_file_number = Some(parse(rest)?),
+                "next_column_family_id" => next_column_family_id = Some(parse(rest)?),
+                "oldest_wal" => oldest_wal = Some(parse(rest)?),
+                "column_family" => {
+                    let (id, name) = rest.split_once(' ').ok_or_else(|| corrupt(line))?;
+                    column_families.push(ManifestColumnFamily {
+                        id: parse(id)?,
+                        name: name.to_owned(),
+                        tables: Vec::new(),
+                    });
+                }
+                "table" => {
+                    let (id, file_number) = rest.split_once(' ').ok_or_else(|| corrupt(line))?;
+                    let id: u32 = parse(id)?;
+                    let column_family = column_families
+                        .iter_mut()
+                        .find(|cf| cf.id == id)
+                        .ok_or_else(|| corrupt(line))?;
+                    column_family.tables.push(parse(file_number)?);
+                }
+                _ => return Err(corrupt(line)),
+            }
+        }
+        let missing = |line: &str| corrupt(&format!("no {} line", line));
+        Ok(Manifest {
+            env: env.clone(),
+            path,
+            comparator: comparator.ok_or_else(|| missing("comparator"))?,
+            next_file_number: next_file_number.ok_or_else(|| missing("next_file_number"))?,
+            next_column_family_id: next_column_family_id.ok_or_else(|| missing("next_column_family_id"))?,
+            oldest_wal: oldest_wal.ok_or_else(|| missing("oldest_wal"))?,
+            column_families,
+        })
+    }
+
+    //Write the whole MANIFEST to a temporary file and rename it into place
+    pub fn save(&self) -> io::Result<()> {
+        self.write_to(&self.path)
+    }
+
+    //Write the MANIFEST into another directory, this one keeps saving to its own
+    pub fn save_to(&self, dir: &Path) -> io::Result<()> {
+        self.write_to(&dir.join("MANIFEST"))
+    }
+
+    fn write_to(&self, path: &Path) -> io::Result<()> {
+        let mut contents = format!(
+            "{}\ncomparator {}\nnext_file_number {}\nnext_column_family_id {}\noldest_wal {}\n",
+            HEADER, self.comparator, self.next_file_number, self.next_column_family_id, self.oldest_wal
+        );
+        for column_family in self.column_families.iter() {
+            contents += &format!("column_family {} {}\n", column_family.id, column_family.name);
+            for file_number in column_family.tables.iter() {
+                contents += &format!("table {} {}\n", column_family.id, file_number);
+            }
+        }
+        let tmp_path = path.with_extension("tmp");
+        let mut file = self.env.create_file(&tmp_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync()?;
+        self.env.rename(&tmp_path, path)?;
+        self.env.sync_dir(path.parent().unwrap())
+    }
+
+    //Name of the comparator the tables are sorted with
+    pub fn comparator(&self) -> &str {
+        &self.comparator
+    }
+
+    //Take the next number for a table file, it is persisted with the next save
+    pub fn new_file_number(&mut self) -> u64 {
+        let file_number = self.next_file_number;
+        self.next_file_number += 1;
+        file_number
+    }
+
+    //Number of the oldest WAL that holds writes no table does
+    pub fn oldest_wal(&self) -> u128 {
+        self.oldest_wal
+    }
+
+    pub fn set_oldest_wal(&mut self, oldest_wal: u128) {
+        self.oldest_wal = oldest_wal;
+    }
+
+    pub fn column_families(&self) -> &[ManifestColumnFamily] {
+        &self.column_families
+    }
+
+    //Add a column family without tables, returns its id
+    pub fn add_column_family(&mut self, name: &str) -> u32 {
+        let id = self.next_column_family_id;
+        self.next_column_family_id += 1;
+        self.column_families.push(ManifestColumnFamily {
+            id,
+            name: name.to_owned(),
+            tables: Vec::new(),
+        });
+        id
+    }
+
+    pub fn drop_column_family(&mut self, id: u32) {
+        self.column_families.retain(|cf| cf.id != id);
+    }
+
+    pub fn set_tables(&mut self, id: u32, tables: Vec<u64>) {
+        if let Some(column_family) = self.column_families.iter_mut().find(|cf| cf.id == id) {
+            column_family.tables = tables;
+        }
+    }
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> io::Result<T> {
+    value.parse().map_err(|_| corrupt(value))
+}
+
+fn corrupt(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("corrupt MANIFEST entry: {}", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::comparator::{BytewiseComparator, NumericComparator};
+    use crate::env::{Env, MemEnv};
+    use crate::manifest::Manifest;
+    use std::io::{ErrorKind, Read, Write};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_save_and_load() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let dir = Path::new("db");
+        env.create_dir(dir).unwrap();
+
+        let mut manifest = Manifest::load_or_create(&env, dir, &NumericComparator).unwrap();
+        assert_eq!(manifest.column_families().len(), 1);
+        let users = manifest.add_column_family("users and groups");
+        let orders = manifest.add_column_family("orders");
+        let first = manifest.new_file_number();
+        let second = manifest.new_file_number();
+        manifest.set_tables(0, vec![first]);
+        manifest.set_tables(users, vec![second]);
+        manifest.drop_column_family(orders);
+        manifest.set_oldest_wal(1700000000000000);
+        manifest.save().unwrap();
+
+        //The comparator it was created with wins over the one it is loaded with
+        let mut manifest = Manifest::load_or_create(&env, dir, &BytewiseComparator).unwrap();
+        assert_eq!(manifest.comparator(), "lanadb.NumericComparator");
+        let names: Vec<&str> = manifest.column_families().iter().map(|cf| cf.name.as_str()).collect();
+        assert_eq!(names, vec!["default", "users and groups"]);
+        assert_eq!(manifest.column_families()[0].tables, vec![first]);
+        assert_eq!(manifest.column_families()[1].tables, vec![second]);
+        assert_eq!(manifest.oldest_wal(), 1700000000000000);
+        //Neither the file numbers nor the dropped id are handed out again
+        assert_eq!(manifest.new_file_number(), second + 1);
+        assert!(manifest.add_column_family("orders") > orders);
+    }
+
+    #[test]
+    fn test_missing_line() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let dir = Path::new("db");
+        env.create_dir(dir).unwrap();
+        Manifest::load_or_create(&env, dir, &BytewiseComparator).unwrap();
+        let mut contents = String::new();
+        env.open_file(&dir.join("MANIFEST")).unwrap().read_to_string(&mut contents).unwrap();
+
+        //Every line above the column families has to be there, none of them has a default
+        for line in ["comparator", "next_file_number", "next_column_family_id", "oldest_wal"] {
+            let kept: Vec<&str> = contents.lines().filter(|l| !l.starts_with(line)).collect();
+            env.create_file(&dir.join("MANIFEST")).unwrap().write_all(kept.join("\n").as_bytes()).unwrap();
+            let err = Manifest::load(&env, dir).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert!(err.to_string().contains(line));
+        }
+    }
+}
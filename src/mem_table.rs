@@ -1,92 +1,271 @@
 //This memtable will hold a sorted list of key-value records
 //We will write a duplicate to the WAL in case a failure in lanadb
 //There will be a max capacity to a MemTable at which point we will flust the table to the Disk
-//Entries will be stored in a HashMap 
+//Entries are kept sorted by key and then newest timestamp first, so every version of a key sits together
+//Keys are ordered by the comparator, byte order unless the Database was opened with another one
+//Older versions are kept while a snapshot still needs to read them or the history retention asks for them
+
+use std::cmp::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::comparator::{BytewiseComparator, Comparator};
 
 pub struct MemTable{
     entries: Vec<Record>,
+    comparator: Arc<dyn Comparator>,
     size: usize,
+    snapshots: Vec<u128>,
+    retention: HistoryRetention,
+    latest_timestamp: u128,
+}
+
+//How much history is kept for each key on top of what the live snapshots need
+//With both limits set a version has to be inside both of them to be kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HistoryRetention {
+    //Keep versions that were still the current value at some point inside this window before the newest write
+    pub window: Option<Duration>,
+    //Keep at most this many versions of a key, counting the newest one
+    pub max_versions: Option<usize>,
+}
+
+impl HistoryRetention {
+    //Keep every version, used while replaying the WAL so no history is lost before the retention is known
+    pub fn keep_all() -> HistoryRetention {
+        HistoryRetention {
+            window: None,
+            max_versions: None,
+        }
+    }
 }
 
+//Only the newest version of each key
+impl Default for HistoryRetention {
+    fn default() -> Self {
+        HistoryRetention {
+            window: None,
+            max_versions: Some(1),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Record{
     pub key: Vec<u8>,
     pub value: Option<Vec<u8>>,
     pub timestamp: u128,
-    pub deleted: bool
+    pub deleted: bool,
+    //Time in microseconds the value expires at, None if it never expires
+    pub expires_at: Option<u128>,
+    //The value is a merge operand that has to be combined with the older versions
+    pub merge: bool,
+}
+
+impl Record {
+    //Does this version hold a value that has not expired at the time `at`
+    //A merge operand only holds part of a value so it is never live on its own
+    pub fn is_live(&self, at: u128) -> bool {
+        !self.deleted && !self.merge && self.expires_at.is_none_or(|expires_at| expires_at > at)
+    }
+}
+
+impl Default for MemTable{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MemTable{
     pub fn new() -> MemTable{
+        MemTable::with_comparator(Arc::new(BytewiseComparator))
+    }
+    pub fn with_comparator(comparator: Arc<dyn Comparator>) -> MemTable{
         MemTable{
             entries: Vec::new(),
+            comparator,
             size: 0,
+            snapshots: Vec::new(),
+            retention: HistoryRetention::default(),
+            latest_timestamp: 0,
         }
     }
+    #[cfg(test)]
     pub fn set(&mut self, key:&[u8], value:&[u8], timestamp:u128) {
+        self.set_with_expiry(key, value, timestamp, None);
+    }
+    //Set a value that reads as missing from expires_at on, the expiration takes 16 more bytes
+    pub fn set_with_expiry(&mut self, key:&[u8], value:&[u8], timestamp:u128, expires_at: Option<u128>) {
         let entry = Record {
             key: key.to_owned(),
             value: Some(value.to_owned()),
             timestamp,
-            deleted: false
+            deleted: false,
+            expires_at,
+            merge: false,
         };
-        //Binary search helps us find the index that entry can be stored
-        //this is so we always maintain the sorted list structure for O(log n) look up
-        match self.get_index(key){
-            Ok(idx) => {
-                println!("this is the index for this key in the entries vector: {}",idx);
-                //if there was key-val pair before we are finding the difference in the size of the value 
-                //Then we update the size of Memtable based on the difference and setting the entry
-                if let Some(v) = self.entries[idx].value.as_ref() {
-                    if value.len() < v.len() {
-                      self.size -= v.len() - value.len();
-                    } else {
-                      self.size += value.len() - v.len();
-                    }
-                  }
-                self.entries[idx] = entry;
-            }
-            //Key does not exist so we are adding new entry and update new size for the Memtable
-            Err(idx) => {
-                self.size += key.len() + value.len() + 16 + 1;
-                self.entries.insert(idx,entry);
-            }
-        }
+        self.size += Self::record_size(&entry);
+        self.insert_version(entry);
+    }
+    //Stack a merge operand on top of the key's versions, it is combined when the key is read
+    pub fn merge(&mut self, key:&[u8], operand:&[u8], timestamp:u128) {
+        let entry = Record {
+            key: key.to_owned(),
+            value: Some(operand.to_owned()),
+            timestamp,
+            deleted: false,
+            expires_at: None,
+            merge: true,
+        };
+        self.size += Self::record_size(&entry);
+        self.insert_version(entry);
+    }
+    //Insert a version read back from somewhere else, like a table being compacted
+    pub fn insert(&mut self, record: Record) {
+        self.size += Self::record_size(&record);
+        self.insert_version(record);
     }
     //Delete record from the Memtable
     pub fn delete(&mut self, key: &[u8], timestamp: u128){
         let entry = Record{
             key: key.to_owned(),
             value: None,
-            timestamp: timestamp,
-            deleted: true
+            timestamp,
+            deleted: true,
+            expires_at: None,
+            merge: false,
         };
+        self.size += key.len() + 16 + 1;
+        self.insert_version(entry);
+    }
+    //Newest version of the key, this can be a tombstone
+    #[cfg(test)]
+    pub fn get(&mut self, key: &[u8]) -> Option<&Record>{
+        let idx = self.first_index(key);
+        self.entries.get(idx).filter(|e| self.same_key(&e.key, key))
+    }
 
-        match self.get_index(key) {
-            Ok(idx) => {
-                if let Some(value) = self.entries[idx].value.as_ref(){
-                    self.size -= value.len();
-                }
-                self.entries[idx] = entry;
+    //Newest version of the key written at or before timestamp
+    #[cfg(test)]
+    pub fn get_at(&self, key: &[u8], timestamp: u128) -> Option<&Record>{
+        let idx = self.first_index(key);
+        self.entries[idx..]
+            .iter()
+            .take_while(|e| self.same_key(&e.key, key))
+            .find(|e| e.timestamp <= timestamp)
+    }
+
+    //Newest version of each key, keys have to be sorted by the comparator
+    //Each lookup only searches past where the previous key was found, so the table is walked once
+    pub fn multi_get<'a>(&'a self, sorted_keys: &[&[u8]]) -> Vec<Option<&'a Record>> {
+        let mut start = 0;
+        let mut records = Vec::with_capacity(sorted_keys.len());
+        for key in sorted_keys {
+            start += self.entries[start..].partition_point(|e| self.comparator.compare(&e.key, key) == Ordering::Less);
+            records.push(self.entries.get(start).filter(|e| self.same_key(&e.key, key)));
+        }
+        records
+    }
+
+    //Keys whose newest version is a value that expired at or before now
+    pub fn expired_keys(&self, now: u128) -> Vec<Vec<u8>> {
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        let mut last_key: Option<&[u8]> = None;
+        for entry in self.entries.iter() {
+            //Only the newest version of each key counts
+            if last_key.is_some_and(|key| self.same_key(key, &entry.key)) {
+                continue;
             }
-            Err(idx) => {
-                self.size += key.len() + 16 + 1;
-                self.entries.insert(idx, entry);  
+            last_key = Some(entry.key.as_slice());
+            if entry.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                keys.push(entry.key.clone());
             }
         }
+        keys
     }
-    pub fn get(&mut self, key: &[u8]) -> Option<&Record>{
-        if let Ok(index) = self.get_index(key){
-            return Some(&self.entries[index]);
+
+    //Versions of the key written at or before timestamp, newest first
+    pub fn versions_at<'a>(&'a self, key: &'a [u8], timestamp: u128) -> impl Iterator<Item = &'a Record> + 'a {
+        self.versions(key).filter(move |e| e.timestamp <= timestamp)
+    }
+
+    //Every version of the key that is still kept, newest first, tombstones included
+    pub fn versions<'a>(&'a self, key: &'a [u8]) -> impl Iterator<Item = &'a Record> + 'a {
+        let idx = self.first_index(key);
+        self.entries[idx..].iter().take_while(move |e| self.same_key(&e.key, key))
+    }
+
+    //Versions that fall out of the new retention are dropped on the next write of their key
+    //or right away with prune
+    pub fn set_history_retention(&mut self, retention: HistoryRetention) {
+        self.retention = retention;
+    }
+
+    //Drop every version that neither a snapshot nor the history retention needs
+    pub fn prune(&mut self) {
+        self.prune_all();
+    }
+
+    //Timestamps of the live snapshots, versions one of these can still see are not pruned
+    //When a snapshot goes away the versions only it needed are dropped right away
+    pub fn set_snapshots(&mut self, mut snapshots: Vec<u128>) {
+        snapshots.sort_unstable();
+        if snapshots == self.snapshots {
+            return;
         }
-        else {
-            return None;
+        let released = self.snapshots.iter().any(|s| snapshots.binary_search(s).is_err());
+        self.snapshots = snapshots;
+        if released {
+            self.prune_all();
         }
     }
-     // # of records in the MemTable.
+    //Drop every version written at or before timestamp
+    pub fn drop_through(&mut self, timestamp: u128) {
+        self.entries.retain(|e| e.timestamp > timestamp);
+    }
+
+     // # of records in the MemTable, counting every version that is kept.
     pub fn len(&self) -> usize {
         self.entries.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    //Records whose key starts with prefix, in key order
+    //Binary search to the first key >= prefix and stop at the first key past the prefix
+    //when the comparator keeps prefixes together, otherwise every key is looked at
+    #[cfg(test)]
+    pub fn scan_prefix<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = &'a Record> + 'a {
+        self.scan_prefix_at(prefix, u128::MAX)
+    }
+
+    //Same as scan_prefix but only returns the newest version of each key written at or before timestamp
+    #[cfg(test)]
+    pub fn scan_prefix_at<'a>(&'a self, prefix: &'a [u8], timestamp: u128) -> impl Iterator<Item = &'a Record> + 'a {
+        let mut last_key: Option<&'a [u8]> = None;
+        self.scan_prefix_versions(prefix)
+            .filter(move |e| e.timestamp <= timestamp)
+            .filter(move |e| {
+                if last_key.is_some_and(|key| self.same_key(key, &e.key)) {
+                    return false;
+                }
+                last_key = Some(e.key.as_slice());
+                true
+            })
+    }
+
+    //Every version of every key that starts with prefix, sorted like the table
+    pub fn scan_prefix_versions<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = &'a Record> + 'a {
+        let grouped = self.comparator.groups_prefixes();
+        let start = if grouped { self.first_index(prefix) } else { 0 };
+        self.entries[start..]
+            .iter()
+            .take_while(move |e| !grouped || e.key.starts_with(prefix))
+            .filter(move |e| e.key.starts_with(prefix))
+    }
+
     //all of the records from the MemTable.
     pub fn entries(&self) -> &[Record] {
         &self.entries
@@ -97,16 +276,88 @@ impl MemTable{
         self.size
     }
 
+    //Do the comparator's eyes see one key, it need not be the same bytes
+    fn same_key(&self, a: &[u8], b: &[u8]) -> bool {
+        self.comparator.compare(a, b) == Ordering::Equal
+    }
 
-    fn get_index(&self, key: &[u8]) -> Result<usize, usize> {
-        self.entries.binary_search_by_key(&key, |e| e.key.as_slice())
+    //Index of the newest version of key, or where the key would go if it is not in the table
+    fn first_index(&self, key: &[u8]) -> usize {
+        self.entries.partition_point(|e| self.comparator.compare(&e.key, key) == Ordering::Less)
+    }
+
+    //Binary search helps us find the index that entry can be stored
+    //this is so we always maintain the sorted list structure for O(log n) look up
+    //A write with the same timestamp as an existing version is treated as the newer one
+    fn insert_version(&mut self, entry: Record) {
+        let key_start = self.first_index(&entry.key);
+        let idx = key_start + self.entries[key_start..]
+            .partition_point(|e| self.same_key(&e.key, &entry.key) && e.timestamp > entry.timestamp);
+        self.latest_timestamp = self.latest_timestamp.max(entry.timestamp);
+        self.entries.insert(idx, entry);
+        self.prune_versions(key_start);
+    }
+
+    //Drop the older versions of the key starting at key_start that nobody can read anymore
+    //The newest version is always kept, an older one is kept when a snapshot falls between it and the next newer version
+    //or when the history retention still covers it
+    //Everything under a kept merge operand down to the first set or delete is kept too, the operand needs it to be read
+    fn prune_versions(&mut self, key_start: usize) {
+        let mut idx = key_start + 1;
+        let mut kept = 1;
+        let mut newer_timestamp = self.entries[key_start].timestamp;
+        let mut under_merge = self.entries[key_start].merge;
+        while idx < self.entries.len() && self.same_key(&self.entries[idx].key, &self.entries[key_start].key) {
+            let timestamp = self.entries[idx].timestamp;
+            if under_merge || self.is_visible_to_snapshot(timestamp, newer_timestamp) || self.is_retained(kept, newer_timestamp) {
+                under_merge = self.entries[idx].merge;
+                kept += 1;
+                newer_timestamp = timestamp;
+                idx += 1;
+            } else {
+                let pruned = self.entries.remove(idx);
+                self.size -= Self::record_size(&pruned);
+            }
+        }
+    }
+
+    fn prune_all(&mut self) {
+        let mut key_start = 0;
+        while key_start < self.entries.len() {
+            self.prune_versions(key_start);
+            let key = &self.entries[key_start].key;
+            key_start += self.entries[key_start..].partition_point(|e| self.same_key(&e.key, key));
+        }
+    }
+
+    //Is there a snapshot taken at or after timestamp but before the next newer version was written
+    fn is_visible_to_snapshot(&self, timestamp: u128, newer_timestamp: u128) -> bool {
+        let idx = self.snapshots.partition_point(|s| *s < timestamp);
+        idx < self.snapshots.len() && self.snapshots[idx] < newer_timestamp
+    }
+
+    //Is the version with `kept` newer versions in front of it, replaced at newer_timestamp, inside the history retention
+    fn is_retained(&self, kept: usize, newer_timestamp: u128) -> bool {
+        let within_count = self.retention.max_versions.is_none_or(|max| kept < max);
+        let within_window = self.retention.window
+            .is_none_or(|window| newer_timestamp + window.as_micros() > self.latest_timestamp);
+        within_count && within_window
+    }
+
+    fn record_size(record: &Record) -> usize {
+        let expiry = if record.expires_at.is_some() { 16 } else { 0 };
+        record.key.len() + record.value.as_ref().map_or(0, |v| v.len()) + 16 + 1 + expiry
     }
 
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
-    use crate::mem_table::MemTable;
+    use crate::comparator::{CaseInsensitiveComparator, NumericComparator, ReverseBytewiseComparator};
+    use crate::mem_table::{HistoryRetention, MemTable};
+    use std::sync::Arc;
+    use std::time::Duration;
   
     #[test]
     fn test_mem_table_put_start() {
@@ -249,6 +500,163 @@ mod tests {
         assert_eq!(table.size, 100);
     }
 
+    #[test]
+    fn test_scan_prefix(){
+        let mut table = MemTable::new();
+        table.set(b"acme/users/1", b"Badri", 0);
+        table.set(b"acme/orders/1", b"Car", 10);
+        table.set(b"acme/users/2", b"Lavanya", 20);
+        table.set(b"acmf/users/1", b"Keerthi", 30);
+        table.set(b"acme/users", b"Not a user", 40);
+
+        let keys: Vec<&[u8]> = table.scan_prefix(b"acme/users/").map(|r| r.key.as_slice()).collect();
+        assert_eq!(keys, vec![b"acme/users/1".as_slice(), b"acme/users/2".as_slice()]);
+
+        assert_eq!(table.scan_prefix(b"acme/").count(), 4);
+        assert_eq!(table.scan_prefix(b"zzz").count(), 0);
+        assert_eq!(table.scan_prefix(b"").count(), 5);
+    }
+
+    #[test]
+    fn test_comparator_equality(){
+        let mut table = MemTable::with_comparator(Arc::new(CaseInsensitiveComparator));
+        table.set(b"Badri", b"one", 10);
+        table.set(b"BADRI", b"two", 20);
+        table.set(b"car", b"Tesla", 30);
+        //Keys the comparator calls equal are versions of one key, the older one is pruned
+        assert_eq!(table.len(), 2);
+        let record = table.get(b"badri").unwrap();
+        assert_eq!(record.key, b"BADRI");
+        assert_eq!(record.value.as_ref().unwrap(), b"two");
+        assert_eq!(table.multi_get(&[b"bAdRi", b"CAR"]).iter().flatten().count(), 2);
+        table.delete(b"Car", 40);
+        assert!(table.get(b"car").unwrap().deleted);
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn test_comparator(){
+        let mut table = MemTable::with_comparator(Arc::new(NumericComparator));
+        table.set(b"10", b"ten", 0);
+        table.set(b"9", b"nine", 10);
+        table.set(b"100", b"hundred", 20);
+        table.set(b"10", b"TEN", 30);
+        let keys: Vec<&[u8]> = table.scan_prefix(b"").map(|r| r.key.as_slice()).collect();
+        assert_eq!(keys, vec![b"9".as_slice(), b"10", b"100"]);
+        assert_eq!(table.get(b"10").unwrap().value.as_deref(), Some(b"TEN".as_slice()));
+        //Numbers starting with 1 are not next to each other, the scan still finds them all
+        assert_eq!(table.scan_prefix(b"1").count(), 2);
+
+        let mut table = MemTable::with_comparator(Arc::new(ReverseBytewiseComparator));
+        table.set(b"acme/users/1", b"Badri", 0);
+        table.set(b"acme/users/2", b"Lavanya", 10);
+        table.set(b"acmf/users/1", b"Keerthi", 20);
+        let keys: Vec<&[u8]> = table.scan_prefix(b"acme/").map(|r| r.key.as_slice()).collect();
+        assert_eq!(keys, vec![b"acme/users/2".as_slice(), b"acme/users/1"]);
+    }
+
+    #[test]
+    fn test_versions_kept_for_snapshots(){
+        let mut table = MemTable::new();
+        table.set(b"Badri", b"Badri Krishnan", 10);
+        table.set_snapshots(vec![15]);
+        table.set(b"Badri", b"Part of groomsmen", 20);
+        table.delete(b"Badri", 30);
+
+        //The version at 20 is not visible to the snapshot at 15 so it was dropped
+        assert_eq!(table.len(), 2);
+        assert!(table.get(b"Badri").unwrap().deleted);
+        assert_eq!(table.get_at(b"Badri", 15).unwrap().value.as_ref().unwrap(), b"Badri Krishnan");
+        assert!(table.get_at(b"Badri", 5).is_none());
+        assert_eq!(table.size, 22 + 36);
+
+        table.set_snapshots(vec![]);
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.size, 22);
+    }
+
+    #[test]
+    fn test_history_max_versions(){
+        let mut table = MemTable::new();
+        table.set_history_retention(HistoryRetention { window: None, max_versions: Some(3) });
+        table.set(b"Badri", b"one", 10);
+        table.set(b"Badri", b"two", 20);
+        table.delete(b"Badri", 30);
+        table.set(b"Badri", b"four", 40);
+
+        let timestamps: Vec<u128> = table.versions(b"Badri").map(|r| r.timestamp).collect();
+        assert_eq!(timestamps, vec![40, 30, 20]);
+        assert!(table.get_at(b"Badri", 35).unwrap().deleted);
+        assert_eq!(table.get_at(b"Badri", 25).unwrap().value.as_ref().unwrap(), b"two");
+        assert!(table.get_at(b"Badri", 15).is_none());
+    }
+
+    #[test]
+    fn test_history_window(){
+        let mut table = MemTable::new();
+        table.set_history_retention(HistoryRetention { window: Some(Duration::from_micros(100)), max_versions: None });
+        table.set(b"Badri", b"one", 10);
+        table.set(b"Badri", b"two", 20);
+        table.set(b"Badri", b"three", 150);
+
+        //"two" was current until 150 which is inside the window, "one" was replaced at 20 which is not
+        let timestamps: Vec<u128> = table.versions(b"Badri").map(|r| r.timestamp).collect();
+        assert_eq!(timestamps, vec![150, 20]);
+
+        table.set_history_retention(HistoryRetention::default());
+        table.prune();
+        assert_eq!(table.versions(b"Badri").count(), 1);
+    }
+
+    #[test]
+    fn test_multi_get(){
+        let mut table = MemTable::new();
+        table.set(b"Badri", b"Badri Krishnan", 0);
+        table.set(b"Keerthi", b"Keerthi Krishnan", 10);
+        table.set(b"Lavanya", b"Lavanya Krishnan", 20);
+        table.delete(b"Keerthi", 30);
+
+        let keys: Vec<&[u8]> = vec![b"Aaron", b"Badri", b"Badri", b"Keerthi", b"Lavanya", b"Zed"];
+        let records = table.multi_get(&keys);
+        assert!(records[0].is_none());
+        assert_eq!(records[1].unwrap().timestamp, 0);
+        assert_eq!(records[2].unwrap().timestamp, 0);
+        assert!(records[3].unwrap().deleted);
+        assert_eq!(records[4].unwrap().value.as_ref().unwrap(), b"Lavanya Krishnan");
+        assert!(records[5].is_none());
+    }
+
+    #[test]
+    fn test_set_with_expiry(){
+        let mut table = MemTable::new();
+        table.set_with_expiry(b"Session", b"Badri", 10, Some(100)); // 7 + 5 + 16 + 1 + 16 = 45
+        table.set(b"Car", b"Garage", 20); // 3 + 6 + 16 + 1 = 26
+
+        let record = table.get(b"Session").unwrap();
+        assert!(record.is_live(99));
+        assert!(!record.is_live(100));
+        assert!(table.get(b"Car").unwrap().is_live(u128::MAX));
+        assert_eq!(table.size, 45 + 26);
+    }
+
+    #[test]
+    fn test_merge_operands_keep_base(){
+        let mut table = MemTable::new();
+        table.set(b"Badri", b"a", 10);
+        table.set(b"Badri", b"b", 20);
+        table.merge(b"Badri", b"c", 30);
+        table.merge(b"Badri", b"d", 40);
+
+        //The older set at 10 is not under an operand anymore so it is pruned
+        let timestamps: Vec<u128> = table.versions(b"Badri").map(|r| r.timestamp).collect();
+        assert_eq!(timestamps, vec![40, 30, 20]);
+        assert_eq!(table.versions_at(b"Badri", 35).count(), 2);
+        assert!(!table.get(b"Badri").unwrap().is_live(50));
+
+        table.set(b"Badri", b"e", 50);
+        assert_eq!(table.versions(b"Badri").count(), 1);
+    }
+
     #[test]
     fn test_mem_table_delete_empty() {
         let mut table = MemTable::new();
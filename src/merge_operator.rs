@@ -0,0 +1,132 @@
+//Merge Operator - read-modify-write without the read
+
+/*
+Database::merge logs an operand instead of a new value. Operands stack up as versions of the key
+in the MemTable and nothing is read when they are written. A read walks from the newest version down
+to the first set or delete, then hands the value it found (None for a delete or a missing key) and
+the operands, oldest first, to the merge operator to get the value.
+*/
+
+pub trait MergeOperator: Send + Sync {
+    //Name of the operator
+    fn name(&self) -> &str;
+
+    //Apply operands, oldest first, on top of the existing value
+    //None means the operands could not be merged and the key reads as missing
+    fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>>;
+}
+
+//Values and operands are u64 in little endian, operands are added to the value
+//A missing value counts as 0, additions wrap around
+pub struct U64AddOperator;
+
+impl MergeOperator for U64AddOperator {
+    fn name(&self) -> &str {
+        "lanadb.U64AddOperator"
+    }
+
+    fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>> {
+        let mut total = match existing {
+            Some(value) => u64::from_le_bytes(value.try_into().ok()?),
+            None => 0,
+        };
+        for operand in operands {
+            total = total.wrapping_add(u64::from_le_bytes((*operand).try_into().ok()?));
+        }
+        Some(total.to_le_bytes().to_vec())
+    }
+}
+
+//Operands are appended to the value, with the delimiter between each piece when there is one
+pub struct AppendOperator {
+    delimiter: Option<Vec<u8>>,
+}
+
+impl AppendOperator {
+    pub fn new() -> AppendOperator {
+        AppendOperator { delimiter: None }
+    }
+
+    pub fn with_delimiter(delimiter: &[u8]) -> AppendOperator {
+        AppendOperator {
+            delimiter: Some(delimiter.to_owned()),
+        }
+    }
+}
+
+impl Default for AppendOperator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MergeOperator for AppendOperator {
+    fn name(&self) -> &str {
+        "lanadb.AppendOperator"
+    }
+
+    fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>> {
+        let mut value = existing.map(|v| v.to_vec());
+        for operand in operands {
+            match value.as_mut() {
+                Some(value) => {
+                    if let Some(delimiter) = self.delimiter.as_ref() {
+                        value.extend_from_slice(delimiter);
+                    }
+                    value.extend_from_slice(operand);
+                }
+                None => value = Some(operand.to_vec()),
+            }
+        }
+        value
+    }
+}
+
+//Keeps the largest of the value and the operands, compared as bytes
+//Store numbers big endian so the byte order matches the numeric order
+pub struct MaxOperator;
+
+impl MergeOperator for MaxOperator {
+    fn name(&self) -> &str {
+        "lanadb.MaxOperator"
+    }
+
+    fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>> {
+        existing
+            .into_iter()
+            .chain(operands.iter().copied())
+            .max()
+            .map(|v| v.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merge_operator::{AppendOperator, MaxOperator, MergeOperator, U64AddOperator};
+
+    #[test]
+    fn test_u64_add() {
+        let operator = U64AddOperator;
+        let one = 1u64.to_le_bytes();
+        let two = 2u64.to_le_bytes();
+        let ten = 10u64.to_le_bytes();
+        assert_eq!(operator.full_merge(b"k", None, &[&one, &two]).unwrap(), 3u64.to_le_bytes());
+        assert_eq!(operator.full_merge(b"k", Some(&ten), &[&one]).unwrap(), 11u64.to_le_bytes());
+        assert!(operator.full_merge(b"k", Some(b"bad"), &[&one]).is_none());
+    }
+
+    #[test]
+    fn test_append() {
+        assert_eq!(AppendOperator::new().full_merge(b"k", Some(b"a"), &[b"b", b"c"]).unwrap(), b"abc");
+        let operator = AppendOperator::with_delimiter(b",");
+        assert_eq!(operator.full_merge(b"k", None, &[b"a", b"b"]).unwrap(), b"a,b");
+        assert_eq!(operator.full_merge(b"k", Some(b"a"), &[b"b"]).unwrap(), b"a,b");
+    }
+
+    #[test]
+    fn test_max() {
+        let operator = MaxOperator;
+        assert_eq!(operator.full_merge(b"k", Some(b"b"), &[b"a", b"c", b"b"]).unwrap(), b"c");
+        assert_eq!(operator.full_merge(b"k", Some(b"z"), &[b"a"]).unwrap(), b"z");
+    }
+}
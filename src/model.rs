@@ -0,0 +1,180 @@
+//Model - randomized tests of the Database against a BTreeMap that does the same thing
+
+/*
+Every case is a random sequence of sets, deletes, gets, scans, reopens, flushes and compactions. Each operation
+is applied to a Database on a MemEnv and to a BTreeMap, the model, and both have to answer the same: a get or
+a scan returns what the model holds, and after every step a scan of the whole Database equals the model.
+
+A case that fails is shrunk before it is reported. Runs of operations are cut out of it for as long as what is
+left still fails, first halves, then quarters and so on down to single operations, so the case reported is
+one that stops failing when any one of its operations is taken out.
+*/
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use rand::rngs::StdRng;
+use rand::Rng;
+
+use crate::database::Database;
+use crate::env::{Env, MemEnv};
+use crate::options::Options;
+
+//Few keys, so that sets and deletes keep hitting keys that are already there
+const KEYS: u8 = 16;
+
+//Keys and values in key order, as a scan returns them
+type Entries = Vec<(String, String)>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Set(u8, u32),
+    Delete(u8),
+    Get(u8),
+    //Scan the keys that start with the prefix, key0 and key1 hold half of them each
+    Scan(&'static str),
+    Reopen,
+    Flush,
+    Compact,
+}
+
+fn key(key: u8) -> String {
+    format!("key{:02}", key)
+}
+
+fn value(value: u32) -> String {
+    format!("value{}", value)
+}
+
+fn generate(rng: &mut StdRng, len: usize) -> Vec<Operation> {
+    (0..len)
+        .map(|i| match rng.gen_range(0..100) {
+            0..=39 => Operation::Set(rng.gen_range(0..KEYS), i as u32),
+            40..=59 => Operation::Delete(rng.gen_range(0..KEYS)),
+            60..=74 => Operation::Get(rng.gen_range(0..KEYS)),
+            75..=84 => Operation::Scan(["", "key0", "key1"][rng.gen_range(0..3)]),
+            85..=89 => Operation::Reopen,
+            90..=94 => Operation::Flush,
+            _ => Operation::Compact,
+        })
+        .collect()
+}
+
+//Apply the operations to a new Database and the model, Err tells at which step they first differ
+fn check(operations: &[Operation]) -> Result<(), String> {
+    let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+    //Small MemTables, so that writes flush on their own too
+    let options = Options::new().env(env).write_buffer_size(256);
+    let dir = Path::new("db");
+    let open = || Database::open(dir, &options).map_err(|err| format!("open failed: {}", err));
+    let mut db = Some(open()?);
+    let mut model: BTreeMap<String, String> = BTreeMap::new();
+
+    for (step, operation) in operations.iter().enumerate() {
+        let failed = |what: String| format!("step {} {:?}: {}", step, operation, what);
+        let database = db.as_ref().unwrap();
+        match *operation {
+            Operation::Set(k, v) => {
+                database.set(key(k).as_bytes(), value(v).as_bytes()).map_err(|err| failed(err.to_string()))?;
+                model.insert(key(k), value(v));
+            }
+            Operation::Delete(k) => {
+                database.delete(key(k).as_bytes()).map_err(|err| failed(err.to_string()))?;
+                model.remove(&key(k));
+            }
+            Operation::Get(k) => {
+                let found = database.get(key(k).as_bytes()).map_err(|err| failed(err.to_string()))?;
+                let found = found.map(|record| String::from_utf8_lossy(record.value()).into_owned());
+                if found.as_ref() != model.get(&key(k)) {
+                    return Err(failed(format!("got {:?}, the model holds {:?}", found, model.get(&key(k)))));
+                }
+            }
+            Operation::Scan(prefix) => {
+                let found = scan(database, prefix.as_bytes()).map_err(failed)?;
+                let expected: Entries = model
+                    .iter()
+                    .filter(|(k, _)| k.starts_with(prefix))
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect();
+                if found != expected {
+                    return Err(failed(format!("scanned {:?}, the model holds {:?}", found, expected)));
+                }
+            }
+            Operation::Reopen => {
+                drop(db.take());
+                db = Some(open().map_err(failed)?);
+            }
+            Operation::Flush => database.flush().map_err(|err| failed(err.to_string()))?,
+            Operation::Compact => database.compact().map_err(|err| failed(err.to_string()))?,
+        }
+
+        let found = scan(db.as_ref().unwrap(), b"").map_err(failed)?;
+        let expected: Entries = model.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        if found != expected {
+            return Err(failed(format!("the Database holds {:?}, the model {:?}", found, expected)));
+        }
+    }
+    Ok(())
+}
+
+fn scan(db: &Database, prefix: &[u8]) -> Result<Entries, String> {
+    let records = db.scan_prefix(prefix).map_err(|err| err.to_string())?;
+    let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+    Ok(records.iter().map(|record| (text(record.key()), text(record.value()))).collect())
+}
+
+//Cut runs of operations out of a failing case for as long as it keeps failing
+fn shrink(mut operations: Vec<Operation>, fails: impl Fn(&[Operation]) -> bool) -> Vec<Operation> {
+    let mut chunk = operations.len() / 2;
+    while chunk > 0 {
+        let mut start = 0;
+        while start + chunk <= operations.len() {
+            let mut candidate = operations.clone();
+            candidate.drain(start..start + chunk);
+            if fails(&candidate) {
+                operations = candidate;
+            } else {
+                start += chunk;
+            }
+        }
+        chunk /= 2;
+    }
+    operations
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::{check, generate, shrink, Operation};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_database_matches_model() {
+        for seed in 0..50 {
+            let operations = generate(&mut StdRng::seed_from_u64(seed), 200);
+            if let Err(err) = check(&operations) {
+                let minimal = shrink(operations, |operations| check(operations).is_err());
+                let reason = check(&minimal).unwrap_err();
+                panic!("seed {} failed: {}\nshrunk to {:?}: {}", seed, err, minimal, reason);
+            }
+        }
+    }
+
+    #[test]
+    fn test_shrink() {
+        //Fails once a key is deleted and read after it, whatever else happens around it
+        let fails = |operations: &[Operation]| {
+            operations.iter().enumerate().any(|(i, operation)| {
+                matches!(operation, Operation::Delete(3)) && operations[i + 1..].contains(&Operation::Get(3))
+            })
+        };
+        let mut operations = generate(&mut StdRng::seed_from_u64(7), 300);
+        operations.insert(40, Operation::Delete(3));
+        operations.insert(250, Operation::Get(3));
+        assert!(fails(&operations));
+
+        let minimal = shrink(operations, fails);
+        assert_eq!(minimal, vec![Operation::Delete(3), Operation::Get(3)]);
+    }
+}
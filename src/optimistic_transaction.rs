@@ -0,0 +1,160 @@
+//Optimistic Transaction
+
+/*
+Reads go through a snapshot taken when the transaction begins and writes are buffered in the
+transaction, nothing is locked while it runs. Every key read is remembered with the timestamp of the
+version that was read (None if the key did not exist).
+
+On commit the Database lock is taken and every read key is checked against its newest version. If
+any of them changed since it was read another writer got there first and the commit fails with a
+Conflict, otherwise the buffered writes go to the WAL as one atomic batch.
+*/
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::column_family::DEFAULT_COLUMN_FAMILY_ID;
+use crate::database::Database;
+use crate::error::{Error, Result};
+use crate::options::WriteOptions;
+use crate::snapshot::Snapshot;
+use crate::write_batch::WriteBatch;
+
+pub struct OptimisticTransaction<'a> {
+    db: &'a Database,
+    snapshot: Snapshot,
+    reads: HashMap<Vec<u8>, Option<u128>>,
+    //None deletes the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> OptimisticTransaction<'a> {
+    pub fn new(db: &'a Database) -> OptimisticTransaction<'a> {
+        OptimisticTransaction {
+            db,
+            snapshot: db.snapshot(),
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    //Value of the key as this transaction sees it, its own writes first and then the snapshot
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let inner = self.db.lock_open()?;
+        let column_family = inner.column_family(DEFAULT_COLUMN_FAMILY_ID);
+        let version = column_family.versions_at(key, self.snapshot.timestamp(), true)?.first().map(|r| r.timestamp);
+        self.reads.entry(key.to_owned()).or_insert(version);
+        Ok(column_family
+            .read(key, self.snapshot.timestamp(), self.snapshot.timestamp(), true)?
+            .map(|record| record.value().to_vec()))
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.insert(key.to_owned(), Some(value.to_owned()));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_owned(), None);
+    }
+
+    //Timestamp the transaction reads at
+    pub fn start_timestamp(&self) -> u128 {
+        self.snapshot.timestamp()
+    }
+
+    //Check every read key is unchanged and write the buffered writes as one batch
+    pub fn commit(self) -> Result<()> {
+        let mut inner = self.db.lock_open()?;
+        for (key, version) in self.reads.iter() {
+            let current = inner
+                .column_family(DEFAULT_COLUMN_FAMILY_ID)
+                .versions_at(key, u128::MAX, true)?
+                .first()
+                .map(|r| r.timestamp);
+            if current != *version {
+                return Err(Error::Conflict { key: key.clone() });
+            }
+        }
+
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes.iter() {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.delete(key),
+            }
+        }
+        inner.write_batch(&batch, self.db.snapshot_list(), &WriteOptions::default())
+    }
+
+    //Throw away the buffered writes, same as dropping the transaction
+    pub fn rollback(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::Database;
+    use crate::error::Error;
+    use crate::env::MemEnv;
+    use crate::options::Options;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_commit() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        db.set(b"Badri", b"10").unwrap();
+
+        let mut txn = db.begin_optimistic();
+        assert_eq!(txn.get(b"Badri").unwrap().unwrap(), b"10");
+        txn.set(b"Badri", b"5");
+        txn.set(b"Lavanya", b"5");
+        txn.delete(b"Keerthi");
+        assert_eq!(txn.get(b"Lavanya").unwrap().unwrap(), b"5");
+        assert!(db.get(b"Lavanya").unwrap().is_none());
+        txn.commit().unwrap();
+
+        assert_eq!(db.get(b"Badri").unwrap().unwrap().value(), b"5");
+        assert_eq!(db.get(b"Lavanya").unwrap().unwrap().value(), b"5");
+        assert_eq!(db.get(b"Badri").unwrap().unwrap().timestamp(), db.get(b"Lavanya").unwrap().unwrap().timestamp());
+
+        //The batch is replayed from the WAL after a restart
+        drop(db);
+        let db = Database::open("db", &options).unwrap();
+        assert_eq!(db.get(b"Badri").unwrap().unwrap().value(), b"5");
+        assert_eq!(db.get(b"Lavanya").unwrap().unwrap().value(), b"5");
+    }
+
+    #[test]
+    fn test_conflict() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        db.set(b"Badri", b"10").unwrap();
+
+        let mut first = db.begin_optimistic();
+        let mut second = db.begin_optimistic();
+        assert_eq!(first.get(b"Badri").unwrap().unwrap(), b"10");
+        assert_eq!(second.get(b"Badri").unwrap().unwrap(), b"10");
+        //Reading a missing key still conflicts if someone creates it
+        assert!(second.get(b"Car").unwrap().is_none());
+        first.set(b"Badri", b"11");
+        second.set(b"Badri", b"12");
+
+        first.commit().unwrap();
+        match second.commit() {
+            Err(Error::Conflict { key }) => assert_eq!(key, b"Badri"),
+            _ => panic!("expected a conflict"),
+        }
+        assert_eq!(db.get(b"Badri").unwrap().unwrap().value(), b"11");
+
+        let mut third = db.begin_optimistic();
+        assert!(third.get(b"Car").unwrap().is_none());
+        db.set(b"Car", b"Garage").unwrap();
+        third.set(b"Bike", b"Bike Rack");
+        assert!(matches!(third.commit(), Err(Error::Conflict { .. })));
+        assert!(db.get(b"Bike").unwrap().is_none());
+    }
+}
@@ -0,0 +1,203 @@
+//Options - how a Database is opened, read and written
+
+/*
+Options are set once when the Database is opened with Database::open. ReadOptions and WriteOptions are
+given to a single read or write.
+
+The comparator decides how the data on disk is sorted. It is written to the MANIFEST when the Database is
+created and opening it again with a different comparator fails with Error::ComparatorMismatch.
+The compression is written to every table, so it can change between opens and the old tables are still read
+with the compression they were written with.
+Every file of the Database is read and written through the env, the file system unless it is set.
+The clock and the scheduler are the time of day and a thread per background job unless they are set,
+a Simulation stands in for both to make a run repeatable from its seed.
+*/
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::{default_clock, Clock};
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::compression::Compression;
+use crate::env::{default_env, Env};
+use crate::error::Error;
+use crate::scheduler::{default_scheduler, Scheduler};
+use crate::snapshot::Snapshot;
+
+//Called with an error no caller is left to return it to
+pub type BackgroundErrorHandler = Arc<dyn Fn(&Error) + Send + Sync>;
+
+//When the WAL is synced to disk, a write that was not synced yet can be lost if the machine goes down
+//Writes always reach the OS before they return, so a crash of only the process loses nothing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    //Leave it to the OS
+    #[default]
+    Never,
+    //Sync before every write returns
+    EveryWrite,
+    //Sync on the first write once this long has passed since the last sync
+    Periodic(Duration),
+}
+
+#[derive(Clone)]
+pub struct Options {
+    pub(crate) create_if_missing: bool,
+    pub(crate) error_if_exists: bool,
+    pub(crate) write_buffer_size: usize,
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) comparator: Arc<dyn Comparator>,
+    pub(crate) compression: Compression,
+    pub(crate) block_cache_size: usize,
+    pub(crate) flush_on_close: bool,
+    pub(crate) env: Arc<dyn Env>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) scheduler: Arc<dyn Scheduler>,
+    pub(crate) on_background_error: Option<BackgroundErrorHandler>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            create_if_missing: true,
+            error_if_exists: false,
+            write_buffer_size: 4 * 1024 * 1024,
+            sync_policy: SyncPolicy::default(),
+            comparator: Arc::new(BytewiseComparator),
+            compression: Compression::default(),
+            block_cache_size: 8 * 1024 * 1024,
+            flush_on_close: false,
+            env: default_env(),
+            clock: default_clock(),
+            scheduler: default_scheduler(),
+            on_background_error: None,
+        }
+    }
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options::default()
+    }
+
+    //Create the directory and an empty Database when there is none yet, otherwise opening fails
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Options {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    //Fail to open when there already is a Database in the directory
+    pub fn error_if_exists(mut self, error_if_exists: bool) -> Options {
+        self.error_if_exists = error_if_exists;
+        self
+    }
+
+    //Flush once the default column family's MemTable holds this many bytes
+    pub fn write_buffer_size(mut self, write_buffer_size: usize) -> Options {
+        self.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Options {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    pub fn comparator(mut self, comparator: Arc<dyn Comparator>) -> Options {
+        self.comparator = comparator;
+        self
+    }
+
+    //Compression of the data blocks of new tables
+    pub fn compression(mut self, compression: Compression) -> Options {
+        self.compression = compression;
+        self
+    }
+
+    //Bytes of decoded data blocks kept in memory for reads, 0 turns the cache off
+    pub fn block_cache_size(mut self, block_cache_size: usize) -> Options {
+        self.block_cache_size = block_cache_size;
+        self
+    }
+
+    //Write the MemTables to tables when the Database is closed, otherwise they are replayed from the WAL on the next open
+    pub fn flush_on_close(mut self, flush_on_close: bool) -> Options {
+        self.flush_on_close = flush_on_close;
+        self
+    }
+
+    //Where the files of the Database are kept, MemEnv keeps them in memory
+    pub fn env(mut self, env: Arc<dyn Env>) -> Options {
+        self.env = env;
+        self
+    }
+
+    //Where timestamps and the time of day come from, a Simulation makes them repeatable
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Options {
+        self.clock = clock;
+        self
+    }
+
+    //What runs the background jobs, the TTL sweeper for one
+    pub fn scheduler(mut self, scheduler: Arc<dyn Scheduler>) -> Options {
+        self.scheduler = scheduler;
+        self
+    }
+
+    //Hear about the errors of background jobs and of closing a Database when it is dropped, they are dropped otherwise
+    //Write failures also stop the writes, Database::background_error tells about those
+    pub fn on_background_error(mut self, handler: BackgroundErrorHandler) -> Options {
+        self.on_background_error = Some(handler);
+        self
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct ReadOptions<'a> {
+    pub(crate) snapshot: Option<&'a Snapshot>,
+    pub(crate) fill_cache: bool,
+}
+
+impl Default for ReadOptions<'_> {
+    fn default() -> Self {
+        ReadOptions {
+            snapshot: None,
+            fill_cache: true,
+        }
+    }
+}
+
+impl<'a> ReadOptions<'a> {
+    pub fn new() -> ReadOptions<'a> {
+        ReadOptions::default()
+    }
+
+    //Read as of the snapshot instead of now
+    pub fn snapshot(mut self, snapshot: &'a Snapshot) -> ReadOptions<'a> {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    //Keep the blocks this read loads in the block cache, turn it off for one-off scans over cold data
+    pub fn fill_cache(mut self, fill_cache: bool) -> ReadOptions<'a> {
+        self.fill_cache = fill_cache;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct WriteOptions {
+    pub(crate) sync: bool,
+}
+
+impl WriteOptions {
+    pub fn new() -> WriteOptions {
+        WriteOptions::default()
+    }
+
+    //Sync the WAL before the write returns whatever the Database's sync policy is
+    pub fn sync(mut self, sync: bool) -> WriteOptions {
+        self.sync = sync;
+        self
+    }
+}
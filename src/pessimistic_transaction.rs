@@ -0,0 +1,292 @@
+//Pessimistic Transaction
+
+/*
+Every key the transaction writes, or reads with get_for_update, is locked in the Database's
+LockManager until the transaction commits or rolls back. Two transactions can never both change a
+key, so commit never fails with a Conflict, the cost is waiting on the lock instead.
+
+Writes are buffered in the transaction like the optimistic one, so a plain Database::get never sees
+them before commit. A savepoint remembers the buffered writes at that moment and
+rollback_to_savepoint puts them back, locks taken after the savepoint are kept until the end.
+
+For two-phase commit the transaction can be prepared under a name first. That logs its write set
+to the WAL and hands the row locks over to the Database, so the prepared transaction outlives this
+handle and even a crash. Commit or rollback of the handle, or Database::commit_prepared and
+rollback_prepared with the name, decides it.
+*/
+
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+use crate::database::Database;
+use crate::error::{Error, Result};
+use crate::write_batch::WriteBatch;
+
+pub struct PessimisticTransaction<'a> {
+    db: &'a Database,
+    id: u64,
+    lock_timeout: Duration,
+    locked: HashSet<Vec<u8>>,
+    //None deletes the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    savepoints: Vec<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+    prepared_name: Option<String>,
+}
+
+impl<'a> PessimisticTransaction<'a> {
+    pub fn new(db: &'a Database, id: u64, lock_timeout: Duration) -> PessimisticTransaction<'a> {
+        PessimisticTransaction {
+            db,
+            id,
+            lock_timeout,
+            locked: HashSet::new(),
+            writes: BTreeMap::new(),
+            savepoints: Vec::new(),
+            prepared_name: None,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    //How long to wait for another transaction to release a key before giving up
+    pub fn set_lock_timeout(&mut self, timeout: Duration) {
+        self.lock_timeout = timeout;
+    }
+
+    //Latest committed value of the key or this transaction's own write, takes no lock
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        Ok(self.db.get(key)?.map(|record| record.value().to_vec()))
+    }
+
+    //Lock the key then read it, nobody else can change it until this transaction ends
+    pub fn get_for_update(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.lock(key)?;
+        self.get(key)
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.lock(key)?;
+        self.writes.insert(key.to_owned(), Some(value.to_owned()));
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.lock(key)?;
+        self.writes.insert(key.to_owned(), None);
+        Ok(())
+    }
+
+    pub fn set_savepoint(&mut self) {
+        self.savepoints.push(self.writes.clone());
+    }
+
+    //Undo every write made since the last savepoint and pop it
+    pub fn rollback_to_savepoint(&mut self) -> Result<()> {
+        if self.prepared_name.is_some() {
+            return Err(Error::TransactionPrepared);
+        }
+        self.writes = self.savepoints.pop().ok_or(Error::NoSavepoint)?;
+        Ok(())
+    }
+
+    //First phase of two-phase commit, durably log the write set under name
+    //After this the transaction can only be committed or rolled back
+    pub fn prepare(&mut self, name: &str) -> Result<()> {
+        if self.prepared_name.is_some() {
+            return Err(Error::TransactionPrepared);
+        }
+        self.db.prepare(name, self.id, &self.write_batch())?;
+        //The Database owns the row locks now, dropping this handle leaves the transaction prepared
+        self.locked.clear();
+        self.prepared_name = Some(name.to_owned());
+        Ok(())
+    }
+
+    //Write the buffered writes as one batch and release the locks
+    pub fn commit(self) -> Result<()> {
+        if let Some(name) = self.prepared_name.as_ref() {
+            return self.db.commit_prepared(name);
+        }
+        self.db.write(&self.write_batch())?;
+        Ok(())
+    }
+
+    //Throw away the buffered writes and release the locks, same as dropping the transaction
+    //unless it was prepared, then the rollback is logged so it is not recovered
+    pub fn rollback(self) -> Result<()> {
+        if let Some(name) = self.prepared_name.as_ref() {
+            return self.db.rollback_prepared(name);
+        }
+        Ok(())
+    }
+
+    fn write_batch(&self) -> WriteBatch {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes.iter() {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.delete(key),
+            }
+        }
+        batch
+    }
+
+    fn lock(&mut self, key: &[u8]) -> Result<()> {
+        if self.prepared_name.is_some() {
+            return Err(Error::TransactionPrepared);
+        }
+        if self.locked.contains(key) {
+            return Ok(());
+        }
+        self.db.lock_manager().lock(self.id, key, self.lock_timeout)?;
+        self.locked.insert(key.to_owned());
+        Ok(())
+    }
+}
+
+impl Drop for PessimisticTransaction<'_> {
+    fn drop(&mut self) {
+        self.db.lock_manager().unlock(self.id, self.locked.iter());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::Database;
+    use crate::error::Error;
+    use crate::env::MemEnv;
+    use crate::options::Options;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_commit_and_isolation() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        db.set(b"Car", b"1").unwrap();
+
+        let mut txn = db.begin_pessimistic();
+        assert_eq!(txn.get_for_update(b"Car").unwrap().unwrap(), b"1");
+        txn.set(b"Car", b"2").unwrap();
+        assert_eq!(txn.get(b"Car").unwrap().unwrap(), b"2");
+        assert_eq!(db.get(b"Car").unwrap().unwrap().value(), b"1");
+
+        //Another transaction cannot take the lock until the first one is done
+        let mut other = db.begin_pessimistic();
+        other.set_lock_timeout(Duration::from_millis(10));
+        assert!(matches!(other.set(b"Car", b"3"), Err(Error::LockTimeout { .. })));
+
+        txn.commit().unwrap();
+        assert_eq!(db.get(b"Car").unwrap().unwrap().value(), b"2");
+        other.set(b"Car", b"3").unwrap();
+        other.rollback().unwrap();
+        assert_eq!(db.get(b"Car").unwrap().unwrap().value(), b"2");
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        let mut txn = db.begin_pessimistic();
+        txn.set(b"Badri", b"1").unwrap();
+        txn.set_savepoint();
+        txn.set(b"Badri", b"2").unwrap();
+        txn.delete(b"Lavanya").unwrap();
+        txn.set_savepoint();
+        txn.set(b"Keerthi", b"3").unwrap();
+
+        txn.rollback_to_savepoint().unwrap();
+        assert!(txn.get(b"Keerthi").unwrap().is_none());
+        assert_eq!(txn.get(b"Badri").unwrap().unwrap(), b"2");
+        txn.rollback_to_savepoint().unwrap();
+        assert_eq!(txn.get(b"Badri").unwrap().unwrap(), b"1");
+        assert!(matches!(txn.rollback_to_savepoint(), Err(Error::NoSavepoint)));
+
+        txn.commit().unwrap();
+        assert_eq!(db.get(b"Badri").unwrap().unwrap().value(), b"1");
+        assert!(db.get(b"Keerthi").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_counter_increments() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        db.set(b"counter", &0u64.to_le_bytes()).unwrap();
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..25 {
+                        let mut txn = db.begin_pessimistic();
+                        let value = txn.get_for_update(b"counter").unwrap().unwrap();
+                        let count = u64::from_le_bytes(value.try_into().unwrap());
+                        txn.set(b"counter", &(count + 1).to_le_bytes()).unwrap();
+                        txn.commit().unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(db.get(b"counter").unwrap().unwrap().value(), 100u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_prepared_survives_restart() {
+        let options = Options::new().env(Arc::new(MemEnv::new()));
+
+        let db = Database::open("db", &options).unwrap();
+        let mut first = db.begin_pessimistic();
+        first.set(b"Badri", b"1").unwrap();
+        first.delete(b"Lavanya").unwrap();
+        first.prepare("xa-1").unwrap();
+        assert!(matches!(first.set(b"Keerthi", b"2"), Err(Error::TransactionPrepared)));
+        drop(first);
+
+        let mut second = db.begin_pessimistic();
+        second.set(b"Car", b"Garage").unwrap();
+        second.prepare("xa-2").unwrap();
+        drop(second);
+
+        let mut third = db.begin_pessimistic();
+        assert!(matches!(third.prepare("xa-1"), Err(Error::PreparedNameInUse { .. })));
+        third.set(b"Bike", b"Bike Rack").unwrap();
+        third.prepare("xa-3").unwrap();
+        third.rollback().unwrap();
+
+        assert_eq!(db.prepared_transactions(), vec!["xa-1", "xa-2"]);
+        assert!(db.get(b"Badri").unwrap().is_none());
+
+        //Both undecided transactions come back after a restart and keep their locks
+        drop(db);
+        let db = Database::open("db", &options).unwrap();
+        assert_eq!(db.prepared_transactions(), vec!["xa-1", "xa-2"]);
+        let mut blocked = db.begin_pessimistic();
+        blocked.set_lock_timeout(Duration::from_millis(10));
+        assert!(matches!(blocked.set(b"Badri", b"3"), Err(Error::LockTimeout { .. })));
+        drop(blocked);
+
+        db.commit_prepared("xa-1").unwrap();
+        db.rollback_prepared("xa-2").unwrap();
+        assert!(matches!(db.commit_prepared("xa-2"), Err(Error::UnknownPrepared { .. })));
+        assert_eq!(db.get(b"Badri").unwrap().unwrap().value(), b"1");
+        assert!(db.get(b"Car").unwrap().is_none());
+        assert!(db.get(b"Bike").unwrap().is_none());
+
+        //The decisions are durable too
+        drop(db);
+        let db = Database::open("db", &options).unwrap();
+        assert!(db.prepared_transactions().is_empty());
+        assert_eq!(db.get(b"Badri").unwrap().unwrap().value(), b"1");
+        assert!(db.get(b"Car").unwrap().is_none());
+        let mut txn = db.begin_pessimistic();
+        txn.set(b"Badri", b"4").unwrap();
+        txn.commit().unwrap();
+    }
+}
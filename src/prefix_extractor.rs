@@ -0,0 +1,118 @@
+//Prefix Extractor
+
+/*
+A prefix extractor maps a key to the prefix it belongs to. Keys in LanaDB usually look like
+tenant/entity/id so the extractor lets us group keys by tenant/entity without hand parsing.
+
+The extracted prefix is what on-disk tables record in their prefix filter, so a scan can skip a
+whole file when the prefix it asks for was never extracted from any of the file's keys.
+in_domain tells us if a key has a prefix at all, keys outside the domain are never put in a filter
+*/
+
+pub trait PrefixExtractor: Send + Sync {
+    //Name of the extractor, used to tell if a filter was built with the same extractor
+    fn name(&self) -> &str;
+
+    //Does the key have a prefix this extractor knows how to pull out
+    fn in_domain(&self, key: &[u8]) -> bool;
+
+    //Pull out the prefix of the key, only called for keys that are in_domain
+    fn transform<'a>(&self, key: &'a [u8]) -> &'a [u8];
+}
+
+//Prefix is the first `len` bytes of the key
+pub struct FixedPrefix {
+    len: usize,
+    name: String,
+}
+
+impl FixedPrefix {
+    pub fn new(len: usize) -> FixedPrefix {
+        FixedPrefix {
+            len,
+            name: format!("lanadb.FixedPrefix.{}", len),
+        }
+    }
+}
+
+impl PrefixExtractor for FixedPrefix {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn in_domain(&self, key: &[u8]) -> bool {
+        key.len() >= self.len
+    }
+
+    fn transform<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        &key[..self.len]
+    }
+}
+
+//Prefix is everything up to and including the Nth delimiter
+//ex. DelimitedPrefix::new(b'/', 2) turns tenant/entity/id into tenant/entity/
+pub struct DelimitedPrefix {
+    delimiter: u8,
+    count: usize,
+    name: String,
+}
+
+impl DelimitedPrefix {
+    pub fn new(delimiter: u8, count: usize) -> DelimitedPrefix {
+        DelimitedPrefix {
+            delimiter,
+            count,
+            name: format!("lanadb.DelimitedPrefix.{}.{}", delimiter, count),
+        }
+    }
+
+    fn prefix_len(&self, key: &[u8]) -> Option<usize> {
+        if self.count == 0 {
+            return None;
+        }
+        key.iter()
+            .enumerate()
+            .filter(|(_, b)| **b == self.delimiter)
+            .nth(self.count - 1)
+            .map(|(idx, _)| idx + 1)
+    }
+}
+
+impl PrefixExtractor for DelimitedPrefix {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn in_domain(&self, key: &[u8]) -> bool {
+        self.prefix_len(key).is_some()
+    }
+
+    fn transform<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        match self.prefix_len(key) {
+            Some(len) => &key[..len],
+            None => key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prefix_extractor::{DelimitedPrefix, FixedPrefix, PrefixExtractor};
+
+    #[test]
+    fn test_fixed_prefix() {
+        let extractor = FixedPrefix::new(4);
+        assert!(extractor.in_domain(b"acme/users/1"));
+        assert!(!extractor.in_domain(b"acm"));
+        assert_eq!(extractor.transform(b"acme/users/1"), b"acme");
+    }
+
+    #[test]
+    fn test_delimited_prefix() {
+        let extractor = DelimitedPrefix::new(b'/', 2);
+        assert!(extractor.in_domain(b"acme/users/1"));
+        assert!(!extractor.in_domain(b"acme/users"));
+        assert_eq!(extractor.transform(b"acme/users/1"), b"acme/users/");
+        assert_eq!(extractor.transform(b"acme/orders/2/items"), b"acme/orders/");
+    }
+}
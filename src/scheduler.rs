@@ -0,0 +1,68 @@
+//Scheduler - runs the background jobs of a Database
+
+/*
+The TTL sweeper is a job run every interval, the Scheduler in Options decides where and when. ThreadScheduler
+gives every job a thread of its own that sleeps between runs. A Simulation runs the jobs on the thread that
+moves its clock forward, one at a time and in an order its seed decides.
+
+A job keeps running until it returns false or the ScheduledJob handle for it is dropped.
+*/
+
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+pub trait Scheduler: Send + Sync {
+    //Run job every interval, the first run is one interval from now
+    fn every(&self, name: &str, interval: Duration, job: Box<dyn FnMut() -> bool + Send>) -> Box<dyn ScheduledJob>;
+}
+
+//Handle of a scheduled job, dropping it stops the job and waits for a run in progress to end
+pub trait ScheduledJob: Send {}
+
+pub fn default_scheduler() -> Arc<dyn Scheduler> {
+    Arc::new(ThreadScheduler)
+}
+
+pub struct ThreadScheduler;
+
+impl Scheduler for ThreadScheduler {
+    fn every(&self, name: &str, interval: Duration, mut job: Box<dyn FnMut() -> bool + Send>) -> Box<dyn ScheduledJob> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || {
+                //Dropping the Sender disconnects the channel and ends the loop
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    if !job() {
+                        break;
+                    }
+                }
+            })
+            .unwrap();
+        Box::new(ThreadJob {
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+}
+
+struct ThreadJob {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ScheduledJob for ThreadJob {}
+
+impl Drop for ThreadJob {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            //The job can drop its own handle, a thread cannot join itself
+            if handle.thread().id() != thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//Simulation - a clock, a scheduler and an Env that replay exactly from a seed
+
+/*
+A Database opened with Simulation::options reads the time from the simulation, hands its background jobs to
+it and keeps its files in memory behind a FaultInjectionEnv. Nothing then depends on the machine: the clock
+starts at a time picked from the seed and moves on a microsecond every time it is read, background jobs only
+run from advance, on the thread that calls it, one at a time in the order they come due. Jobs due at the same
+time and the faults inject_fault makes are picked from the seed as well.
+
+Simulation::with_stalled_clock starts one whose clock only moves in advance, every read in between gets the
+same time, the way a coarse or stalled system clock does. Writes then share a microsecond and are stamped past it.
+
+A run that only takes its randomness from random does the same thing every time it is given the same seed,
+so a failing run can be replayed from the seed it was reported with.
+*/
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::clock::Clock;
+use crate::env::MemEnv;
+use crate::fault_injection::FaultInjectionEnv;
+use crate::options::Options;
+use crate::scheduler::{ScheduledJob, Scheduler};
+
+//Earliest time the clock starts at, in microseconds since the UNIX epoch
+const START: u128 = 1_600_000_000_000_000;
+
+//Clones share the clock, the jobs and the files
+#[derive(Clone)]
+pub struct Simulation {
+    seed: u64,
+    env: Arc<FaultInjectionEnv>,
+    state: Arc<Mutex<SimulationState>>,
+}
+
+struct SimulationState {
+    rng: StdRng,
+    now: u128,
+    //Reading the clock moves it on a microsecond
+    ticks: bool,
+    next_id: u64,
+    jobs: BTreeMap<u64, SimulatedJob>,
+}
+
+struct SimulatedJob {
+    interval: u128,
+    next_run: u128,
+    //None while it runs
+    job: Option<Box<dyn FnMut() -> bool + Send>>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Simulation {
+        Simulation::with_clock(seed, true)
+    }
+
+    //A simulation whose clock only moves in advance
+    pub fn with_stalled_clock(seed: u64) -> Simulation {
+        Simulation::with_clock(seed, false)
+    }
+
+    fn with_clock(seed: u64, ticks: bool) -> Simulation {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let now = START + rng.gen_range(0..1_000_000_000_000);
+        Simulation {
+            seed,
+            env: Arc::new(FaultInjectionEnv::new(Arc::new(MemEnv::new()))),
+            state: Arc::new(Mutex::new(SimulationState {
+                rng,
+                now,
+                ticks,
+                next_id: 0,
+                jobs: BTreeMap::new(),
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, SimulationState> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    //Options with the simulation as env, clock and scheduler, everything else is the default
+    pub fn options(&self) -> Options {
+        Options::new()
+            .env(self.env.clone())
+            .clock(Arc::new(self.clone()))
+            .scheduler(Arc::new(self.clone()))
+    }
+
+    //The Env the files are kept in, to inject faults or lose unsynced data with
+    pub fn env(&self) -> &Arc<FaultInjectionEnv> {
+        &self.env
+    }
+
+    //A number in range drawn from the seed
+    pub fn random(&self, range: Range<u64>) -> u64 {
+        self.state().rng.gen_range(range)
+    }
+
+    //Make one of the next within operations on the files fail with an I/O error, returns which one
+    pub fn inject_fault(&self, within: u64) -> u64 {
+        let n = self.random(1..within + 1);
+        self.env.fail_operation(n);
+        n
+    }
+
+    //Move the clock forward by duration, running every job that comes due on the way when it does
+    pub fn advance(&self, duration: Duration) {
+        let until = self.state().now + duration.as_micros();
+        loop {
+            let (id, mut job) = {
+                let mut state = self.state();
+                let waiting = state.jobs.values().filter(|job| job.job.is_some());
+                let Some(next_run) = waiting.map(|job| job.next_run).min().filter(|next_run| *next_run <= until) else {
+                    break;
+                };
+                let due: Vec<u64> = state
+                    .jobs
+                    .iter()
+                    .filter(|(_, job)| job.job.is_some() && job.next_run == next_run)
+                    .map(|(id, _)| *id)
+                    .collect();
+                let id = due[state.rng.gen_range(0..due.len())];
+                state.now = state.now.max(next_run);
+                (id, state.jobs.get_mut(&id).unwrap().job.take().unwrap())
+            };
+            //Not under the lock, the job reads the clock and can drop its own handle
+            let again = job();
+            let mut state = self.state();
+            match state.jobs.get_mut(&id) {
+                Some(scheduled) if again => {
+                    scheduled.next_run += scheduled.interval;
+                    scheduled.job = Some(job);
+                }
+                _ => {
+                    state.jobs.remove(&id);
+                }
+            }
+        }
+        let mut state = self.state();
+        state.now = state.now.max(until);
+    }
+}
+
+impl Clock for Simulation {
+    fn now_micros(&self) -> u128 {
+        let mut state = self.state();
+        if state.ticks {
+            state.now += 1;
+        }
+        state.now
+    }
+}
+
+impl Scheduler for Simulation {
+    fn every(&self, _name: &str, interval: Duration, job: Box<dyn FnMut() -> bool + Send>) -> Box<dyn ScheduledJob> {
+        let mut state = self.state();
+        let id = state.next_id;
+        state.next_id += 1;
+        let interval = interval.as_micros().max(1);
+        let next_run = state.now + interval;
+        state.jobs.insert(id, SimulatedJob { interval, next_run, job: Some(job) });
+        Box::new(SimulatedJobHandle { state: self.state.clone(), id })
+    }
+}
+
+struct SimulatedJobHandle {
+    state: Arc<Mutex<SimulationState>>,
+    id: u64,
+}
+
+impl ScheduledJob for SimulatedJobHandle {}
+
+impl Drop for SimulatedJobHandle {
+    fn drop(&mut self) {
+        let removed = self.state.lock().unwrap().jobs.remove(&self.id);
+        //The job is dropped outside the lock, whatever it owns can reach the simulation too
+        drop(removed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::Clock;
+    use crate::database::Database;
+    use crate::env::Env;
+    use crate::scheduler::Scheduler;
+    use crate::simulation::Simulation;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_jobs_run_in_time_order() {
+        let simulation = Simulation::new(1);
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let job = |name: &'static str, limit: usize| {
+            let runs = runs.clone();
+            let mut count = 0;
+            Box::new(move || {
+                runs.lock().unwrap().push(name);
+                count += 1;
+                count < limit
+            })
+        };
+        let every_3 = simulation.every("every 3", Duration::from_millis(3), job("every 3", usize::MAX));
+        let _every_5 = simulation.every("every 5", Duration::from_millis(5), job("every 5", usize::MAX));
+        let _twice = simulation.every("twice", Duration::from_millis(4), job("twice", 2));
+
+        //Nothing runs until the clock is moved on
+        let start = simulation.now_micros();
+        assert!(runs.lock().unwrap().is_empty());
+        simulation.advance(Duration::from_millis(11));
+        assert_eq!(*runs.lock().unwrap(), vec!["every 3", "twice", "every 5", "every 3", "twice", "every 3", "every 5"]);
+        assert!(simulation.now_micros() > start + 11_000);
+
+        runs.lock().unwrap().clear();
+        drop(every_3);
+        simulation.advance(Duration::from_millis(10));
+        assert_eq!(*runs.lock().unwrap(), vec!["every 5", "every 5"]);
+    }
+
+    //How far a write's timestamp can run ahead of the clock it was read from, expiry is only checked outside it
+    const SLACK: u128 = 1_000;
+
+    //Everything a run could tell apart: what reads return, the timestamps and the files
+    //Every read is checked against a model of what the writes that succeeded left behind
+    fn run(simulation: Simulation) -> Vec<String> {
+        let db = Arc::new(Database::open("db", &simulation.options().write_buffer_size(512)).unwrap());
+        db.start_ttl_sweeper(Duration::from_millis(10));
+        //Value and the time it expires from for every key that was set
+        let mut model: BTreeMap<Vec<u8>, (Vec<u8>, u128)> = BTreeMap::new();
+        let mut trace = Vec::new();
+        for step in 0..200 {
+            let key = format!("key{}", simulation.random(0..8)).into_bytes();
+            let value = format!("value{}", step).into_bytes();
+            let now = simulation.now_micros();
+            let result = match simulation.random(0..10) {
+                0..=2 => db.set(&key, &value).map(|_| {
+                    model.insert(key.clone(), (value, u128::MAX));
+                }),
+                3..=4 => {
+                    let ttl = Duration::from_millis(simulation.random(1..30));
+                    db.set_with_ttl(&key, &value, ttl).map(|_| {
+                        model.insert(key.clone(), (value, now + ttl.as_micros()));
+                    })
+                }
+                5 => db.delete(&key).map(|_| {
+                    model.remove(&key);
+                }),
+                6 => db.flush(),
+                7 => {
+                    simulation.inject_fault(50);
+                    Ok(())
+                }
+                8 => db.resume(),
+                _ => {
+                    simulation.advance(Duration::from_millis(simulation.random(1..20)));
+                    Ok(())
+                }
+            };
+            let now = simulation.now_micros();
+            let found = db.get(&key).map(|record| record.map(|record| (record.value().to_vec(), record.timestamp())));
+            //A read the injected fault failed tells nothing about the data
+            if let Ok(found) = &found {
+                let value = found.as_ref().map(|(value, _)| value);
+                match model.get(&key) {
+                    Some((expected, expires_at)) if now + SLACK < *expires_at => {
+                        assert_eq!(value, Some(expected), "seed {} step {}", simulation.seed(), step)
+                    }
+                    Some((expected, expires_at)) if now < expires_at + SLACK => {
+                        assert!(value.is_none_or(|value| value == expected), "seed {} step {}", simulation.seed(), step)
+                    }
+                    _ => assert_eq!(value, None, "seed {} step {}", simulation.seed(), step),
+                }
+            }
+            trace.push(format!("{:?} {:?}", result.err().map(|err| err.to_string()), found));
+        }
+        //A fault that is still pending fails the first listing, it only fails once
+        let mut files = simulation.env().list_dir(Path::new("db")).or_else(|_| simulation.env().list_dir(Path::new("db"))).unwrap();
+        files.sort();
+        trace.push(format!("{:?}", files));
+        trace
+    }
+
+    #[test]
+    fn test_replay_from_seed() {
+        let first = run(Simulation::new(7));
+        assert_eq!(first, run(Simulation::new(7)));
+        assert_ne!(first, run(Simulation::new(8)));
+        //The faults did make writes fail and resume let them through again
+        assert!(first.iter().any(|step| step.starts_with("Some")));
+        assert!(first.last().unwrap().contains(".wal"));
+    }
+
+    #[test]
+    fn test_stalled_clock_runs() {
+        //Writes between two advances share a microsecond, every one of them has to be read back
+        for seed in 0..20 {
+            let first = run(Simulation::with_stalled_clock(seed));
+            assert_eq!(first, run(Simulation::with_stalled_clock(seed)));
+        }
+        let simulation = Simulation::with_stalled_clock(1);
+        let now = simulation.now_micros();
+        assert_eq!(simulation.now_micros(), now);
+        simulation.advance(Duration::from_millis(1));
+        assert_eq!(simulation.now_micros(), now + 1_000);
+    }
+}
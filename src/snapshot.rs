@@ -0,0 +1,71 @@
+//Snapshot - a pinned point in time to read the Database at
+
+/*
+Every write gets a timestamp that is strictly larger than the one before it, so a snapshot only
+needs to remember the timestamp of the last write committed before it was taken.
+Reads through the snapshot skip every version with a larger timestamp.
+
+The Database keeps the list of live snapshots so the MemTable knows which older versions it
+still has to keep around. Dropping the Snapshot takes it off that list.
+*/
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+//Count of live snapshots for each timestamp, more than one snapshot can share a timestamp
+#[derive(Default)]
+pub struct SnapshotList {
+    live: Mutex<BTreeMap<u128, usize>>,
+}
+
+impl SnapshotList {
+    pub fn new() -> SnapshotList {
+        SnapshotList::default()
+    }
+
+    //Timestamps of every live snapshot, oldest first
+    pub fn timestamps(&self) -> Vec<u128> {
+        self.live.lock().unwrap().keys().copied().collect()
+    }
+
+    pub fn oldest(&self) -> Option<u128> {
+        self.live.lock().unwrap().keys().next().copied()
+    }
+
+    fn acquire(&self, timestamp: u128) {
+        *self.live.lock().unwrap().entry(timestamp).or_insert(0) += 1;
+    }
+
+    fn release(&self, timestamp: u128) {
+        let mut live = self.live.lock().unwrap();
+        if let Some(count) = live.get_mut(&timestamp) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&timestamp);
+            }
+        }
+    }
+}
+
+pub struct Snapshot {
+    timestamp: u128,
+    list: Arc<SnapshotList>,
+}
+
+impl Snapshot {
+    pub fn new(timestamp: u128, list: Arc<SnapshotList>) -> Snapshot {
+        list.acquire(timestamp);
+        Snapshot { timestamp, list }
+    }
+
+    //Reads through this snapshot see the versions written at or before this timestamp
+    pub fn timestamp(&self) -> u128 {
+        self.timestamp
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.list.release(self.timestamp);
+    }
+}
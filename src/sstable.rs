@@ -0,0 +1,678 @@
+//SSTable - Sorted String Table
+
+/*
+An immutable file holding the records of a flushed MemTable, or the output of a compaction, sorted by
+key with the Database's comparator and then newest timestamp first like the MemTable keeps them.
+
++--------------+-----+--------------+-------------+--------------+------------------+---------------+
+| Data Block 0 | ... | Data Block n | Index Block | Filter Block | Properties Block | Footer (56B)  |
++--------------+-----+--------------+-------------+--------------+------------------+---------------+
+
+Data Block = records laid out like the WAL records of kind 0 (set), 1 (delete), 6 (set with ttl) and 7 (merge)
+A block is closed once it passes BLOCK_SIZE but never in the middle of a key, so every version of a key
+is in one block. Keys the comparator calls equal are one key even when their bytes differ.
+Each block is compressed on its own with the table's compression.
+
+Index Block = Count (8B) then for every data block | Last Key Size (8B) | Last Key | Offset (8B) | Size (8B) |
+The size is the compressed size the block takes in the file.
+The first block whose last key is >= the key we look for is the only block that can hold it.
+
+Filter Block = | Has Extractor (1B) | Name Size (8B) | Name | Count (8B) | Prefix Size (8B) | Prefix | ...
+Every distinct prefix the column family's prefix extractor pulled out of the table's keys, sorted.
+A prefix scan skips the table when the prefix it asks for is not in the list.
+
+Properties Block = | Min Key Size (8B) | Min Key | Max Key Size (8B) | Max Key | Entries (8B) | Max Timestamp (16B) |
+                   | Comparator Name Size (8B) | Comparator Name | Compression (1B) |
+A table can only be opened with the comparator it was sorted with.
+
+Footer = | Index Offset | Index Size | Filter Offset | Filter Size | Properties Offset | Properties Size | Magic | (8B each)
+*/
+
+use std::cmp::Ordering;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::block_cache::BlockCache;
+use crate::comparator::Comparator;
+use crate::compression::Compression;
+use crate::env::{Env, RandomAccessFile};
+use crate::mem_table::Record;
+use crate::prefix_extractor::PrefixExtractor;
+use crate::wal::{KIND_DELETE, KIND_MERGE, KIND_SET, KIND_SET_WITH_TTL};
+
+const BLOCK_SIZE: usize = 4096;
+const FOOTER_SIZE: usize = 56;
+const MAGIC: &[u8; 8] = b"LANADBT1";
+
+struct IndexEntry {
+    last_key: Vec<u8>,
+    offset: u64,
+    size: u64,
+}
+
+//How the tables of a Database are written and read, every column family shares it
+#[derive(Clone)]
+pub struct TableOptions {
+    pub comparator: Arc<dyn Comparator>,
+    //Compression new tables are written with
+    pub compression: Compression,
+    pub block_cache: Option<Arc<BlockCache>>,
+    pub env: Arc<dyn Env>,
+}
+
+impl TableOptions {
+    //Uncompressed tables without a block cache in env
+    #[cfg(test)]
+    pub fn new(env: Arc<dyn Env>, comparator: Arc<dyn Comparator>) -> TableOptions {
+        TableOptions {
+            comparator,
+            compression: Compression::None,
+            block_cache: None,
+            env,
+        }
+    }
+}
+
+pub struct SSTable {
+    path: PathBuf,
+    file_number: u64,
+    file: Box<dyn RandomAccessFile>,
+    comparator: Arc<dyn Comparator>,
+    compression: Compression,
+    block_cache: Option<Arc<BlockCache>>,
+    index: Vec<IndexEntry>,
+    prefix_extractor_name: Option<String>,
+    prefixes: Vec<Vec<u8>>,
+    min_key: Vec<u8>,
+    max_key: Vec<u8>,
+    entries: u64,
+    max_timestamp: u128,
+}
+
+//Tables are named after the file number the MANIFEST handed out for them
+pub fn table_path(dir: &Path, file_number: u64) -> PathBuf {
+    dir.join(format!("{:06}.sst", file_number))
+}
+
+impl SSTable {
+    //Write records, which have to be sorted by key with the comparator and then newest first, to a new table at path
+    pub fn write<'a>(
+        path: &Path,
+        records: impl IntoIterator<Item = &'a Record>,
+        prefix_extractor: Option<&dyn PrefixExtractor>,
+        options: &TableOptions,
+    ) -> io::Result<()> {
+        let compression = options.compression;
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut block: Vec<u8> = Vec::new();
+        let mut index: Vec<IndexEntry> = Vec::new();
+        let mut prefixes: Vec<Vec<u8>> = Vec::new();
+        let mut min_key: Option<Vec<u8>> = None;
+        let mut last_key: Option<Vec<u8>> = None;
+        let mut entries = 0u64;
+        let mut max_timestamp = 0u128;
+
+        for record in records {
+            //Close the block on a key boundary once it is big enough, keys the comparator calls equal are one key
+            if let Some(last) = last_key.as_ref() {
+                if options.comparator.compare(last, &record.key) != Ordering::Equal && block.len() >= BLOCK_SIZE {
+                    index.push(finish_block(&mut buffer, &mut block, last, compression));
+                }
+            }
+            if let Some(extractor) = prefix_extractor {
+                if extractor.in_domain(&record.key) {
+                    let prefix = extractor.transform(&record.key);
+                    if prefixes.last().map(|p| p.as_slice()) != Some(prefix) {
+                        prefixes.push(prefix.to_vec());
+                    }
+                }
+            }
+            encode_record(&mut block, record);
+            if min_key.is_none() {
+                min_key = Some(record.key.clone());
+            }
+            last_key = Some(record.key.clone());
+            entries += 1;
+            max_timestamp = max_timestamp.max(record.timestamp);
+        }
+        if let Some(last) = last_key.as_ref() {
+            index.push(finish_block(&mut buffer, &mut block, last, compression));
+        }
+
+        let index_offset = buffer.len();
+        put_u64(&mut buffer, index.len() as u64);
+        for entry in index.iter() {
+            put_bytes(&mut buffer, &entry.last_key);
+            put_u64(&mut buffer, entry.offset);
+            put_u64(&mut buffer, entry.size);
+        }
+
+        //Prefixes come out of sorted keys, but two prefixes can still be out of order if one is a prefix of the other
+        prefixes.sort();
+        prefixes.dedup();
+        let filter_offset = buffer.len();
+        match prefix_extractor {
+            Some(extractor) => {
+                buffer.push(1);
+                put_bytes(&mut buffer, extractor.name().as_bytes());
+            }
+            None => buffer.push(0),
+        }
+        put_u64(&mut buffer, prefixes.len() as u64);
+        for prefix in prefixes.iter() {
+            put_bytes(&mut buffer, prefix);
+        }
+
+        let properties_offset = buffer.len();
+        put_bytes(&mut buffer, min_key.as_deref().unwrap_or_default());
+        put_bytes(&mut buffer, last_key.as_deref().unwrap_or_default());
+        put_u64(&mut buffer, entries);
+        buffer.extend_from_slice(&max_timestamp.to_le_bytes());
+        put_bytes(&mut buffer, options.comparator.name().as_bytes());
+        buffer.push(compression.id());
+
+        let footer_offset = buffer.len();
+        for (offset, end) in [
+            (index_offset, filter_offset),
+            (filter_offset, properties_offset),
+            (properties_offset, footer_offset),
+        ] {
+            put_u64(&mut buffer, offset as u64);
+            put_u64(&mut buffer, (end - offset) as u64);
+        }
+        buffer.extend_from_slice(MAGIC);
+
+        let mut file = options.env.create_file(path)?;
+        file.write_all(&buffer)?;
+        file.sync()?;
+        //The table has to stay in the directory before a MANIFEST can list it
+        options.env.sync_dir(path.parent().unwrap())
+    }
+
+    //Open a table and read its index, filter and properties into memory, data blocks are read when needed
+    //The table is read with the compression it was written with, whatever options.compression is
+    pub fn open(path: &Path, file_number: u64, options: &TableOptions) -> io::Result<SSTable> {
+        let comparator = options.comparator.clone();
+        let file = options.env.open_random_access(path)?;
+        let len = file.size()? as usize;
+        if len < FOOTER_SIZE {
+            return Err(corrupt(path, "file is shorter than the footer"));
+        }
+        let mut footer = vec![0; FOOTER_SIZE];
+        file.read_exact_at(&mut footer, (len - FOOTER_SIZE) as u64)?;
+        if &footer[48..] != MAGIC {
+            return Err(corrupt(path, "bad magic number"));
+        }
+        let mut footer_reader = BlockReader::new(&footer);
+        let mut sections = Vec::new();
+        for _ in 0..3 {
+            let offset = footer_reader.u64().unwrap();
+            let size = footer_reader.u64().unwrap();
+            if offset.checked_add(size).is_none_or(|end| end > len as u64) {
+                return Err(corrupt(path, "footer points past the end of the file"));
+            }
+            let mut section = vec![0; size as usize];
+            file.read_exact_at(&mut section, offset)?;
+            sections.push(section);
+        }
+
+        let mut reader = BlockReader::new(&sections[0]);
+        let count = reader.u64().ok_or_else(|| corrupt(path, "bad index block"))?;
+        let mut index = Vec::new();
+        for _ in 0..count {
+            let entry = (|| {
+                Some(IndexEntry {
+                    last_key: reader.bytes()?,
+                    offset: reader.u64()?,
+                    size: reader.u64()?,
+                })
+            })();
+            let entry = entry.ok_or_else(|| corrupt(path, "bad index block"))?;
+            if entry.offset.checked_add(entry.size).is_none_or(|end| end > len as u64) {
+                return Err(corrupt(path, "index points past the end of the file"));
+            }
+            index.push(entry);
+        }
+
+        let mut reader = BlockReader::new(&sections[1]);
+        let filter = (|| {
+            let prefix_extractor_name = match reader.u8()? {
+                0 => None,
+                _ => Some(String::from_utf8_lossy(&reader.bytes()?).into_owned()),
+            };
+            let mut prefixes = Vec::new();
+            for _ in 0..reader.u64()? {
+                prefixes.push(reader.bytes()?);
+            }
+            Some((prefix_extractor_name, prefixes))
+        })();
+        let (prefix_extractor_name, prefixes) = filter.ok_or_else(|| corrupt(path, "bad filter block"))?;
+
+        let mut reader = BlockReader::new(&sections[2]);
+        let properties = (|| Some((reader.bytes()?, reader.bytes()?, reader.u64()?, reader.u128()?, reader.bytes()?, reader.u8()?)))();
+        let (min_key, max_key, entries, max_timestamp, comparator_name, compression) =
+            properties.ok_or_else(|| corrupt(path, "bad properties block"))?;
+        let compression = Compression::from_id(compression).ok_or_else(|| corrupt(path, "unknown compression"))?;
+        if comparator_name != comparator.name().as_bytes() {
+            return Err(corrupt(path, &format!(
+                "sorted with {} but opened with {}",
+                String::from_utf8_lossy(&comparator_name),
+                comparator.name()
+            )));
+        }
+
+        Ok(SSTable {
+            path: path.to_owned(),
+            file_number,
+            file,
+            comparator,
+            compression,
+            block_cache: options.block_cache.clone(),
+            index,
+            prefix_extractor_name,
+            prefixes,
+            min_key,
+            max_key,
+            entries,
+            max_timestamp,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn file_number(&self) -> u64 {
+        self.file_number
+    }
+
+    #[cfg(test)]
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    pub fn max_timestamp(&self) -> u128 {
+        self.max_timestamp
+    }
+
+    //Could the key be in this table going by its key range
+    pub fn may_contain_key(&self, key: &[u8]) -> bool {
+        self.entries > 0
+            && self.comparator.compare(key, &self.min_key) != Ordering::Less
+            && self.comparator.compare(key, &self.max_key) != Ordering::Greater
+    }
+
+    //Could a key starting with prefix be in this table, going by the key range and the prefix filter
+    //The key range only helps when the comparator keeps prefixes together
+    //The filter only helps when the table was built with the same extractor and prefix is a whole extracted prefix
+    pub fn may_contain_prefix(&self, prefix: &[u8], extractor: Option<&dyn PrefixExtractor>) -> bool {
+        if self.entries == 0 {
+            return false;
+        }
+        if self.comparator.groups_prefixes() {
+            if self.comparator.compare(&self.max_key, prefix) == Ordering::Less {
+                return false;
+            }
+            if self.comparator.compare(&self.min_key, prefix) == Ordering::Greater && !self.min_key.starts_with(prefix) {
+                return false;
+            }
+        }
+        match (extractor, self.prefix_extractor_name.as_deref()) {
+            (Some(extractor), Some(name))
+                if extractor.name() == name
+                    && extractor.in_domain(prefix)
+                    && extractor.transform(prefix) == prefix =>
+            {
+                self.prefixes.binary_search_by(|p| p.as_slice().cmp(prefix)).is_ok()
+            }
+            _ => true,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    //Every version of the key in this table, newest first
+    pub fn versions(&self, key: &[u8]) -> io::Result<Vec<Record>> {
+        Ok(self.multi_get(&[key], true)?.pop().unwrap_or_default())
+    }
+
+    //Versions of each key, keys have to be sorted
+    //Keys that land in the same data block share a single read of that block
+    //The blocks read are only kept in the block cache when fill_cache is set
+    pub fn multi_get(&self, sorted_keys: &[&[u8]], fill_cache: bool) -> io::Result<Vec<Vec<Record>>> {
+        let mut results: Vec<Vec<Record>> = Vec::with_capacity(sorted_keys.len());
+        let mut block: Option<(usize, Arc<Vec<Record>>)> = None;
+        for key in sorted_keys {
+            if !self.may_contain_key(key) {
+                results.push(Vec::new());
+                continue;
+            }
+            let block_idx = self.index.partition_point(|e| self.is_before(&e.last_key, key));
+            if block_idx == self.index.len() {
+                results.push(Vec::new());
+                continue;
+            }
+            if block.as_ref().map(|(idx, _)| *idx) != Some(block_idx) {
+                block = Some((block_idx, self.read_block(block_idx, fill_cache)?));
+            }
+            let records = &block.as_ref().unwrap().1;
+            let start = records.partition_point(|r| self.is_before(&r.key, key));
+            let same_key = |r: &&Record| self.comparator.compare(&r.key, key) == Ordering::Equal;
+            results.push(records[start..].iter().take_while(same_key).cloned().collect());
+        }
+        Ok(results)
+    }
+
+    //Every version of every key starting with prefix, sorted like the table
+    //Without a comparator that keeps prefixes together every block is read
+    pub fn scan_prefix(&self, prefix: &[u8], fill_cache: bool) -> io::Result<Vec<Record>> {
+        let mut records = Vec::new();
+        let grouped = self.comparator.groups_prefixes();
+        let first = if grouped { self.index.partition_point(|e| self.is_before(&e.last_key, prefix)) } else { 0 };
+        for block_idx in first..self.index.len() {
+            let block = self.read_block(block_idx, fill_cache)?;
+            let mut past_prefix = false;
+            for record in block.iter() {
+                if record.key.starts_with(prefix) {
+                    records.push(record.clone());
+                } else if grouped && self.comparator.compare(&record.key, prefix) == Ordering::Greater {
+                    past_prefix = true;
+                    break;
+                }
+            }
+            if past_prefix {
+                break;
+            }
+        }
+        Ok(records)
+    }
+
+    //Every record in the table, used by compaction so the blocks are not cached
+    pub fn records(&self) -> io::Result<Vec<Record>> {
+        let mut records = Vec::with_capacity(self.entries as usize);
+        for block_idx in 0..self.index.len() {
+            records.extend(self.read_block(block_idx, false)?.iter().cloned());
+        }
+        Ok(records)
+    }
+
+    fn is_before(&self, key: &[u8], other: &[u8]) -> bool {
+        self.comparator.compare(key, other) == Ordering::Less
+    }
+
+    //Data block from the block cache, or read from the file and decoded
+    fn read_block(&self, block_idx: usize, fill_cache: bool) -> io::Result<Arc<Vec<Record>>> {
+        let entry = &self.index[block_idx];
+        let cache = self.block_cache.as_deref();
+        if let Some(records) = cache.and_then(|cache| cache.get(self.file_number, entry.offset)) {
+            return Ok(records);
+        }
+        let mut buffer = vec![0; entry.size as usize];
+        self.file.read_exact_at(&mut buffer, entry.offset)?;
+        let buffer = self
+            .compression
+            .decompress(&buffer)
+            .ok_or_else(|| corrupt(&self.path, "bad compressed block"))?;
+        let mut reader = BlockReader::new(&buffer);
+        let mut records = Vec::new();
+        while !reader.is_empty() {
+            records.push(decode_record(&mut reader).ok_or_else(|| corrupt(&self.path, "bad data block"))?);
+        }
+        let records = Arc::new(records);
+        if let Some(cache) = cache.filter(|_| fill_cache) {
+            cache.insert(self.file_number, entry.offset, records.clone(), buffer.len());
+        }
+        Ok(records)
+    }
+}
+
+//Compress the block onto the end of the file buffer and start the next one
+fn finish_block(buffer: &mut Vec<u8>, block: &mut Vec<u8>, last_key: &[u8], compression: Compression) -> IndexEntry {
+    let compressed = compression.compress(block);
+    let entry = IndexEntry {
+        last_key: last_key.to_vec(),
+        offset: buffer.len() as u64,
+        size: compressed.len() as u64,
+    };
+    buffer.extend_from_slice(&compressed);
+    block.clear();
+    entry
+}
+
+fn corrupt(path: &Path, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt table {}: {}", path.display(), reason),
+    )
+}
+
+fn put_u64(buffer: &mut Vec<u8>, value: u64) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    put_u64(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+fn encode_record(buffer: &mut Vec<u8>, record: &Record) {
+    let kind = if record.deleted {
+        KIND_DELETE
+    } else if record.merge {
+        KIND_MERGE
+    } else if record.expires_at.is_some() {
+        KIND_SET_WITH_TTL
+    } else {
+        KIND_SET
+    };
+    put_u64(buffer, record.key.len() as u64);
+    buffer.push(kind);
+    if let Some(value) = record.value.as_ref() {
+        put_u64(buffer, value.len() as u64);
+    }
+    buffer.extend_from_slice(&record.key);
+    if let Some(value) = record.value.as_ref() {
+        buffer.extend_from_slice(value);
+    }
+    buffer.extend_from_slice(&record.timestamp.to_le_bytes());
+    if let Some(expires_at) = record.expires_at {
+        buffer.extend_from_slice(&expires_at.to_le_bytes());
+    }
+}
+
+fn decode_record(reader: &mut BlockReader) -> Option<Record> {
+    let key_len = reader.u64()? as usize;
+    let kind = reader.u8()?;
+    let value_len = match kind {
+        KIND_DELETE => None,
+        KIND_SET | KIND_SET_WITH_TTL | KIND_MERGE => Some(reader.u64()? as usize),
+        _ => return None,
+    };
+    let key = reader.take(key_len)?.to_vec();
+    let value = match value_len {
+        Some(len) => Some(reader.take(len)?.to_vec()),
+        None => None,
+    };
+    let timestamp = reader.u128()?;
+    let expires_at = match kind {
+        KIND_SET_WITH_TTL => Some(reader.u128()?),
+        _ => None,
+    };
+    Some(Record {
+        key,
+        value,
+        timestamp,
+        deleted: kind == KIND_DELETE,
+        expires_at,
+        merge: kind == KIND_MERGE,
+    })
+}
+
+//Reads the little endian fields back out of a block, None once the block runs out
+struct BlockReader<'a> {
+    buffer: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BlockReader<'a> {
+    fn new(buffer: &'a [u8]) -> BlockReader<'a> {
+        BlockReader { buffer, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buffer.len()
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(len)?;
+        let bytes = self.buffer.get(self.pos..end)?;
+        self.pos = end;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn u128(&mut self) -> Option<u128> {
+        Some(u128::from_le_bytes(self.take(16)?.try_into().ok()?))
+    }
+
+    fn bytes(&mut self) -> Option<Vec<u8>> {
+        let len = self.u64()? as usize;
+        Some(self.take(len)?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block_cache::BlockCache;
+    use crate::comparator::{BytewiseComparator, NumericComparator};
+    use crate::compression::Compression;
+    use crate::env::{Env, MemEnv};
+    use crate::mem_table::MemTable;
+    use crate::prefix_extractor::{DelimitedPrefix, FixedPrefix, PrefixExtractor};
+    use crate::sstable::{SSTable, TableOptions};
+    use std::io::{ErrorKind, Read, Write};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_write_and_read() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+
+        let mut table = MemTable::new();
+        for i in 0..2000u32 {
+            table.set(format!("key/{:05}", i).as_bytes(), &i.to_le_bytes(), i as u128);
+        }
+        table.delete(b"key/00010", 5000);
+        table.set_with_expiry(b"key/00020", b"ttl", 5001, Some(9000));
+        table.merge(b"merge", b"operand", 5002);
+
+        let path = Path::new("000001.sst");
+        let extractor = DelimitedPrefix::new(b'/', 1);
+        let options = TableOptions::new(env.clone(), Arc::new(BytewiseComparator));
+        SSTable::write(path, table.entries(), Some(&extractor), &options).unwrap();
+        let sstable = SSTable::open(path, 1, &options).unwrap();
+        assert_eq!(sstable.entries(), table.len() as u64);
+        assert_eq!(sstable.max_timestamp(), 5002);
+        assert!(sstable.index.len() > 1);
+
+        let versions = sstable.versions(b"key/01500").unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].value.as_ref().unwrap(), &1500u32.to_le_bytes());
+        assert!(sstable.versions(b"key/00010").unwrap()[0].deleted);
+        assert_eq!(sstable.versions(b"key/00020").unwrap()[0].expires_at, Some(9000));
+        assert!(sstable.versions(b"merge").unwrap()[0].merge);
+        assert!(sstable.versions(b"key/99999").unwrap().is_empty());
+        assert!(sstable.versions(b"aaa").unwrap().is_empty());
+
+        let keys: Vec<&[u8]> = vec![b"key/00001", b"key/00002", b"key/01999", b"nope"];
+        let found = sstable.multi_get(&keys, true).unwrap();
+        assert_eq!(found.iter().map(|v| v.len()).collect::<Vec<_>>(), vec![1, 1, 1, 0]);
+
+        assert_eq!(sstable.scan_prefix(b"key/001", true).unwrap().len(), 100);
+        assert_eq!(sstable.records().unwrap().len(), table.len());
+
+        //The prefix filter only has key/ and merge is outside the extractor's domain
+        let extractor: &dyn PrefixExtractor = &extractor;
+        assert!(sstable.may_contain_prefix(b"key/", Some(extractor)));
+        assert!(!sstable.may_contain_prefix(b"kez/", Some(extractor)));
+        assert!(!sstable.may_contain_prefix(b"zzz", Some(extractor)));
+        //A different extractor cannot use the filter
+        assert!(sstable.may_contain_prefix(b"kez/", Some(&FixedPrefix::new(4))));
+
+        //A table sorted one way cannot be opened to search it another way
+        assert!(SSTable::open(path, 1, &TableOptions::new(env, Arc::new(NumericComparator))).is_err());
+    }
+
+    #[test]
+    fn test_compression_and_block_cache() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+
+        let mut table = MemTable::new();
+        for i in 0..2000u32 {
+            table.set(format!("key/{:05}", i).as_bytes(), b"Badri Krishnan Badri Krishnan", i as u128);
+        }
+        let plain_path = Path::new("000001.sst");
+        let options = TableOptions::new(env.clone(), Arc::new(BytewiseComparator));
+        SSTable::write(plain_path, table.entries(), None, &options).unwrap();
+
+        let cache = Arc::new(BlockCache::new(1024 * 1024));
+        let options = TableOptions {
+            compression: Compression::Lz,
+            block_cache: Some(cache.clone()),
+            ..options
+        };
+        let path = Path::new("000002.sst");
+        SSTable::write(path, table.entries(), None, &options).unwrap();
+        assert!(env.file_size(path).unwrap() < env.file_size(plain_path).unwrap() / 2);
+
+        //The table keeps the compression it was written with
+        let sstable = SSTable::open(path, 2, &TableOptions::new(env.clone(), Arc::new(BytewiseComparator))).unwrap();
+        assert_eq!(sstable.compression(), Compression::Lz);
+        let sstable = SSTable::open(path, 2, &options).unwrap();
+        assert_eq!(sstable.records().unwrap().len(), 2000);
+        assert_eq!(cache.usage(), 0);
+
+        assert_eq!(sstable.scan_prefix(b"key/01", false).unwrap().len(), 1000);
+        assert_eq!(cache.usage(), 0);
+        assert_eq!(sstable.versions(b"key/01234").unwrap()[0].value.as_deref(), Some(b"Badri Krishnan Badri Krishnan".as_slice()));
+        let usage = cache.usage();
+        assert!(usage > 0);
+        //A second read of the same block comes from the cache
+        assert_eq!(sstable.versions(b"key/01234").unwrap().len(), 1);
+        assert_eq!(cache.usage(), usage);
+    }
+
+    #[test]
+    fn test_corrupt_footer() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let options = TableOptions::new(env.clone(), Arc::new(BytewiseComparator));
+        let mut table = MemTable::new();
+        table.set(b"Badri", b"Krishnan", 1);
+        let path = Path::new("000001.sst");
+        SSTable::write(path, table.entries(), None, &options).unwrap();
+        let mut data = Vec::new();
+        env.open_file(path).unwrap().read_to_end(&mut data).unwrap();
+
+        //An offset and a size that add up past u64::MAX, then a size past the end of the file
+        let footer = data.len() - 56;
+        for (offset, size) in [(u64::MAX - 8, 16), (0, data.len() as u64)] {
+            let mut corrupted = data.clone();
+            corrupted[footer..footer + 8].copy_from_slice(&offset.to_le_bytes());
+            corrupted[footer + 8..footer + 16].copy_from_slice(&size.to_le_bytes());
+            env.create_file(path).unwrap().write_all(&corrupted).unwrap();
+            let err = SSTable::open(path, 1, &options).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+}
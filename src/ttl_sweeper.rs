@@ -0,0 +1,37 @@
+//TTL Sweeper - background job that deletes expired keys
+
+/*
+Expired values are already hidden from reads, the sweeper writes real tombstones for them so they
+stop taking up space. It only holds a Weak reference to the Database so it never keeps the Database
+alive, and it stops as soon as the Database is gone or the sweeper handle is dropped.
+A sweep that fails is handed to Options::on_background_error and tried again on the next interval.
+*/
+
+use std::sync::Weak;
+use std::time::Duration;
+
+use crate::database::Database;
+use crate::scheduler::{ScheduledJob, Scheduler};
+
+pub struct TtlSweeper {
+    _job: Box<dyn ScheduledJob>,
+}
+
+impl TtlSweeper {
+    pub fn start(db: Weak<Database>, interval: Duration, scheduler: &dyn Scheduler) -> TtlSweeper {
+        let job = scheduler.every(
+            "lanadb-ttl-sweeper",
+            interval,
+            Box::new(move || {
+                let Some(db) = db.upgrade() else {
+                    return false;
+                };
+                if let Err(err) = db.sweep_expired() {
+                    db.report_background_error(&err);
+                }
+                true
+            }),
+        );
+        TtlSweeper { _job: job }
+    }
+}
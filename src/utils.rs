@@ -1,14 +1,52 @@
 use std::path::{Path,PathBuf};
-use std::fs::{read_dir};
+use std::io;
+
+use crate::env::Env;
 
 //Get a List of files for a particular path and extend
-pub fn files_with_ext(dir: &Path, ext: &str) -> Vec<PathBuf> {
+pub fn files_with_ext(env: &dyn Env, dir: &Path, ext: &str) -> io::Result<Vec<PathBuf>> {
     let mut files = Vec::new();
-    for file in read_dir(dir).unwrap() {
-      let path = file.unwrap().path();
-      if path.extension().unwrap() == ext {
+    for path in env.list_dir(dir)? {
+      if path.extension().is_some_and(|e| e == ext) {
         files.push(path);
       }
     }
-    files
-}
\ No newline at end of file
+    Ok(files)
+}
+//CRC-32 (IEEE) lookup table, built at compile time
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB88320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+//Continue the CRC-32 of everything before bytes, start with 0
+pub fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in bytes {
+        crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::crc32_update;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32_update(0, b""), 0);
+        assert_eq!(crc32_update(0, b"123456789"), 0xCBF43926);
+        assert_eq!(crc32_update(crc32_update(0, b"1234"), b"56789"), 0xCBF43926);
+    }
+}
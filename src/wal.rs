@@ -16,80 +16,221 @@ Timestamp = Timestamp of the operation in microseconds
 
 Each entry in DB will have one WAL record buffer associated to it
 
+The Tombstone byte is the kind of the record, 0 is a set and 1 is a delete. Deletes have no Value Size and no Value.
+Kind 2 starts an atomic batch, the Key Size holds how many records follow and it only carries a Timestamp
+
++-----------------+-----------+-----------------+----------...----------+
+| Count (8B)      | Kind(1B)  | Timestamp (16B) | Count batch records   |
++-----------------+-----------+-----------------+----------...----------+
+
+If the log ends before every record of a batch is read back, the whole batch is thrown away on recovery
+A size that reaches past the end of the log is read as the log ending there, the replay stops at that record
+
+Two-phase commit adds three kinds. Kind 3 prepares a transaction under a name and holds its write set
+
++-----------------+-----------+------------+--------...-------+-----------------+---------...---------+
+| Name Size (8B)  | Kind(1B)  | Count (8B) | Name(Variable)   | Timestamp (16B) | Count write records |
++-----------------+-----------+------------+--------...-------+-----------------+---------...---------+
+
+Kind 4 commits and kind 5 rolls back the prepared transaction with that name, they are laid out like a delete
+with the name in place of the key. A commit applies the write set at the commit record's timestamp.
+Recovery keeps the transactions that were prepared but never committed or rolled back.
+
+Kind 6 is a set with a time to live, laid out like a set with the expiration time after the Timestamp
+
++---------------+-----------+-----------------+-...-+--...--+-----------------+------------------+
+| Key Size (8B) | Kind(1B)  | Value Size (8B) | Key | Value | Timestamp (16B) | Expires At (16B) |
++---------------+-----------+-----------------+-...-+--...--+-----------------+------------------+
+Expires At = Time in microseconds from which the key reads as missing
+
+Kind 7 is a merge operand, laid out like a set with the operand in place of the Value
+
+Records outside the default column family have the high bit of the kind set and the id of their column family
+right after it, the rest of the record does not change
+
++---------------+-----------------+-----------------------+-----...-----+
+| Key Size (8B) | Kind | 0x80(1B) | Column Family Id (4B) | rest as is  |
++---------------+-----------------+-----------------------+-----...-----+
+
+A WAL is named after the time it was created in microseconds, replay goes through them in that order.
+When the clock is behind the newest WAL or the oldest_wal the MANIFEST keeps, the name is taken past both.
+Opening rewrites what it replayed into a new WAL and moves oldest_wal to it before the old ones are deleted.
+Timestamps only go up from one WAL to the next, so a record no newer than one an older WAL held is a copy
+left by an open that crashed before that, it is not replayed twice.
+
 */
 
-use std::fs::{File,OpenOptions,remove_file};
+use std::collections::BTreeMap;
 use std::io::prelude::*;
 use std::io::{self, BufWriter};
 use std::path::{Path,PathBuf};
-use std::time::{SystemTime,UNIX_EPOCH};
+use std::sync::Arc;
 
-use crate::mem_table::MemTable;
+use crate::clock::Clock;
+use crate::comparator::Comparator;
+use crate::env::{Env, WritableFile};
+use crate::manifest::Manifest;
+use crate::mem_table::{HistoryRetention, MemTable};
 use crate::utils::files_with_ext;
-use crate::wal_iterator::{WALRecordIterator, WALRecord};
+use crate::wal_iterator::{WALEntry, WALRecordIterator, WALRecord};
+
+pub const KIND_SET: u8 = 0;
+pub const KIND_DELETE: u8 = 1;
+pub const KIND_BATCH: u8 = 2;
+pub const KIND_PREPARE: u8 = 3;
+pub const KIND_COMMIT_PREPARED: u8 = 4;
+pub const KIND_ROLLBACK_PREPARED: u8 = 5;
+pub const KIND_SET_WITH_TTL: u8 = 6;
+pub const KIND_MERGE: u8 = 7;
+pub const COLUMN_FAMILY_FLAG: u8 = 0x80;
+
+//Transaction prepared for two-phase commit whose decision has not been logged yet
+pub struct PreparedTransaction {
+    pub name: Vec<u8>,
+    pub records: Vec<WALRecord>,
+    pub timestamp: u128,
+}
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct WAL{
     wal_path: PathBuf,
-    wal_file: BufWriter<File>,
+    wal_file: BufWriter<Box<dyn WritableFile>>,
+    env: Arc<dyn Env>,
 }
 
 impl WAL{
-    //Create New WAL
-    pub fn new(dir: &Path) -> io::Result<WAL>{
-        let timestamp = SystemTime::now().
-            duration_since(UNIX_EPOCH).
-            unwrap().as_micros();
-        let wal_path = Path::new(dir).join(timestamp.to_string()+".wal");
-        let wal_file = OpenOptions::new().append(true).create(true).open(&wal_path)?;
-        let wal_file = BufWriter::new(wal_file);
-
-        Ok(WAL{wal_path, wal_file})
-    }
-
-    //Create WAL from path
-    pub fn from_path(path: &Path) -> io::Result<WAL>{
-        let wal_file = OpenOptions::new().append(true).create(true).open(&path)?;
-        let wal_file = BufWriter::new(wal_file);
-        let wal_path = path.to_owned();
-        Ok(WAL{
-            wal_path, 
-            wal_file
-        })
+    //Create New WAL, named after the time on clock
+    //The number has to come after every WAL in dir and after oldest_wal even when the clock is behind them,
+    //a WAL numbered below oldest_wal is not replayed and one rolled over within the same microsecond must
+    //not append to the one before it
+    pub fn new(env: &Arc<dyn Env>, clock: &dyn Clock, dir: &Path, oldest_wal: u128) -> io::Result<WAL>{
+        let newest = Self::wal_files(env.as_ref(), dir)?.iter().map(|path| wal_number(path).saturating_add(1)).max().unwrap_or(0);
+        let number = clock.now_micros().max(oldest_wal.saturating_add(1)).max(newest);
+        let wal_path = Path::new(dir).join(number.to_string()+".wal");
+        let wal_file = BufWriter::new(env.append_file(&wal_path)?);
+        //A WAL that is not in the directory after a power loss takes every write logged to it along
+        env.sync_dir(dir)?;
+
+        Ok(WAL{wal_path, wal_file, env: env.clone()})
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.wal_path
+    }
+
+    //Number of the WAL, the time it was created at, a WAL created after it has a higher one
+    pub fn number(&self) -> u128 {
+        wal_number(&self.wal_path)
     }
     
     //Set Records in the WAL
+    #[cfg(test)]
     pub fn set(&mut self, key:&[u8], value:&[u8], timestamp:u128) ->io::Result<()>{
+        self.write_entry(0, KIND_SET, key, Some(value), timestamp, None)
+    }
+    
+    //Set Record that expires at expires_at in the WAL
+    #[cfg(test)]
+    pub fn set_with_ttl(&mut self, key:&[u8], value:&[u8], timestamp:u128, expires_at:u128) ->io::Result<()>{
+        self.write_entry(0, KIND_SET_WITH_TTL, key, Some(value), timestamp, Some(expires_at))
+    }
+
+    //Merge operand Record in the WAL
+    #[cfg(test)]
+    pub fn merge(&mut self, key:&[u8], operand:&[u8], timestamp:u128) ->io::Result<()>{
+        self.write_entry(0, KIND_MERGE, key, Some(operand), timestamp, None)
+    }
+
+    //Delete Record in the WAL
+    #[cfg(test)]
+    pub fn delete(&mut self, key:&[u8], timestamp:u128) -> io::Result<()>{
+        self.write_entry(0, KIND_DELETE, key, None, timestamp, None)
+    }
+
+    //Write a record of any kind, for any column family
+    pub fn write_record(&mut self, record: &WALRecord) -> io::Result<()>{
+        let kind = match (record.value.as_ref(), record.expires_at) {
+            (Some(_), _) if record.merge => KIND_MERGE,
+            (Some(_), Some(_)) if !record.deleted => KIND_SET_WITH_TTL,
+            (Some(_), None) if !record.deleted => KIND_SET,
+            _ => KIND_DELETE,
+        };
+        let value = if kind == KIND_DELETE { None } else { record.value.as_deref() };
+        let expires_at = if kind == KIND_SET_WITH_TTL { record.expires_at } else { None };
+        self.write_entry(record.column_family, kind, &record.key, value, record.timestamp, expires_at)
+    }
+
+    fn write_entry(&mut self, column_family: u32, kind: u8, key:&[u8], value: Option<&[u8]>, timestamp:u128, expires_at: Option<u128>) -> io::Result<()>{
         //Key size write buffer
         self.wal_file.write_all(&key.len().to_le_bytes())?;
-        
-        //tombstone write buffer
-        let tombstone = false as u8;
-        self.wal_file.write_all(&tombstone.to_le_bytes())?;
-        
-        //value size write buffer
-        self.wal_file.write_all(&value.len().to_le_bytes())?;
+
+        //kind write buffer, flagged when the column family id follows
+        if column_family == 0 {
+            self.wal_file.write_all(&kind.to_le_bytes())?;
+        } else {
+            self.wal_file.write_all(&(kind | COLUMN_FAMILY_FLAG).to_le_bytes())?;
+            self.wal_file.write_all(&column_family.to_le_bytes())?;
+        }
+
+        //value size write buffer, deletes have no value
+        if let Some(value) = value {
+            self.wal_file.write_all(&value.len().to_le_bytes())?;
+        }
 
         //Write the Key & Value & TimeStamp
         self.wal_file.write_all(key)?;
-        self.wal_file.write_all(value)?;
+        if let Some(value) = value {
+            self.wal_file.write_all(value)?;
+        }
         self.wal_file.write_all(&timestamp.to_le_bytes())?;
+        if let Some(expires_at) = expires_at {
+            self.wal_file.write_all(&expires_at.to_le_bytes())?;
+        }
         Ok(())
+    }
 
+    //Write records that have to be recovered all together or not at all
+    pub fn batch(&mut self, records: &[WALRecord], timestamp:u128) -> io::Result<()>{
+        //Count, kind & timestamp header
+        self.wal_file.write_all(&records.len().to_le_bytes())?;
+        self.wal_file.write_all(&KIND_BATCH.to_le_bytes())?;
+        self.wal_file.write_all(&timestamp.to_le_bytes())?;
+        self.write_records(records)
     }
-    
-    //Delete Record in the WAL
-    pub fn delete(&mut self, key:&[u8], timestamp:u128) -> io::Result<()>{
-        //Key size write buffer
-        self.wal_file.write_all(&key.len().to_le_bytes())?;
-        
-        //tombstone write buffer - deleted = true
-        let tombstone = true as u8;
-        self.wal_file.write_all(&tombstone.to_le_bytes())?;
 
-        //Key & timestamp write only
-        self.wal_file.write_all(key)?;
+    //Write the PREPARE marker and write set of a two-phase commit transaction
+    pub fn prepare(&mut self, name:&[u8], records: &[WALRecord], timestamp:u128) -> io::Result<()>{
+        //Name size, kind & write set count
+        self.wal_file.write_all(&name.len().to_le_bytes())?;
+        self.wal_file.write_all(&KIND_PREPARE.to_le_bytes())?;
+        self.wal_file.write_all(&records.len().to_le_bytes())?;
+
+        self.wal_file.write_all(name)?;
         self.wal_file.write_all(&timestamp.to_le_bytes())?;
+        self.write_records(records)
+    }
+
+    //Decide a prepared transaction, commit applies its write set at timestamp
+    pub fn commit_prepared(&mut self, name:&[u8], timestamp:u128) -> io::Result<()>{
+        self.decide_prepared(KIND_COMMIT_PREPARED, name, timestamp)
+    }
+
+    pub fn rollback_prepared(&mut self, name:&[u8], timestamp:u128) -> io::Result<()>{
+        self.decide_prepared(KIND_ROLLBACK_PREPARED, name, timestamp)
+    }
 
+    fn decide_prepared(&mut self, kind: u8, name:&[u8], timestamp:u128) -> io::Result<()>{
+        self.wal_file.write_all(&name.len().to_le_bytes())?;
+        self.wal_file.write_all(&kind.to_le_bytes())?;
+        self.wal_file.write_all(name)?;
+        self.wal_file.write_all(&timestamp.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn write_records(&mut self, records: &[WALRecord]) -> io::Result<()>{
+        for record in records.iter(){
+            self.write_record(record)?;
+        }
         Ok(())
     }
 
@@ -97,71 +238,183 @@ impl WAL{
         self.wal_file.flush()
     }
 
-    pub fn load_mem_table_from_dir(dir:&Path) -> io::Result<(WAL,MemTable)>{
-        let extension = "wal";
-        
-        let mut wal_files = files_with_ext(dir, extension);
-        
-        //Multiple WAL in path then sort by date
-        wal_files.sort();
+    //Flush and wait until the OS has the WAL on disk
+    pub fn sync(&mut self) -> io::Result<()>{
+        self.wal_file.flush()?;
+        self.wal_file.get_mut().sync()
+    }
+
+    //Replay every WAL in dir into one MemTable per column family id ordered by comparator, rewrite them into one new WAL and delete the old ones
+    //WALs numbered below the manifest's oldest_wal were flushed to tables already, they are deleted without being replayed
+    //Transactions that were prepared but never decided come back so the coordinator can finish them
+    pub fn load_mem_tables_from_dir(env: &Arc<dyn Env>, clock: &dyn Clock, dir:&Path, manifest: &mut Manifest, comparator: &Arc<dyn Comparator>) -> io::Result<(WAL,BTreeMap<u32,MemTable>,Vec<PreparedTransaction>)>{
+        let oldest_wal = manifest.oldest_wal();
+        let wal_files = Self::wal_files(env.as_ref(), dir)?;
+        let mut new_wal = WAL::new(env, clock, dir, oldest_wal)?;
+        let replayed: Vec<PathBuf> = wal_files.iter().filter(|path| wal_number(path) >= oldest_wal).cloned().collect();
+        let (mem_tables, prepared) = Self::replay_files(env.as_ref(), &replayed, comparator, Some(&mut new_wal))?;
+
+        //Undecided transactions stay prepared in the new WAL
+        for transaction in prepared.iter() {
+            new_wal.prepare(&transaction.name, &transaction.records, transaction.timestamp)?;
+        }
+        //The new WAL must be on disk before the old ones are gone
+        new_wal.sync()?;
+        //Once the MANIFEST says so the old WALs are not replayed, a crash before they are deleted would
+        //otherwise replay them along with the new one and apply their merge operands twice
+        manifest.set_oldest_wal(new_wal.number());
+        if let Err(err) = manifest.save() {
+            manifest.set_oldest_wal(oldest_wal);
+            let _ = env.remove_file(new_wal.path());
+            return Err(err);
+        }
+
+        //Delete previous wal files, once that is synced they cannot come back and be replayed again
+        for wal_file in wal_files {
+            env.remove_file(&wal_file)?;
+        }
+        env.sync_dir(dir)?;
+        Ok((new_wal, mem_tables, prepared))
+    }
+
+    //Replay the WALs in dir numbered oldest_wal or above like load_mem_tables_from_dir without creating, writing or deleting any file
+    //A WAL deleted by the Database that owns dir while it is being read is skipped
+    pub fn read_mem_tables_from_dir(env: &Arc<dyn Env>, dir:&Path, oldest_wal: u128, comparator: &Arc<dyn Comparator>) -> io::Result<(BTreeMap<u32,MemTable>,Vec<PreparedTransaction>)>{
+        let wal_files: Vec<PathBuf> = Self::wal_files(env.as_ref(), dir)?.into_iter().filter(|path| wal_number(path) >= oldest_wal).collect();
+        Self::replay_files(env.as_ref(), &wal_files, comparator, None)
+    }
+
+    //Multiple WAL in path then sort by date
+    fn wal_files(env: &dyn Env, dir:&Path) -> io::Result<Vec<PathBuf>>{
+        let mut wal_files = files_with_ext(env, dir, "wal")?;
+        //By number, not by name, 10.wal comes after 9.wal
+        wal_files.sort_by_key(|path| wal_number(path));
+        Ok(wal_files)
+    }
 
-        let mut recovery_mem_table = MemTable::new();
-        let mut new_wal = WAL::new(dir)?;
+    //Replay the WAL files in order, every record applied is also written to new_wal when there is one
+    //Writes are logged in timestamp order, a record no newer than one an older WAL held is a copy a recovery
+    //made before it crashed and is skipped
+    fn replay_files(env: &dyn Env, wal_files: &[PathBuf], comparator: &Arc<dyn Comparator>, mut new_wal: Option<&mut WAL>) -> io::Result<(BTreeMap<u32,MemTable>,Vec<PreparedTransaction>)>{
+        //Replay every version, the Database decides how much history to keep afterwards
+        let mut mem_tables: BTreeMap<u32, MemTable> = BTreeMap::new();
+        let mut prepared: Vec<PreparedTransaction> = Vec::new();
+        let mut newest: Option<u128> = None;
 
         for file in wal_files.iter(){
-            if let Ok(current_wal) = WAL::from_path(file){
-                for wal_record in current_wal.into_iter(){
-                    if wal_record.deleted {
-                        recovery_mem_table.delete(wal_record.key.as_slice(), wal_record.timestamp);
-                        new_wal.delete(wal_record.key.as_slice(), wal_record.timestamp)?;
-                    } 
-                    else{
-                        recovery_mem_table.set(
-                            wal_record.key.as_slice(),
-                            wal_record.value.as_ref().unwrap().as_slice(),
-                            wal_record.timestamp
-                        );
-                        new_wal.set(
-                            wal_record.key.as_slice(),
-                            wal_record.value.unwrap().as_slice(),
-                            wal_record.timestamp
-                        )?;
+            let replayed_through = newest;
+            if let Ok(entries) = WALRecordIterator::new(env, file){
+                for entry in entries{
+                    match entry {
+                        WALEntry::Record(wal_record) => {
+                            if replayed_through.is_some_and(|through| wal_record.timestamp <= through) {
+                                continue;
+                            }
+                            newest = newest.max(Some(wal_record.timestamp));
+                            Self::replay_record(Self::recovery_mem_table(&mut mem_tables, comparator, wal_record.column_family), &wal_record);
+                            if let Some(new_wal) = new_wal.as_deref_mut() {
+                                new_wal.write_record(&wal_record)?;
+                            }
+                        }
+                        WALEntry::Prepare(transaction) => {
+                            prepared.retain(|p| p.name != transaction.name);
+                            prepared.push(transaction);
+                        }
+                        WALEntry::CommitPrepared { name, timestamp } => {
+                            newest = newest.max(Some(timestamp));
+                            if let Some(idx) = prepared.iter().position(|p| p.name == name) {
+                                let mut transaction = prepared.remove(idx);
+                                for record in transaction.records.iter_mut() {
+                                    record.timestamp = timestamp;
+                                    Self::replay_record(Self::recovery_mem_table(&mut mem_tables, comparator, record.column_family), record);
+                                }
+                                if let Some(new_wal) = new_wal.as_deref_mut() {
+                                    new_wal.batch(&transaction.records, timestamp)?;
+                                }
+                            }
+                        }
+                        WALEntry::RollbackPrepared { name } => {
+                            prepared.retain(|p| p.name != name);
+                        }
                     }
                 }
             }
         }
-        //write buffer to file
-        new_wal.flush().unwrap();
-        
-        //Delete previous wal files
-        wal_files.into_iter().for_each(|wf| remove_file(wf).unwrap());
-        Ok((new_wal, recovery_mem_table))
+        Ok((mem_tables, prepared))
+    }
+
+    fn recovery_mem_table<'a>(
+        mem_tables: &'a mut BTreeMap<u32, MemTable>,
+        comparator: &Arc<dyn Comparator>,
+        column_family: u32,
+    ) -> &'a mut MemTable {
+        mem_tables.entry(column_family).or_insert_with(|| {
+            let mut mem_table = MemTable::with_comparator(comparator.clone());
+            mem_table.set_history_retention(HistoryRetention::keep_all());
+            mem_table
+        })
+    }
+
+    //Apply a record read back from the WAL to a MemTable
+    pub fn replay_record(mem_table: &mut MemTable, wal_record: &WALRecord) {
+        match wal_record.value.as_ref() {
+            Some(operand) if wal_record.merge => {
+                mem_table.merge(wal_record.key.as_slice(), operand.as_slice(), wal_record.timestamp)
+            }
+            Some(value) if !wal_record.deleted => mem_table.set_with_expiry(
+                wal_record.key.as_slice(),
+                value.as_slice(),
+                wal_record.timestamp,
+                wal_record.expires_at,
+            ),
+            _ => mem_table.delete(wal_record.key.as_slice(), wal_record.timestamp),
+        }
     }
 }
 
 impl IntoIterator for WAL {
     type IntoIter = WALRecordIterator;
-    type Item = WALRecord;
+    type Item = WALEntry;
   
     /// Converts a WAL into a `WALIterator` to iterate over the entries.
     fn into_iter(self) -> WALRecordIterator {
-        WALRecordIterator::new(self.wal_path).unwrap()
+        WALRecordIterator::new(self.env.as_ref(), &self.wal_path).unwrap()
     }
 }
 
+//The name of a WAL is its number, a file that is named otherwise counts as the oldest
+fn wal_number(path: &Path) -> u128 {
+    path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()).unwrap_or(0)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::wal::{WAL, self};
-    use rand::Rng;
-    use std::fs::{create_dir, remove_dir_all};
-    use std::fs::{metadata, File, OpenOptions};
+    use crate::clock::{Clock, ManualClock, SystemClock};
+    use crate::comparator::{BytewiseComparator, Comparator};
+    use crate::env::{Env, MemEnv};
+    use crate::manifest::Manifest;
+    use crate::wal::WAL;
+    use crate::wal_iterator::{WALEntry, WALRecord};
     use std::io::prelude::*;
     use std::io::BufReader;
-    use std::path::PathBuf;
-    use std::time::{SystemTime, UNIX_EPOCH};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    fn bytewise() -> Arc<dyn Comparator> {
+        Arc::new(BytewiseComparator)
+    }
+
+    fn mem_env() -> Arc<dyn Env> {
+        Arc::new(MemEnv::new())
+    }
+
+    //A new MANIFEST in dir that replays every WAL
+    fn manifest(env: &Arc<dyn Env>, dir: &Path) -> Manifest {
+        Manifest::load_or_create(env, dir, &BytewiseComparator).unwrap()
+    }
     
     //Helper method to validate WAL Record Block Format and Value
-    fn validate_wal_record(reader: &mut BufReader<File>,key: &[u8], value: Option<&[u8]>,timestamp: u128,deleted: bool){
+    fn validate_wal_record(reader: &mut impl Read,key: &[u8], value: Option<&[u8]>,timestamp: u128,deleted: bool){
         let mut len_buffer = [0;8];
         reader.read_exact(&mut len_buffer).unwrap();
         
@@ -200,20 +453,17 @@ mod tests {
 
     #[test]
     fn test_write_one() {
-        let mut rng = rand::thread_rng();
-        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
-        create_dir(&dir).unwrap();
+        let env = mem_env();
+        let dir = PathBuf::from("db");
+        env.create_dir(&dir).unwrap();
     
-        let timestamp = SystemTime::now()
-          .duration_since(UNIX_EPOCH)
-          .unwrap()
-          .as_micros();
+        let timestamp = SystemClock.now_micros();
     
-        let mut wal = WAL::new(&dir).unwrap();
+        let mut wal = WAL::new(&env, &SystemClock, &dir, 0).unwrap();
         wal.set(b"Badri", b"Badri Krishnan", timestamp).unwrap();
         wal.flush().unwrap();
     
-        let wal_file = OpenOptions::new().read(true).open(&wal.wal_path).unwrap();
+        let wal_file = env.open_file(&wal.wal_path).unwrap();
         let mut reader = BufReader::new(wal_file);
     
         validate_wal_record(
@@ -224,65 +474,56 @@ mod tests {
           false,
         );
     
-        remove_dir_all(&dir).unwrap();
     }
     
     #[test]
     fn test_write_many_records() {
-        let mut rng = rand::thread_rng();
-        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        let env = mem_env();
+        let dir = PathBuf::from("db");
         println!("HEllo : {}",dir.to_str().unwrap());
-        create_dir(&dir).unwrap();
+        env.create_dir(&dir).unwrap();
 
-        let timestamp = SystemTime::now()
-          .duration_since(UNIX_EPOCH)
-          .unwrap()
-          .as_micros();
+        let timestamp = SystemClock.now_micros();
         
         let records: Vec<(&[u8], Option<&[u8]>)> = vec![
             (b"Car", Some(b"Garage")),
             (b"Bike", Some(b"Bike Rack")),
             (b"Pedestrian", Some(b"Pedastrian Walkway")),
         ];
-        let mut wal = WAL::new(&dir).unwrap();
+        let mut wal = WAL::new(&env, &SystemClock, &dir, 0).unwrap();
         for record in records.iter(){
-            wal.set(record.0, record.1.unwrap(), timestamp);
+            wal.set(record.0, record.1.unwrap(), timestamp).unwrap();
         }
         wal.flush().unwrap();
-        let wal_file = OpenOptions::new().read(true).open(&wal.wal_path).unwrap();
+        let wal_file = env.open_file(&wal.wal_path).unwrap();
         let mut reader = BufReader::new(wal_file);
         for record in records.iter(){
             validate_wal_record(&mut reader, record.0, record.1, timestamp, false);
         }
-
-        remove_dir_all(&dir).unwrap();
     }
     #[test]
     fn test_write_and_delete() {
-        let mut rng = rand::thread_rng();
-        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        let env = mem_env();
+        let dir = PathBuf::from("db");
         println!("HEllo : {}",dir.to_str().unwrap());
-        create_dir(&dir).unwrap();
+        env.create_dir(&dir).unwrap();
 
-        let timestamp = SystemTime::now()
-          .duration_since(UNIX_EPOCH)
-          .unwrap()
-          .as_micros();
+        let timestamp = SystemClock.now_micros();
         
         let records: Vec<(&[u8], Option<&[u8]>)> = vec![
             (b"Car", Some(b"Garage")),
             (b"Bike", Some(b"Bike Rack")),
             (b"Pedestrian", Some(b"Pedastrian Walkway")),
         ];
-        let mut wal = WAL::new(&dir).unwrap();
+        let mut wal = WAL::new(&env, &SystemClock, &dir, 0).unwrap();
         for record in records.iter(){
-            wal.set(record.0, record.1.unwrap(), timestamp);
+            wal.set(record.0, record.1.unwrap(), timestamp).unwrap();
         }
         for record in records.iter(){
-            wal.delete(record.0, timestamp);
+            wal.delete(record.0, timestamp).unwrap();
         }
         wal.flush().unwrap();
-        let wal_file = OpenOptions::new().read(true).open(&wal.wal_path).unwrap();
+        let wal_file = env.open_file(&wal.wal_path).unwrap();
         let mut reader = BufReader::new(wal_file);
         for record in records.iter() {
             validate_wal_record(&mut reader, record.0, record.1, timestamp, false);
@@ -290,42 +531,39 @@ mod tests {
         for record in records.iter() {
             validate_wal_record(&mut reader, record.0, None, timestamp, true);
         }
-        remove_dir_all(&dir).unwrap();
     }
     #[test]
     fn test_read_wal_none() {
-        let mut rng = rand::thread_rng();
-        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
-        create_dir(&dir).unwrap();
+        let env = mem_env();
+        let dir = PathBuf::from("db");
+        env.create_dir(&dir).unwrap();
 
-        let (new_wal, new_mem_table) = WAL::load_mem_table_from_dir(&dir).unwrap();
-        assert_eq!(new_mem_table.len(), 0);
+        let (new_wal, new_mem_tables, _) = WAL::load_mem_tables_from_dir(&env, &SystemClock, &dir, &mut manifest(&env, &dir), &bytewise()).unwrap();
+        assert!(new_mem_tables.is_empty());
 
-        let m = metadata(new_wal.wal_path).unwrap();
-        assert_eq!(m.len(), 0);
-
-        remove_dir_all(&dir).unwrap();
+        assert_eq!(env.file_size(new_wal.path()).unwrap(), 0);
     }
 
     #[test]
     fn test_read_wal_one(){
-        let mut rng = rand::thread_rng();
-        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
-        create_dir(&dir).unwrap();    
+        let env = mem_env();
+        let dir = PathBuf::from("db");
+        env.create_dir(&dir).unwrap();    
 
         let records: Vec<(&[u8], Option<&[u8]>)> = vec![
             (b"Car", Some(b"Garage")),
             (b"Bike", Some(b"Bike Rack")),
             (b"Pedestrian", Some(b"Pedastrian Walkway")),
         ];
-        let mut wal = WAL::new(&dir).unwrap();
+        let mut wal = WAL::new(&env, &SystemClock, &dir, 0).unwrap();
         for (time, record) in records.iter().enumerate(){
             wal.set(record.0,record.1.unwrap(), time as u128).unwrap();
         }
         wal.flush().unwrap();
-        let (new_wal, mut recovered_table) = WAL::load_mem_table_from_dir(&dir).unwrap();
-        
-        let file = OpenOptions::new().read(true).open(&new_wal.wal_path).unwrap();
+        let (new_wal, mut recovered_tables, _) = WAL::load_mem_tables_from_dir(&env, &SystemClock, &dir, &mut manifest(&env, &dir), &bytewise()).unwrap();
+        let recovered_table = recovered_tables.get_mut(&0).unwrap();
+
+        let file = env.open_file(&new_wal.wal_path).unwrap();
         let mut reader = BufReader::new(file);
 
         for (time, record) in records.iter().enumerate(){
@@ -335,8 +573,173 @@ mod tests {
             assert_eq!(record_from_mem_table.value.as_ref().unwrap().as_slice(), record.1.unwrap());
             assert_eq!(record_from_mem_table.timestamp, time as u128);
         }
+    }
+
+    #[test]
+    fn test_read_wal_torn_batch(){
+        let env = mem_env();
+        let dir = PathBuf::from("db");
+        env.create_dir(&dir).unwrap();
+
+        let batch = vec![
+            WALRecord::set(0, b"Car", b"Garage", 1),
+            WALRecord::delete(0, b"Bike", 1),
+        ];
+        let mut wal = WAL::new(&env, &SystemClock, &dir, 0).unwrap();
+        wal.set(b"Pedestrian", b"Pedastrian Walkway", 0).unwrap();
+        wal.batch(&batch, 1).unwrap();
+        wal.flush().unwrap();
+
+        //Full batch is read back
+        let wal_path = wal.wal_path.clone();
+        let records: Vec<WALEntry> = wal.into_iter().collect();
+        assert_eq!(records.len(), 3);
+        assert!(matches!(&records[2], WALEntry::Record(record) if record.deleted));
+
+        //Cut the last byte off, the whole batch is dropped but the record before it is not
+        let mut data = Vec::new();
+        env.open_file(&wal_path).unwrap().read_to_end(&mut data).unwrap();
+        env.create_file(&wal_path).unwrap().write_all(&data[..data.len() - 1]).unwrap();
+
+        let (_, mut recovered_tables, _) = WAL::load_mem_tables_from_dir(&env, &SystemClock, &dir, &mut manifest(&env, &dir), &bytewise()).unwrap();
+        let recovered_table = recovered_tables.get_mut(&0).unwrap();
+        assert_eq!(recovered_table.len(), 1);
+        assert!(recovered_table.get(b"Pedestrian").is_some());
+        assert!(recovered_table.get(b"Car").is_none());
+    }
+
+    #[test]
+    fn test_read_wal_ttl(){
+        let env = mem_env();
+        let dir = PathBuf::from("db");
+        env.create_dir(&dir).unwrap();
+
+        let mut wal = WAL::new(&env, &SystemClock, &dir, 0).unwrap();
+        wal.set_with_ttl(b"Session", b"Badri", 10, 1000).unwrap();
+        wal.set(b"Car", b"Garage", 20).unwrap();
+        wal.flush().unwrap();
+
+        let (_, mut recovered_tables, _) = WAL::load_mem_tables_from_dir(&env, &SystemClock, &dir, &mut manifest(&env, &dir), &bytewise()).unwrap();
+        let recovered_table = recovered_tables.get_mut(&0).unwrap();
+        let record = recovered_table.get(b"Session").unwrap();
+        assert_eq!(record.value.as_ref().unwrap(), b"Badri");
+        assert_eq!(record.expires_at, Some(1000));
+        assert_eq!(recovered_table.get(b"Car").unwrap().expires_at, None);
+    }
 
-        remove_dir_all(&dir).unwrap();
+    #[test]
+    fn test_read_wal_merge(){
+        let env = mem_env();
+        let dir = PathBuf::from("db");
+        env.create_dir(&dir).unwrap();
+
+        let mut wal = WAL::new(&env, &SystemClock, &dir, 0).unwrap();
+        wal.set(b"Badri", b"a", 10).unwrap();
+        wal.merge(b"Badri", b"b", 20).unwrap();
+        wal.merge(b"Badri", b"c", 30).unwrap();
+        wal.flush().unwrap();
 
+        let (_, recovered_tables, _) = WAL::load_mem_tables_from_dir(&env, &SystemClock, &dir, &mut manifest(&env, &dir), &bytewise()).unwrap();
+        let recovered_table = &recovered_tables[&0];
+        let versions: Vec<(bool, &[u8])> = recovered_table
+            .versions(b"Badri")
+            .map(|r| (r.merge, r.value.as_ref().unwrap().as_slice()))
+            .collect();
+        assert_eq!(versions, vec![(true, b"c".as_slice()), (true, b"b".as_slice()), (false, b"a".as_slice())]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_read_wal_column_families(){
+        let env = mem_env();
+        let dir = PathBuf::from("db");
+        env.create_dir(&dir).unwrap();
+
+        let mut wal = WAL::new(&env, &SystemClock, &dir, 0).unwrap();
+        wal.write_record(&WALRecord::set(0, b"Car", b"Garage", 10)).unwrap();
+        wal.write_record(&WALRecord::set(3, b"Car", b"Driveway", 20)).unwrap();
+        wal.batch(&[WALRecord::delete(0, b"Car", 30), WALRecord::merge(3, b"Bike", b"Rack", 30)], 30).unwrap();
+        wal.flush().unwrap();
+
+        //The default column family is written without the flag so older logs read the same
+        let wal_file = env.open_file(&wal.wal_path).unwrap();
+        let mut reader = BufReader::new(wal_file);
+        validate_wal_record(&mut reader, b"Car", Some(b"Garage"), 10, false);
+
+        let (_, recovered_tables, _) = WAL::load_mem_tables_from_dir(&env, &SystemClock, &dir, &mut manifest(&env, &dir), &bytewise()).unwrap();
+        assert_eq!(recovered_tables.len(), 2);
+        assert!(recovered_tables[&0].versions(b"Car").next().unwrap().deleted);
+        let versions: Vec<_> = recovered_tables[&3].entries().iter().map(|r| (r.key.as_slice(), r.merge)).collect();
+        assert_eq!(versions, vec![(b"Bike".as_slice(), true), (b"Car".as_slice(), false)]);
+    }
+
+    #[test]
+    fn test_read_wal_in_memory(){
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let dir = PathBuf::from("db");
+        env.create_dir(&dir).unwrap();
+
+        let mut wal = WAL::new(&env, &SystemClock, &dir, 0).unwrap();
+        wal.set(b"Badri", b"Krishnan", 10).unwrap();
+        wal.delete(b"Car", 20).unwrap();
+        wal.sync().unwrap();
+        let old_path = wal.path().to_path_buf();
+
+        let (new_wal, mut recovered_tables, _) = WAL::load_mem_tables_from_dir(&env, &SystemClock, &dir, &mut manifest(&env, &dir), &bytewise()).unwrap();
+        let recovered_table = recovered_tables.get_mut(&0).unwrap();
+        assert_eq!(recovered_table.get(b"Badri").unwrap().value.as_deref(), Some(b"Krishnan".as_slice()));
+        assert!(recovered_table.get(b"Car").unwrap().deleted);
+        assert!(!env.exists(&old_path));
+        assert_eq!(WAL::wal_files(env.as_ref(), &dir).unwrap(), vec![new_wal.path().to_path_buf()]);
+        assert_eq!(new_wal.into_iter().count(), 2);
+    }
+
+    #[test]
+    fn test_skip_flushed_wal(){
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let dir = PathBuf::from("db");
+        env.create_dir(&dir).unwrap();
+
+        let mut flushed = WAL::new(&env, &SystemClock, &dir, 0).unwrap();
+        flushed.set(b"Badri", b"Krishnan", 10).unwrap();
+        flushed.sync().unwrap();
+        let mut current = WAL::new(&env, &SystemClock, &dir, 0).unwrap();
+        current.set(b"Lavanya", b"Krishnan", 20).unwrap();
+        current.sync().unwrap();
+        assert!(current.number() > flushed.number());
+
+        //The older WAL is deleted along with the rest but not replayed
+        let mut flushed_through = manifest(&env, &dir);
+        flushed_through.set_oldest_wal(current.number());
+        let (new_wal, mut recovered_tables, _) = WAL::load_mem_tables_from_dir(&env, &SystemClock, &dir, &mut flushed_through, &bytewise()).unwrap();
+        let recovered_table = recovered_tables.get_mut(&0).unwrap();
+        assert!(recovered_table.get(b"Badri").is_none());
+        assert!(recovered_table.get(b"Lavanya").is_some());
+        assert_eq!(WAL::wal_files(env.as_ref(), &dir).unwrap(), vec![new_wal.path().to_path_buf()]);
+    }
+
+    #[test]
+    fn test_replay_wals_in_number_order(){
+        let env = mem_env();
+        let dir = PathBuf::from("db");
+        env.create_dir(&dir).unwrap();
+
+        //The clock crosses a power of ten between the two WALs, 10.wal sorts before 9.wal by name
+        let clock = ManualClock::new(9);
+        let mut older = WAL::new(&env, &clock, &dir, 0).unwrap();
+        older.prepare(b"transfer", &[WALRecord::set(0, b"Badri", b"Krishnan", 10)], 10).unwrap();
+        older.set(b"Lavanya", b"Krishnan", 11).unwrap();
+        older.sync().unwrap();
+        clock.set(10);
+        let mut newer = WAL::new(&env, &clock, &dir, 0).unwrap();
+        newer.commit_prepared(b"transfer", 12).unwrap();
+        newer.sync().unwrap();
+        assert_eq!(WAL::wal_files(env.as_ref(), &dir).unwrap(), vec![dir.join("9.wal"), dir.join("10.wal")]);
+
+        //The COMMIT is replayed after its PREPARE
+        let (mut recovered_tables, prepared) = WAL::read_mem_tables_from_dir(&env, &dir, 0, &bytewise()).unwrap();
+        assert!(prepared.is_empty());
+        let recovered_table = recovered_tables.get_mut(&0).unwrap();
+        assert!(recovered_table.get(b"Badri").is_some());
+        assert!(recovered_table.get(b"Lavanya").is_some());
+    }
+}
@@ -1,81 +1,221 @@
-use std::fs::{File,OpenOptions};
+use std::collections::VecDeque;
 use std::io::prelude::*;
 use std::io::{self, BufReader};
-use std::path::PathBuf;
+use std::path::Path;
 
+use crate::env::Env;
+use crate::wal::{PreparedTransaction, COLUMN_FAMILY_FLAG, KIND_BATCH, KIND_COMMIT_PREPARED, KIND_DELETE, KIND_PREPARE, KIND_ROLLBACK_PREPARED, KIND_MERGE, KIND_SET, KIND_SET_WITH_TTL};
 
 pub struct WALRecord {
     pub key: Vec<u8>,
     pub value: Option<Vec<u8>>,
     pub timestamp: u128,
-    pub deleted: bool
+    pub deleted: bool,
+    //Time in microseconds the value expires at, None if it never expires
+    pub expires_at: Option<u128>,
+    //The value is a merge operand for the key's merge operator
+    pub merge: bool,
+    //Id of the column family the record belongs to
+    pub column_family: u32,
+}
+
+impl WALRecord {
+    pub fn set(column_family: u32, key: &[u8], value: &[u8], timestamp: u128) -> WALRecord {
+        WALRecord::set_with_ttl(column_family, key, value, timestamp, None)
+    }
+
+    pub fn set_with_ttl(column_family: u32, key: &[u8], value: &[u8], timestamp: u128, expires_at: Option<u128>) -> WALRecord {
+        WALRecord {
+            key: key.to_owned(),
+            value: Some(value.to_owned()),
+            timestamp,
+            deleted: false,
+            expires_at,
+            merge: false,
+            column_family,
+        }
+    }
+
+    pub fn merge(column_family: u32, key: &[u8], operand: &[u8], timestamp: u128) -> WALRecord {
+        WALRecord {
+            merge: true,
+            ..WALRecord::set(column_family, key, operand, timestamp)
+        }
+    }
+
+    pub fn delete(column_family: u32, key: &[u8], timestamp: u128) -> WALRecord {
+        WALRecord {
+            key: key.to_owned(),
+            value: None,
+            timestamp,
+            deleted: true,
+            expires_at: None,
+            merge: false,
+            column_family,
+        }
+    }
+}
+
+//What the WAL holds, records of a batch are handed out one at a time once the whole batch was read
+pub enum WALEntry {
+    Record(WALRecord),
+    //Write set of a transaction prepared for two-phase commit, not applied until it is committed
+    Prepare(PreparedTransaction),
+    //Decision for the prepared transaction with this name, a commit applies its writes at timestamp
+    CommitPrepared { name: Vec<u8>, timestamp: u128 },
+    //A rollback is logged with a timestamp too, nothing needs it back
+    RollbackPrepared { name: Vec<u8> },
 }
 
 pub struct WALRecordIterator {
-    buffered_reader: BufReader<File>,
+    buffered_reader: BufReader<Box<dyn Read + Send>>,
+    //Bytes of the file not read yet, as of when it was opened
+    remaining: u64,
+    //Records of a batch that was read back in full and not handed out yet
+    pending: VecDeque<WALRecord>,
 }
 impl WALRecordIterator {
-    pub fn new(path: PathBuf) -> io::Result<WALRecordIterator> {
-        let file = OpenOptions::new().read(true).open(path)?;
+    pub fn new(env: &dyn Env, path: &Path) -> io::Result<WALRecordIterator> {
+        let remaining = env.file_size(path)?;
+        let file = env.open_file(path)?;
         let buff_reader = BufReader::new(file);
         Ok(WALRecordIterator{
-            buffered_reader: buff_reader
+            buffered_reader: buff_reader,
+            remaining,
+            pending: VecDeque::new(),
         })
     }
-}
 
-impl Iterator for WALRecordIterator{
-    type Item = WALRecord;
+    //Fill buffer from the log, None if the log ends first
+    fn read_exact(&mut self, buffer: &mut [u8]) -> Option<()> {
+        if buffer.len() as u64 > self.remaining || self.buffered_reader.read_exact(buffer).is_err() {
+            return None;
+        }
+        self.remaining -= buffer.len() as u64;
+        Some(())
+    }
 
-    fn next(&mut self) -> Option<WALRecord>{
-        let mut len_buffer = [0;8];
-        if self.buffered_reader.read_exact(& mut len_buffer).is_err(){
+    //Read len bytes the log gave the length of, a length past the end of the log is corrupt
+    //and nothing is allocated for it
+    fn read_bytes(&mut self, len: usize) -> Option<Vec<u8>> {
+        if len as u64 > self.remaining {
             return None;
         }
+        let mut bytes = vec![0; len];
+        self.read_exact(&mut bytes)?;
+        Some(bytes)
+    }
+
+    //Read the key size and kind that start every record, and the column family id when the kind is flagged with one
+    fn read_header(&mut self) -> Option<(usize, u8, u32)> {
+        let mut len_buffer = [0;8];
+        self.read_exact(&mut len_buffer)?;
         let key_len = usize::from_le_bytes(len_buffer);
-        
+
         let mut tombstone_buffer = [0;1];
 
-        if self.buffered_reader.read_exact(& mut tombstone_buffer).is_err(){
-            return None;
+        self.read_exact(&mut tombstone_buffer)?;
+        let kind = tombstone_buffer[0];
+        if kind & COLUMN_FAMILY_FLAG == 0 {
+            return Some((key_len, kind, 0));
         }
+        let mut column_family_buffer = [0;4];
+        self.read_exact(&mut column_family_buffer)?;
+        Some((key_len, kind & !COLUMN_FAMILY_FLAG, u32::from_le_bytes(column_family_buffer)))
+    }
 
-        let deleted = tombstone_buffer[0] != 0;
-        let mut key = vec![0 ; key_len];
+    //Read a set or delete record, None if the log ends part way or the kind is unknown
+    fn read_record(&mut self, key_len: usize, kind: u8, column_family: u32) -> Option<WALRecord> {
+        if ![KIND_SET, KIND_DELETE, KIND_SET_WITH_TTL, KIND_MERGE].contains(&kind) {
+            return None;
+        }
+        let deleted = kind == KIND_DELETE;
+        let mut len_buffer = [0;8];
+        let key;
         let mut value = None;
-        if (deleted){
-            if self.buffered_reader.read_exact(& mut key).is_err() {
-                return None;
-            }  
+        if deleted {
+            key = self.read_bytes(key_len)?;
         } 
         else {
-            if self.buffered_reader.read_exact(&mut len_buffer).is_err() {
-              return None;
-            }
+            self.read_exact(&mut len_buffer)?;
             let value_len = usize::from_le_bytes(len_buffer);
-            if self.buffered_reader.read_exact(&mut key).is_err() {
-              return None;
-            }
-            let mut value_buffer = vec![0; value_len];
-            if self.buffered_reader.read_exact(&mut value_buffer).is_err() {
-              return None;
-            }
-            value = Some(value_buffer);
+            key = self.read_bytes(key_len)?;
+            value = Some(self.read_bytes(value_len)?);
         }
 
-        let mut timestamp_buffer = [0; 16];
-        if self.buffered_reader.read_exact(&mut timestamp_buffer).is_err() {
-            return None;
+        let timestamp = self.read_timestamp()?;
+        let mut expires_at = None;
+        if kind == KIND_SET_WITH_TTL {
+            expires_at = Some(self.read_timestamp()?);
         }
-        let timestamp = u128::from_le_bytes(timestamp_buffer);
         
         Some(
             WALRecord{
                 key,
                 value,
                 timestamp,
-                deleted
+                deleted,
+                expires_at,
+                merge: kind == KIND_MERGE,
+                column_family,
             }
         )
     }
+
+    fn read_timestamp(&mut self) -> Option<u128> {
+        let mut timestamp_buffer = [0; 16];
+        self.read_exact(&mut timestamp_buffer)?;
+        Some(u128::from_le_bytes(timestamp_buffer))
+    }
+
+    //Read the prepare header and its write set, a write set cut short by the end of the log is dropped
+    fn read_prepare(&mut self, name_len: usize) -> Option<PreparedTransaction> {
+        let mut len_buffer = [0;8];
+        self.read_exact(&mut len_buffer)?;
+        let count = usize::from_le_bytes(len_buffer);
+        let name = self.read_bytes(name_len)?;
+        let timestamp = self.read_timestamp()?;
+        let mut records = Vec::new();
+        for _ in 0..count {
+            let (key_len, kind, column_family) = self.read_header()?;
+            records.push(self.read_record(key_len, kind, column_family)?);
+        }
+        Some(PreparedTransaction { name, records, timestamp })
+    }
+
+    //Read every record of a batch, a batch cut short by the end of the log is dropped whole
+    fn read_batch(&mut self, count: usize) -> Option<()> {
+        self.read_timestamp()?;
+        let mut records = VecDeque::new();
+        for _ in 0..count {
+            let (key_len, kind, column_family) = self.read_header()?;
+            records.push_back(self.read_record(key_len, kind, column_family)?);
+        }
+        self.pending = records;
+        Some(())
+    }
+}
+
+impl Iterator for WALRecordIterator{
+    type Item = WALEntry;
+
+    fn next(&mut self) -> Option<WALEntry>{
+        while self.pending.is_empty() {
+            let (key_len, kind, column_family) = self.read_header()?;
+            match kind {
+                KIND_BATCH => self.read_batch(key_len)?,
+                KIND_PREPARE => return self.read_prepare(key_len).map(WALEntry::Prepare),
+                KIND_COMMIT_PREPARED | KIND_ROLLBACK_PREPARED => {
+                    let name = self.read_bytes(key_len)?;
+                    let timestamp = self.read_timestamp()?;
+                    if kind == KIND_COMMIT_PREPARED {
+                        return Some(WALEntry::CommitPrepared { name, timestamp });
+                    }
+                    return Some(WALEntry::RollbackPrepared { name });
+                }
+                _ => return self.read_record(key_len, kind, column_family).map(WALEntry::Record),
+            }
+        }
+        self.pending.pop_front().map(WALEntry::Record)
+    }
 }
@@ -0,0 +1,71 @@
+//WriteBatch - a group of sets and deletes that are applied atomically
+
+/*
+The batch is written to the WAL behind a single batch header so recovery replays either every write
+in it or none of them. Every write in the batch gets the same timestamp, so a snapshot sees all of
+them or none of them too.
+
+Writes can go to any column family, they all share the one WAL so the batch stays atomic across them.
+*/
+
+use crate::column_family::DEFAULT_COLUMN_FAMILY;
+
+pub struct WriteBatch {
+    writes: Vec<BatchWrite>,
+}
+
+pub struct BatchWrite {
+    pub column_family: String,
+    pub key: Vec<u8>,
+    //None deletes the key
+    pub value: Option<Vec<u8>>,
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch { writes: Vec::new() }
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.set_cf(DEFAULT_COLUMN_FAMILY, key, value);
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.delete_cf(DEFAULT_COLUMN_FAMILY, key);
+    }
+
+    pub fn set_cf(&mut self, column_family: &str, key: &[u8], value: &[u8]) {
+        self.writes.push(BatchWrite {
+            column_family: column_family.to_owned(),
+            key: key.to_owned(),
+            value: Some(value.to_owned()),
+        });
+    }
+
+    pub fn delete_cf(&mut self, column_family: &str, key: &[u8]) {
+        self.writes.push(BatchWrite {
+            column_family: column_family.to_owned(),
+            key: key.to_owned(),
+            value: None,
+        });
+    }
+
+    //Writes in the order they were added, a later write to the same key wins
+    pub fn writes(&self) -> &[BatchWrite] {
+        &self.writes
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}